@@ -0,0 +1,60 @@
+use std::env;
+use std::path::Path;
+
+use viva::compile::compile_prog_to_asm;
+use viva::compile_repl::run_prog;
+use viva::expressions::Program;
+use viva::parse::parse_prog_file;
+use viva::runtime::{format_value, parse_arg, set_args};
+use viva::{cli_mode, fmt_mode, parse_flags, Options};
+
+const USAGE: &str = "usage: cli -c <input.viva> <output.s> | -e <input.viva> <arg>* | -g <input.viva> <output.s> <arg>* | -i | fmt [--check] <input.viva>...";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(err) = run(&args) {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+fn run(args: &[String]) -> std::io::Result<()> {
+    if args.first().map(|a| a.as_str()) == Some("fmt") {
+        return fmt_mode(&args[1..]);
+    }
+    let (options, rest) = parse_flags(args)?;
+    match rest.iter().map(|a| a.as_str()).collect::<Vec<&str>>()[..] {
+        ["-c", input, output] => {
+            let prog = load(input, options)?;
+            std::fs::write(output, compile_prog_to_asm(&prog, options)?)
+        }
+        ["-e", input, ref prog_args @ ..] => evaluate(&load(input, options)?, prog_args, options),
+        ["-g", input, output, ref prog_args @ ..] => {
+            let prog = load(input, options)?;
+            std::fs::write(output, compile_prog_to_asm(&prog, options)?)?;
+            evaluate(&prog, prog_args, options)
+        }
+        ["-i"] => cli_mode(options),
+        _ => Err(std::io::Error::other(USAGE)),
+    }
+}
+
+/* Warnings are reported on stderr, they don't stop the program */
+fn load(input: &str, options: Options) -> std::io::Result<Program> {
+    let prog = parse_prog_file(Path::new(input), options)?;
+    for warning in &prog.warnings {
+        eprintln!("{}", warning);
+    }
+    Ok(prog)
+}
+
+/* The arguments after the file are what input, arg and argc see */
+fn evaluate(prog: &Program, prog_args: &[&str], options: Options) -> std::io::Result<()> {
+    let mut values = Vec::new();
+    for arg in prog_args {
+        values.push(parse_arg(arg)?);
+    }
+    set_args(values);
+    println!("{}", format_value(run_prog(prog, options)?));
+    Ok(())
+}
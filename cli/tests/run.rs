@@ -0,0 +1,55 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/* Writes the program into a fresh file, the name of the test keeps the files of the tests apart */
+fn write_program(test: &str, source: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("viva-cli-{}-{}.viva", std::process::id(), test));
+    std::fs::write(&path, source).unwrap();
+    path
+}
+
+/* What cli -e printed, the run has to succeed */
+fn evaluate(test: &str, source: &str, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_cli")).arg("-e").arg(write_program(test, source)).args(args).output().unwrap();
+    assert!(output.status.success(), "{}\n{:?}\n{}", source, output.status, String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn programs_are_evaluated() {
+    assert_eq!(evaluate("numbers", "(fun (f x) (* x 2)) (define a 3) (+ (f a) input)", &["4"]), "10\n");
+}
+
+#[test]
+fn literals_can_be_results() {
+    assert_eq!(evaluate("string", "\"hi\"", &[]), "hi\n");
+    assert_eq!(evaluate("float", "2.5", &[]), "2.5\n");
+    assert_eq!(evaluate("print", "(print \"hi\")", &[]), "hi\nhi\n");
+    assert_eq!(evaluate("let", "(let ((x \"hi\")) (print x))", &[]), "hi\nhi\n");
+    assert_eq!(evaluate("function", "(fun (f) \"x\") (f)", &[]), "x\n");
+    assert_eq!(evaluate("vector", "(define v (vector 1.5 \"a\")) v", &[]), "[1.5, a]\n");
+}
+
+/* What cli -i printed for the lines, without the prompts */
+fn interact(lines: &str) -> Vec<String> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_cli")).arg("-i").stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+    child.stdin.take().unwrap().write_all(lines.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    String::from_utf8(output.stdout).unwrap().split("> ").skip(1).map(|s| s.trim_end().to_string()).collect()
+}
+
+#[test]
+fn the_repl_stops_a_line_at_its_first_failing_form() {
+    let outputs = interact("(define a 1) (foo 1) (print 5) (define b 2)\n(+ a 1)\nb\nexit\n");
+    assert!(outputs[0].starts_with("Error: unknown function `foo`") && !outputs[0].contains("\n5"), "{:?}", outputs);
+    assert_eq!(outputs[1], "2");
+    assert_eq!(outputs[2], "Unbound variable identifier b");
+    assert_eq!(outputs[3], "Thanks for you business with us!");
+}
+
+#[test]
+fn the_repl_reads_the_process_stdin() {
+    let outputs = interact("(+ (read-num) 1)\n41\nexit\n");
+    assert_eq!(outputs[0], "42");
+}
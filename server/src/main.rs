@@ -11,7 +11,7 @@ use std::{
 
 use axum::{
     extract::{Json, Path, State}, 
-    http::{header::CONTENT_TYPE, Method}, 
+    http::{header::CONTENT_TYPE, Method, StatusCode}, 
     routing::post, 
     Router
 };
@@ -20,11 +20,17 @@ use serde::{Deserialize, Serialize};
 use tower_http::cors::{Any, CorsLayer};
 use tokio::sync::{Mutex, RwLock};
 
-use viva::Repl;
+use viva::{parse_flags, Repl};
 
 #[derive(Deserialize)]
 struct Input {
-    text: String
+    text: String,
+    #[serde(default)]
+    inputs: Option<Vec<String>>,
+    #[serde(default)]
+    stdin: Option<String>,
+    #[serde(default)]
+    flags: Option<Vec<String>>
 }
 
 #[derive(Serialize)]
@@ -89,7 +95,20 @@ async fn eval_handler(
     let (result_str, elapsed) = if let Some(repl_mutex) = possible_repl {
         let mut repl = repl_mutex.lock().await;
         let start = Instant::now();
-        let res = match repl.feed(&input.text) {
+        /* Without stdin in the request the lines left over from earlier requests stay */
+        if let Some(stdin) = &input.stdin {
+            repl.set_stdin(stdin);
+        }
+        /* Options and inputs stay as earlier requests set them unless the request sends them */
+        let options_set = match &input.flags {
+            Some(flags) => parse_flags(flags).map(|(options, _)| repl.set_options(options)),
+            None => Ok(()),
+        };
+        let inputs_set = options_set.and_then(|_| match &input.inputs {
+            Some(inputs) => repl.set_inputs(inputs),
+            None => Ok(()),
+        });
+        let res = match inputs_set.and_then(|_| repl.feed(&input.text)) {
             Ok(Some(s)) => s,
             Ok(None) => String::new(),
             Err(e) => format!("Error: {}", e),
@@ -106,11 +125,12 @@ async fn eval_handler(
     })
 }
 
-async fn create_repl_handler (State(state): State<Arc<AppState>>) -> Json<NewRepl> {
+async fn create_repl_handler (State(state): State<Arc<AppState>>) -> Result<Json<NewRepl>, (StatusCode, String)> {
+    let repl = Repl::new().map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Error: {}", e)))?;
     let id = state.next_id.fetch_add(1, Ordering::Relaxed);
     {
         let mut map = state.repls.write().await;
-        map.insert(id, Arc::new(Mutex::new(Repl::new())));
+        map.insert(id, Arc::new(Mutex::new(repl)));
     }
-    Json(NewRepl { id })
+    Ok(Json(NewRepl { id }))
 }
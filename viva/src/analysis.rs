@@ -0,0 +1,139 @@
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+
+use ariadne::{Config, IndexType, Label, Report, ReportKind, Source};
+
+use crate::expressions::Signature;
+
+/* The problems the reader and the resolver find and how they are shown. The reader gives every form the
+   byte range of the source it was read from and the parser passes it on to the expressions, so a diagnostic
+   points at the node that caused it. */
+
+/* Where a node was read from. Two nodes are the same whatever their place, so a span takes no part in
+   comparing and hashing. A node that a pass makes up has the empty span, it has no place to point at. */
+#[derive(Debug, Clone, Default)]
+pub struct Span(pub Range<usize>);
+
+impl PartialEq for Span {
+    fn eq(&self, _: &Span) -> bool {
+        true
+    }
+}
+
+impl Eq for Span {}
+
+impl Hash for Span {
+    fn hash<H: Hasher>(&self, _: &mut H) {}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub label: String,
+    pub span: Span,
+    /* A warning is reported, but the program still runs */
+    pub warning: bool,
+}
+
+impl Diagnostic {
+    pub fn error(message: String, label: &str, span: Span) -> Self {
+        Diagnostic { message, label: label.to_string(), span, warning: false }
+    }
+
+    pub fn warning(message: String, label: &str, span: Span) -> Self {
+        Diagnostic { message, label: label.to_string(), span, warning: true }
+    }
+
+    pub fn wrong_arity(name: &str, signature: &Signature, argc: usize, span: Span) -> Self {
+        let most = if signature.rest { signature.required } else { signature.required + signature.defaults.len() };
+        Diagnostic::error(
+            format!("{} expects {} {}, got {}", name, signature.expected(), arguments(most), argc),
+            &format!("called with {} {}", argc, arguments(argc)),
+            span,
+        )
+    }
+}
+
+/* The rendered warnings, or an error with the reports of all errors in it. Without a source the reports
+   are only the messages. */
+pub fn into_result(diagnostics: &[Diagnostic], source: Option<(&str, &str)>) -> std::io::Result<Vec<String>> {
+    let (warnings, errors): (Vec<&Diagnostic>, Vec<&Diagnostic>) = diagnostics.iter().partition(|d| d.warning);
+    if !errors.is_empty() {
+        let reports: Vec<String> = errors.iter().map(|d| render(d, source)).collect();
+        return Err(std::io::Error::other(reports.join("\n")));
+    }
+    Ok(warnings.iter().map(|d| render(d, source)).collect())
+}
+
+/* The closest known name, if it is close enough to be a typo. A name of up to two characters has no edits to
+   spare, one edit is only allowed when the names start the same way. Operators like + are not suggested for
+   other names and the other way round. Among equally close names the one that starts like the misspelled
+   name is the more likely. */
+pub fn suggestion<'b>(name: &str, candidates: impl Iterator<Item = &'b String>) -> Option<&'b String> {
+    let limit = name.chars().count() / 3;
+    let operator = |n: &str| !n.chars().any(char::is_alphanumeric);
+    candidates
+        .filter(|candidate| operator(candidate) == operator(name))
+        .map(|candidate| {
+            let prefix = name.chars().zip(candidate.chars()).take_while(|(a, b)| a == b).count();
+            (edit_distance(name, candidate), std::cmp::Reverse(prefix), candidate)
+        })
+        .filter(|(distance, std::cmp::Reverse(prefix), _)| *distance <= limit || (*distance == 1 && *prefix > 0))
+        .min()
+        .map(|(_, _, candidate)| candidate)
+}
+
+/* Edits to turn one name into the other, counted in characters: inserting, deleting or replacing one, or
+   swapping two neighbours */
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1).min(d[i][j - 1] + 1).min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+fn arguments(count: usize) -> &'static str {
+    if count == 1 { "argument" } else { "arguments" }
+}
+
+/* The message with the node underlined in the source, the report has no colors so that it can be shown
+   anywhere */
+pub fn render(diagnostic: &Diagnostic, source: Option<(&str, &str)>) -> String {
+    let (kind, prefix) = if diagnostic.warning { (ReportKind::Warning, "Warning") } else { (ReportKind::Error, "Error") };
+    let plain = format!("{}: {}", prefix, diagnostic.message);
+    let (file, text) = match source {
+        Some(source) => source,
+        None => return plain,
+    };
+    /* A node a pass made up has no place, and one of another source, like the prelude, no place in this one */
+    let span = diagnostic.span.0.clone();
+    if span.is_empty() || span.end > text.len() {
+        return plain;
+    }
+    let mut out = Vec::new();
+    let written = Report::build(kind, (file, span.clone()))
+        .with_config(Config::default().with_color(false).with_index_type(IndexType::Byte))
+        .with_message(&diagnostic.message)
+        .with_label(Label::new((file, span)).with_message(&diagnostic.label))
+        .finish()
+        .write((file, Source::from(text)), &mut out);
+    match written {
+        Ok(()) => String::from_utf8_lossy(&out).trim_end().to_string(),
+        Err(_) => plain,
+    }
+}
@@ -0,0 +1,163 @@
+use std::cmp::Ordering;
+
+/* Sign and magnitude integers, the magnitude is little endian in 64 bit limbs
+   without leading zero limbs, so zero is an empty magnitude and never negative. */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BigInt {
+    pub negative: bool,
+    pub limbs: Vec<u64>,
+}
+
+impl BigInt {
+    pub fn from_i64(n: i64) -> Self {
+        let limbs = if n == 0 { vec![] } else { vec![n.unsigned_abs()] };
+        BigInt { negative: n < 0, limbs }
+    }
+
+    pub fn from_parts(negative: bool, limbs: Vec<u64>) -> Self {
+        let mut result = BigInt { negative, limbs };
+        result.trim();
+        result
+    }
+
+    fn trim(&mut self) {
+        while self.limbs.last() == Some(&0) {
+            self.limbs.pop();
+        }
+        if self.limbs.is_empty() {
+            self.negative = false;
+        }
+    }
+
+    pub fn to_i64(&self) -> Option<i64> {
+        match self.limbs.as_slice() {
+            [] => Some(0),
+            [limb] if !self.negative => i64::try_from(*limb).ok(),
+            [limb] if *limb <= i64::MIN.unsigned_abs() => Some((*limb as i128).wrapping_neg() as i64),
+            _ => None,
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        let magnitude = self.limbs.iter().rev().fold(0.0, |acc, limb| acc * 18446744073709551616.0 + *limb as f64);
+        if self.negative { -magnitude } else { magnitude }
+    }
+
+    pub fn negate(mut self) -> Self {
+        self.negative = !self.negative;
+        self.trim();
+        self
+    }
+
+    pub fn plus(&self, other: &BigInt) -> BigInt {
+        if self.negative == other.negative {
+            return BigInt::from_parts(self.negative, add_magnitudes(&self.limbs, &other.limbs));
+        }
+        match compare_magnitudes(&self.limbs, &other.limbs) {
+            Ordering::Less => BigInt::from_parts(other.negative, sub_magnitudes(&other.limbs, &self.limbs)),
+            _ => BigInt::from_parts(self.negative, sub_magnitudes(&self.limbs, &other.limbs)),
+        }
+    }
+
+    pub fn minus(&self, other: &BigInt) -> BigInt {
+        self.plus(&other.clone().negate())
+    }
+
+    pub fn times(&self, other: &BigInt) -> BigInt {
+        let mut limbs = vec![0u64; self.limbs.len() + other.limbs.len()];
+        for (i, a) in self.limbs.iter().enumerate() {
+            let mut carry: u128 = 0;
+            for (j, b) in other.limbs.iter().enumerate() {
+                let current = limbs[i + j] as u128 + (*a as u128) * (*b as u128) + carry;
+                limbs[i + j] = current as u64;
+                carry = current >> 64;
+            }
+            limbs[i + other.limbs.len()] = carry as u64;
+        }
+        BigInt::from_parts(self.negative != other.negative, limbs)
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => compare_magnitudes(&self.limbs, &other.limbs),
+            (true, true) => compare_magnitudes(&other.limbs, &self.limbs),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl std::fmt::Display for BigInt {
+    /* Repeatedly divides the magnitude by 10^19, the largest power of ten in a limb */
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        const CHUNK: u128 = 10_000_000_000_000_000_000;
+
+        if self.limbs.is_empty() {
+            return write!(f, "0");
+        }
+
+        let mut limbs = self.limbs.clone();
+        let mut chunks: Vec<u64> = Vec::new();
+        while !limbs.is_empty() {
+            let mut remainder: u128 = 0;
+            for limb in limbs.iter_mut().rev() {
+                let current = (remainder << 64) | *limb as u128;
+                *limb = (current / CHUNK) as u64;
+                remainder = current % CHUNK;
+            }
+            chunks.push(remainder as u64);
+            while limbs.last() == Some(&0) {
+                limbs.pop();
+            }
+        }
+
+        if self.negative {
+            write!(f, "-")?;
+        }
+        write!(f, "{}", chunks.pop().unwrap_or(0))?;
+        for chunk in chunks.iter().rev() {
+            write!(f, "{:019}", chunk)?;
+        }
+        Ok(())
+    }
+}
+
+fn compare_magnitudes(a: &[u64], b: &[u64]) -> Ordering {
+    a.len().cmp(&b.len()).then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_magnitudes(a: &[u64], b: &[u64]) -> Vec<u64> {
+    let mut result = Vec::with_capacity(a.len().max(b.len()) + 1);
+    let mut carry = false;
+    for i in 0..a.len().max(b.len()) {
+        let (sum, c1) = a.get(i).copied().unwrap_or(0).overflowing_add(b.get(i).copied().unwrap_or(0));
+        let (sum, c2) = sum.overflowing_add(carry as u64);
+        result.push(sum);
+        carry = c1 || c2;
+    }
+    if carry {
+        result.push(1);
+    }
+    result
+}
+
+/* Expects the magnitude of a to be at least the one of b */
+fn sub_magnitudes(a: &[u64], b: &[u64]) -> Vec<u64> {
+    let mut result = Vec::with_capacity(a.len());
+    let mut borrow = false;
+    for (i, limb) in a.iter().enumerate() {
+        let (diff, b1) = limb.overflowing_sub(b.get(i).copied().unwrap_or(0));
+        let (diff, b2) = diff.overflowing_sub(borrow as u64);
+        result.push(diff);
+        borrow = b1 || b2;
+    }
+    result
+}
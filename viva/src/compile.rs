@@ -1,20 +1,33 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashSet};
 
 use crate::compile_helpers::{
-    overflow_handler,
     at_least_one_bool_handler,
-    unary_not_bool_handler,
+    unary_bool_handler,
+    gen_isnum,
     gen_compare,
     gen_istype,
+    gen_divide,
+    gen_shift,
+    gen_arith,
+    gen_static_object,
+    gen_struct_check,
+    gen_struct_tag,
+    gen_pop_handlers,
+    constant_bool,
     recursively_collet_depth,
     CmpOp,
     TypeOp,
+    DivOp,
+    ShiftOp,
+    ArithOp,
 };
-use crate::expressions::{Expr, Op1, Op2, Defenition};
-use crate::instructions::{Reg, Instr};
+use crate::expressions::{Binder, Expr, Op1, Op2, Defenition, Pattern, Program};
+use crate::instructions::{Reg, Instr, RustFn, rust_fn_symbol, instrs_to_string};
 use crate::counter::{next_id};
-use crate::context::{Context};
+use crate::context::{Context, LoopScope, Options};
+use crate::runtime::{char_value, string_words, float_words, structs_with_field};
 
 pub fn compile_expr_to_instr(e: &Expr, ctx: &mut Context<'_>) -> std::io::Result<Vec<Instr>> {
     match e {
@@ -25,6 +38,15 @@ pub fn compile_expr_to_instr(e: &Expr, ctx: &mut Context<'_>) -> std::io::Result
                 false => Ok(vec![Instr::Mov(Reg::Rax, 1)]),
             }
         }
+        Expr::Char(c) => Ok(vec![Instr::Mov(Reg::Rax, char_value(*c))]),
+        /* String and float literals are laid out like heap objects, outside of the code */
+        Expr::Str(s) => Ok(gen_static_object(string_words(s), ctx)),
+        Expr::Float(bits) => Ok(gen_static_object(float_words(f64::from_bits(*bits)), ctx)),
+        /* Nothing is 0b0111: odd like a boolean but with bit 2 set, and bit 3 clear unlike a character */
+        Expr::Nothing => Ok(vec![Instr::Mov(Reg::Rax, 7)]),
+        Expr::Argc => Ok(vec![Instr::CallRust(RustFn::Argc, vec![], ctx.si * 8)]),
+        Expr::ReadNum => Ok(vec![Instr::CallRust(RustFn::ReadNum, vec![], ctx.si * 8)]),
+        Expr::ReadBool => Ok(vec![Instr::CallRust(RustFn::ReadBool, vec![], ctx.si * 8)]),
         Expr::Id(s) => {
             if s == "input" {
                 return Ok(vec![Instr::MovFromReg(Reg::Rax, Reg::Rdi)]);
@@ -36,9 +58,15 @@ pub fn compile_expr_to_instr(e: &Expr, ctx: &mut Context<'_>) -> std::io::Result
                         Instr::Mov(Reg::Rax, *ptr),
                         Instr::MovRaxFromRaxPtr,
                     ]),
-                    None => match ctx.define_env.get(s) {
-                        Some(value) => Ok(vec![Instr::Mov(Reg::Rax, *value)]),
-                        None => Err(std::io::Error::new(std::io::ErrorKind::Other, format!("Unbound variable identifier {}", s))),
+                    None => match ctx.globals.and_then(|globals| globals.get(s)) {
+                        Some(label) => Ok(vec![
+                            Instr::LeaLabel(Reg::Rax, label.clone()),
+                            Instr::MovRaxFromRaxPtr,
+                        ]),
+                        None => match ctx.define_env.get(s) {
+                            Some(value) => Ok(vec![Instr::Mov(Reg::Rax, *value)]),
+                            None => Err(std::io::Error::other(format!("Unbound variable identifier {}", s))),
+                        }
                     }
                 }
             }
@@ -49,9 +77,9 @@ pub fn compile_expr_to_instr(e: &Expr, ctx: &mut Context<'_>) -> std::io::Result
             let mut curr_env = ctx.env.clone();
 
             let mut level = HashSet::new();
-            for (v, e) in bindings {
+            for (Binder { name: v, .. }, e) in bindings {
                 if level.contains(v) {
-                    return Err(std::io::Error::new(std::io::ErrorKind::Other, "Duplicate binding"));
+                    return Err(std::io::Error::other("Duplicate binding"));
                 }
                 let e_ctx =  &mut Context { si: curr_si, env: curr_env.clone(), ..*ctx };
                 let e_instr = compile_expr_to_instr(e, e_ctx)?;
@@ -69,6 +97,7 @@ pub fn compile_expr_to_instr(e: &Expr, ctx: &mut Context<'_>) -> std::io::Result
 
             Ok(result_instr)
         },
+        Expr::LetRec(_, _) => Err(std::io::Error::other("letrec has to be lifted before compilation")),
         Expr::UnOp(op, e) => {
             let mut result_instr: Vec<Instr> = Vec::new();
 
@@ -77,32 +106,49 @@ pub fn compile_expr_to_instr(e: &Expr, ctx: &mut Context<'_>) -> std::io::Result
             result_instr.extend(e_instr);
 
             match op {
-                Op1::Add1 => {
-                    result_instr.extend(unary_not_bool_handler());
-
-                    result_instr.push(Instr::Add(Reg::Rax, 2));
-
-                    result_instr.extend(overflow_handler());
-                },
-                Op1::Sub1 => { 
-                    result_instr.extend(unary_not_bool_handler());
-
-                    result_instr.push(Instr::Sub(Reg::Rax, 2));
-
-                    result_instr.extend(overflow_handler());
+                Op1::Add1 | Op1::Sub1 => {
+                    let offset = ctx.si * 8;
+                    result_instr.push(Instr::MovToStack(Reg::Rax, offset));
+                    result_instr.push(Instr::Mov(Reg::R8, 2));
+                    result_instr.push(Instr::MovToStack(Reg::R8, offset + 8));
+                    let arith_op = if matches!(op, Op1::Add1) { ArithOp::Add } else { ArithOp::Sub };
+                    result_instr.extend(gen_arith(offset, arith_op, ctx.options.bignum));
                 },
+                /* Floats and bignums are numbers too, heap objects are checked by the runtime */
                 Op1::IsNum => {
-                    result_instr.extend(gen_istype(TypeOp::Num));
+                    result_instr.extend(gen_isnum(ctx.si * 8));
                 },
                 Op1::IsBool => {
                     result_instr.extend(gen_istype(TypeOp::Bool));
                 },
+                Op1::Not => {
+                    result_instr.extend(unary_bool_handler(ctx.si * 8));
+
+                    /* true is 3 and false is 1, so flipping the second bit negates */
+                    result_instr.push(Instr::Mov(Reg::R8, 2));
+                    result_instr.push(Instr::Xor(Reg::Rax, Reg::R8));
+                },
                 Op1::Print => {
-                    result_instr.push(Instr::MovFromReg(Reg::Rbx, Reg::Rax));
-                    result_instr.push(Instr::MovFromReg(Reg::R12, Reg::Rdi));
-                    result_instr.push(Instr::CallRustPrint(Reg::Rax));
-                    result_instr.push(Instr::MovFromReg(Reg::Rdi, Reg::R12));
-                    result_instr.push(Instr::MovFromReg(Reg::Rax, Reg::Rbx));
+                    let offset = ctx.si * 8;
+                    result_instr.push(Instr::MovToStack(Reg::Rax, offset));
+                    result_instr.push(Instr::CallRust(RustFn::Print, vec![offset], offset));
+                    result_instr.push(Instr::MovFromStack(Reg::Rax, offset));
+                },
+                Op1::Arg => {
+                    let offset = ctx.si * 8;
+                    result_instr.push(Instr::MovToStack(Reg::Rax, offset));
+                    result_instr.push(Instr::CallRust(RustFn::Arg, vec![offset], offset));
+                },
+                Op1::StringLength => {
+                    let offset = ctx.si * 8;
+                    result_instr.push(Instr::MovToStack(Reg::Rax, offset));
+                    result_instr.push(Instr::CallRust(RustFn::StringLength, vec![offset], offset));
+                },
+                Op1::ErrorCode | Op1::VectorLength => {
+                    let f = if matches!(op, Op1::ErrorCode) { RustFn::ErrorCode } else { RustFn::VectorLength };
+                    let offset = ctx.si * 8;
+                    result_instr.push(Instr::MovToStack(Reg::Rax, offset));
+                    result_instr.push(Instr::CallRust(f, vec![offset], offset));
                 }
             }
 
@@ -118,57 +164,165 @@ pub fn compile_expr_to_instr(e: &Expr, ctx: &mut Context<'_>) -> std::io::Result
             let e2_instr = compile_expr_to_instr(e2, e2_ctx)?;
 
             match op {
-                Op2::Plus => {
+                Op2::Plus | Op2::Minus | Op2::Times | Op2::Divide => {
                     result_instr.extend(e1_instr);
                     result_instr.push(Instr::MovToStack(Reg::Rax, stack_offset));
                     result_instr.extend(e2_instr);
-
-                    result_instr.extend(at_least_one_bool_handler(stack_offset));
-
-                    result_instr.push(Instr::AddRaxMemFromStack(stack_offset));
-                    result_instr.extend(overflow_handler());
-                }
-                Op2::Minus => {
-                    result_instr.extend(compile_expr_to_instr(e2, &mut Context { env: ctx.env.clone(), ..*ctx })?);
-                    result_instr.push(Instr::MovToStack(Reg::Rax, stack_offset));
-                    result_instr.extend(compile_expr_to_instr(e1, &mut Context { si: ctx.si + 1, env: ctx.env.clone(), ..*ctx })?);
-
-                    result_instr.extend(at_least_one_bool_handler(stack_offset));
-
-                    result_instr.push(Instr::SubRaxMemFromStack(stack_offset));
-
-                    result_instr.extend(overflow_handler());
-                }
-                Op2::Times => {
+                    result_instr.push(Instr::MovToStack(Reg::Rax, stack_offset + 8));
+                    let arith_op = match op {
+                        Op2::Plus => ArithOp::Add,
+                        Op2::Minus => ArithOp::Sub,
+                        Op2::Times => ArithOp::Mul,
+                        _ => ArithOp::Div,
+                    };
+                    result_instr.extend(gen_arith(stack_offset, arith_op, ctx.options.bignum));
+                },
+                Op2::Modulo => {
+                    result_instr.extend(gen_divide(e1_instr, e2_instr, stack_offset, DivOp::Modulo));
+                },
+                Op2::Remainder => {
+                    result_instr.extend(gen_divide(e1_instr, e2_instr, stack_offset, DivOp::Remainder));
+                },
+                Op2::BitAnd | Op2::BitOr | Op2::BitXor => {
+                    /* Bitwise operations on two tagged numbers keep the tag bit clear */
                     result_instr.extend(e1_instr);
                     result_instr.push(Instr::MovToStack(Reg::Rax, stack_offset));
                     result_instr.extend(e2_instr);
                     result_instr.extend(at_least_one_bool_handler(stack_offset));
 
-                    result_instr.push(Instr::MulRaxMemFromStack(stack_offset));
-
-                    result_instr.extend(overflow_handler());
-                    result_instr.push(Instr::ShiftArithmeticRight(Reg::Rax, 1));
-
-                },
-                Op2::Equal => {
-                    result_instr.extend(gen_compare(e1_instr, e2_instr, stack_offset, CmpOp::Equal));
+                    result_instr.push(Instr::MovFromStack(Reg::R8, stack_offset));
+                    match op {
+                        Op2::BitAnd => result_instr.push(Instr::And(Reg::Rax, Reg::R8)),
+                        Op2::BitOr => result_instr.push(Instr::Or(Reg::Rax, Reg::R8)),
+                        _ => result_instr.push(Instr::Xor(Reg::Rax, Reg::R8)),
+                    }
                 },
-                Op2::Greater => {
-                    result_instr.extend(gen_compare(e1_instr, e2_instr, stack_offset, CmpOp::Greater));
+                Op2::ShiftLeft => {
+                    result_instr.extend(gen_shift(e1_instr, e2_instr, stack_offset, ShiftOp::Left));
                 },
-                Op2::GreaterEqual => {
-                    result_instr.extend(gen_compare(e1_instr, e2_instr, stack_offset, CmpOp::GreaterEqual));
+                Op2::ShiftRight => {
+                    result_instr.extend(gen_shift(e1_instr, e2_instr, stack_offset, ShiftOp::Right));
                 },
-                Op2::Less => {
-                    result_instr.extend(gen_compare(e1_instr, e2_instr, stack_offset, CmpOp::Less));
+                Op2::StringAppend | Op2::StringEqual | Op2::VectorRef => {
+                    let f = match op {
+                        Op2::StringAppend => RustFn::StringAppend,
+                        Op2::StringEqual => RustFn::StringEqual,
+                        _ => RustFn::VectorRef,
+                    };
+                    result_instr.extend(e1_instr);
+                    result_instr.push(Instr::MovToStack(Reg::Rax, stack_offset));
+                    result_instr.extend(e2_instr);
+                    result_instr.push(Instr::MovToStack(Reg::Rax, stack_offset + 8));
+                    result_instr.push(Instr::CallRust(f, vec![stack_offset, stack_offset + 8], stack_offset + 8));
                 },
-                Op2::LessEqual => {
-                    result_instr.extend(gen_compare(e1_instr, e2_instr, stack_offset, CmpOp::LessEqual));
+                Op2::Equal | Op2::Greater | Op2::GreaterEqual | Op2::Less | Op2::LessEqual => {
+                    let cmp_op = match op {
+                        Op2::Equal => CmpOp::Equal,
+                        Op2::Greater => CmpOp::Greater,
+                        Op2::GreaterEqual => CmpOp::GreaterEqual,
+                        Op2::Less => CmpOp::Less,
+                        _ => CmpOp::LessEqual,
+                    };
+                    result_instr.extend(gen_compare(e1_instr, e2_instr, stack_offset, cmp_op, ctx.options.bignum));
                 }
             }
             Ok(result_instr)
         },
+        Expr::Substring(s, start, end) => {
+            let mut result_instr: Vec<Instr> = Vec::new();
+
+            let offset = ctx.si * 8;
+            result_instr.extend(compile_expr_to_instr(s, &mut Context { env: ctx.env.clone(), ..*ctx })?);
+            result_instr.push(Instr::MovToStack(Reg::Rax, offset));
+            result_instr.extend(compile_expr_to_instr(start, &mut Context { si: ctx.si + 1, env: ctx.env.clone(), ..*ctx })?);
+            result_instr.push(Instr::MovToStack(Reg::Rax, offset + 8));
+            result_instr.extend(compile_expr_to_instr(end, &mut Context { si: ctx.si + 2, env: ctx.env.clone(), ..*ctx })?);
+            result_instr.push(Instr::MovToStack(Reg::Rax, offset + 16));
+            result_instr.push(Instr::CallRust(RustFn::Substring, vec![offset, offset + 8, offset + 16], offset + 16));
+
+            Ok(result_instr)
+        },
+        Expr::MakeStruct(id, fields) => compile_object(fields, RustFn::AllocStruct, &[*id, fields.len() as i64], ctx),
+        Expr::MakeVector(elements) => compile_object(elements, RustFn::AllocVector, &[elements.len() as i64], ctx),
+        Expr::GetField(id, index, e) => {
+            let mut result_instr = compile_expr_to_instr(e, &mut Context { env: ctx.env.clone(), ..*ctx })?;
+
+            let label_id = next_id();
+            let fail_label = format!("wrong_struct{}", label_id);
+            let ok_label = format!("struct_ok{}", label_id);
+            result_instr.extend(gen_struct_check(*id, &fail_label));
+            result_instr.push(Instr::Jmp(ok_label.clone()));
+            result_instr.push(Instr::Label(fail_label));
+            result_instr.push(Instr::CallRustError(8, ctx.si * 8));
+            result_instr.push(Instr::Label(ok_label));
+            result_instr.push(Instr::MovFromPtr(Reg::Rax, Reg::Rax, 11 + 8 * *index as i32));
+
+            Ok(result_instr)
+        },
+        Expr::IsStruct(id, e) => {
+            let mut result_instr = compile_expr_to_instr(e, &mut Context { env: ctx.env.clone(), ..*ctx })?;
+
+            let label_id = next_id();
+            let false_label = format!("not_struct{}", label_id);
+            let end_label = format!("is_struct_end{}", label_id);
+            result_instr.extend(gen_struct_check(*id, &false_label));
+            result_instr.push(Instr::Mov(Reg::Rax, 3));
+            result_instr.push(Instr::Jmp(end_label.clone()));
+            result_instr.push(Instr::Label(false_label));
+            result_instr.push(Instr::Mov(Reg::Rax, 1));
+            result_instr.push(Instr::Label(end_label));
+
+            Ok(result_instr)
+        },
+        Expr::SetField(e, field, value) => {
+            /* Every declared struct and variant with a field of that name is known by now, so at run time
+               only the struct id is compared and the field is stored at its index */
+            let candidates = structs_with_field(field);
+            if candidates.is_empty() {
+                return Err(std::io::Error::other(format!("unknown field {}", field)));
+            }
+            let mut result_instr: Vec<Instr> = Vec::new();
+
+            let offset = ctx.si * 8;
+            result_instr.extend(compile_expr_to_instr(e, &mut Context { env: ctx.env.clone(), ..*ctx })?);
+            result_instr.push(Instr::MovToStack(Reg::Rax, offset));
+            result_instr.extend(compile_expr_to_instr(value, &mut Context { si: ctx.si + 1, env: ctx.env.clone(), ..*ctx })?);
+            result_instr.push(Instr::MovToStack(Reg::Rax, offset + 8));
+            result_instr.push(Instr::MovFromStack(Reg::Rax, offset));
+
+            let label_id = next_id();
+            let end_label = format!("set_field_end{}", label_id);
+            for (candidate, (id, index)) in candidates.iter().enumerate() {
+                let next_label = format!("set_field_next{}_{}", label_id, candidate);
+                result_instr.extend(gen_struct_check(*id, &next_label));
+                result_instr.push(Instr::MovFromStack(Reg::R11, offset + 8));
+                result_instr.push(Instr::MovToPtr(Reg::Rax, 11 + 8 * *index as i32, Reg::R11));
+                result_instr.push(Instr::Jmp(end_label.clone()));
+                result_instr.push(Instr::Label(next_label));
+            }
+            result_instr.push(Instr::CallRustError(8, offset + 8));
+            result_instr.push(Instr::Label(end_label));
+            result_instr.push(Instr::MovFromStack(Reg::Rax, offset + 8));
+
+            Ok(result_instr)
+        },
+        Expr::And(_) | Expr::Or(_) => {
+            let mut result_instr: Vec<Instr> = Vec::new();
+
+            let id = next_id();
+            let false_label = format!("logic_false{}", id);
+            let end_label = format!("logic_end{}", id);
+
+            let inner_ctx = &mut Context { env: ctx.env.clone(), ..*ctx };
+            result_instr.extend(compile_jump_if_false(e, &false_label, inner_ctx)?);
+            result_instr.push(Instr::Mov(Reg::Rax, 3));
+            result_instr.push(Instr::Jmp(end_label.clone()));
+            result_instr.push(Instr::Label(false_label));
+            result_instr.push(Instr::Mov(Reg::Rax, 1));
+            result_instr.push(Instr::Label(end_label));
+
+            Ok(result_instr)
+        },
         Expr::If(cond, ifbr, elbr) => {
             let mut result_instr : Vec<Instr> = Vec::new();
 
@@ -177,15 +331,13 @@ pub fn compile_expr_to_instr(e: &Expr, ctx: &mut Context<'_>) -> std::io::Result
             let end_label = format!("if_statement_end{}", id);
 
             let cond_ctx = &mut Context { env: ctx.env.clone(), ..*ctx };
-            let cond_instr = compile_expr_to_instr(cond, cond_ctx)?;
+            let cond_instr = compile_branch(cond, &else_label, false, cond_ctx)?;
             let ifbr_ctx = &mut Context { env: ctx.env.clone(), ..*ctx };
             let ifbr_instr = compile_expr_to_instr(ifbr, ifbr_ctx)?;
             let elsebr_ctx = &mut Context { si: ctx.si + 1, env: ctx.env.clone(), ..*ctx };
             let elsebr_instr = compile_expr_to_instr(elbr, elsebr_ctx)?;
 
             result_instr.extend(cond_instr);
-            result_instr.push(Instr::Compare(Reg::Rax));
-            result_instr.push(Instr::Jne(else_label.to_string()));
             result_instr.extend(ifbr_instr);
             result_instr.push(Instr::Jmp(end_label.to_string()));
             result_instr.push(Instr::Label(else_label.to_string()));
@@ -194,14 +346,165 @@ pub fn compile_expr_to_instr(e: &Expr, ctx: &mut Context<'_>) -> std::io::Result
 
             Ok(result_instr)
         },
-        Expr::Loop(e) => {
+        Expr::Cond(arms, default) => {
+            let mut result_instr: Vec<Instr> = Vec::new();
+
+            let id = next_id();
+            let end_label = format!("cond_end{}", id);
+
+            for (index, (test, body)) in arms.iter().enumerate() {
+                let next_label = format!("cond_next{}_{}", id, index);
+
+                let test_ctx = &mut Context { env: ctx.env.clone(), ..*ctx };
+                result_instr.extend(compile_branch(test, &next_label, false, test_ctx)?);
+                let body_ctx = &mut Context { env: ctx.env.clone(), ..*ctx };
+                result_instr.extend(compile_expr_to_instr(body, body_ctx)?);
+                result_instr.push(Instr::Jmp(end_label.clone()));
+                result_instr.push(Instr::Label(next_label));
+            }
+
+            match default {
+                Some(body) => {
+                    let body_ctx = &mut Context { env: ctx.env.clone(), ..*ctx };
+                    result_instr.extend(compile_expr_to_instr(body, body_ctx)?);
+                }
+                None => result_instr.push(Instr::Mov(Reg::Rax, 7)),
+            }
+            result_instr.push(Instr::Label(end_label));
+
+            Ok(result_instr)
+        },
+        Expr::When(cond, body) | Expr::Unless(cond, body) => {
+            let mut result_instr: Vec<Instr> = Vec::new();
+
+            let id = next_id();
+            let skip_label = format!("when_skip{}", id);
+            let end_label = format!("when_end{}", id);
+
+            let run_on_true = matches!(e, Expr::When(_, _));
+            let cond_ctx = &mut Context { env: ctx.env.clone(), ..*ctx };
+            result_instr.extend(compile_branch(cond, &skip_label, !run_on_true, cond_ctx)?);
+            let body_ctx = &mut Context { env: ctx.env.clone(), ..*ctx };
+            result_instr.extend(compile_expr_to_instr(body, body_ctx)?);
+            result_instr.push(Instr::Jmp(end_label.clone()));
+            result_instr.push(Instr::Label(skip_label));
+            result_instr.push(Instr::Mov(Reg::Rax, 7));
+            result_instr.push(Instr::Label(end_label));
+
+            Ok(result_instr)
+        },
+        Expr::Case(scrutinee, arms, default) => {
+            let scrutinee_ctx = &mut Context { env: ctx.env.clone(), ..*ctx };
+            let mut result_instr = compile_expr_to_instr(scrutinee, scrutinee_ctx)?;
+            let default_instr = match default {
+                Some(body) => compile_expr_to_instr(body, &mut Context { env: ctx.env.clone(), ..*ctx })?,
+                None => vec![Instr::Mov(Reg::Rax, 7)],
+            };
+            result_instr.extend(compile_case(arms, default_instr, ctx)?);
+            Ok(result_instr)
+        },
+        /* The scrutinee is kept in a hidden slot and dispatched on its struct id like a case on numbers,
+           each arm loads the fields it binds into fresh slots. Exhaustiveness is checked by the resolver,
+           so reaching the default without a wildcard means the value is not of the matched enum. */
+        Expr::Match(scrutinee, arms, _) => {
+            let mut result_instr = compile_expr_to_instr(scrutinee, &mut Context { env: ctx.env.clone(), ..*ctx })?;
+
+            let hidden = format!("#match{}", next_id());
+            result_instr.push(Instr::MovToStack(Reg::Rax, ctx.si * 8));
+            result_instr.extend(gen_struct_tag());
+
+            let mut arms_env = ctx.env.clone();
+            arms_env.insert(hidden.clone(), ctx.si);
+            let arms_ctx = &mut Context { si: ctx.si + 1, env: arms_env, ..*ctx };
+
+            let mut case_arms: Vec<(Vec<Expr>, Expr)> = Vec::new();
+            let mut default_instr = vec![Instr::CallRustError(9, ctx.si * 8)];
+            for (pattern, body) in arms {
+                match pattern {
+                    Pattern::Variant(_, id, _, _) => {
+                        let bindings: Vec<(Binder, Expr)> = pattern.bindings().into_iter()
+                            .map(|(index, name)| (name.clone(), Expr::GetField(*id, index, Box::new(Expr::Id(hidden.clone())))))
+                            .collect();
+                        case_arms.push((vec![Expr::Number(*id)], Expr::Let(bindings, Box::new(body.clone()))));
+                    }
+                    Pattern::Wildcard => {
+                        default_instr = compile_expr_to_instr(body, &mut Context { env: arms_ctx.env.clone(), ..*arms_ctx })?;
+                    }
+                }
+            }
+            result_instr.extend(compile_case(&case_arms, default_instr, arms_ctx)?);
+            Ok(result_instr)
+        },
+        /* Conditions made of literals are decided here, a false one is reported like the runtime would */
+        Expr::Assert(cond, message) => {
+            if ctx.options.no_contracts {
+                return Ok(vec![Instr::Mov(Reg::Rax, 7)]);
+            }
+            /* An assertion that can't fail costs nothing, one that always fails still only fails when it runs */
+            let ok_label = format!("assert_ok{}", next_id());
+            let mut result_instr = match constant_bool(cond) {
+                Some(true) => return Ok(vec![Instr::Mov(Reg::Rax, 7)]),
+                Some(false) => vec![],
+                None => compile_branch(cond, &ok_label, true, &mut Context { env: ctx.env.clone(), ..*ctx })?,
+            };
+            result_instr.extend(gen_static_object(string_words(message), ctx));
+            result_instr.push(Instr::MovToStack(Reg::Rax, ctx.si * 8));
+            result_instr.push(Instr::CallRust(RustFn::AssertFailed, vec![ctx.si * 8], ctx.si * 8));
+            result_instr.push(Instr::Label(ok_label));
+            result_instr.push(Instr::Mov(Reg::Rax, 7));
+            Ok(result_instr)
+        },
+        Expr::Raise(e) => {
+            let mut result_instr = compile_expr_to_instr(e, &mut Context { env: ctx.env.clone(), ..*ctx })?;
+            result_instr.push(Instr::MovToStack(Reg::Rax, ctx.si * 8));
+            result_instr.push(Instr::CallRust(RustFn::Raise, vec![ctx.si * 8], ctx.si * 8));
+            Ok(result_instr)
+        },
+        /* The handler frame takes the four slots at si, see snek_raise. The body runs above it with the
+           handler installed, a raise comes back at the catch label with the stack pointer and input
+           restored, the handler already removed and the raised value in rax. */
+        Expr::Try(body, name, handler) => {
+            let mut result_instr: Vec<Instr> = Vec::new();
+
+            let id = next_id();
+            let catch_label = format!("try_catch{}", id);
+            let end_label = format!("try_end{}", id);
+            let frame_offset = ctx.si * 8;
+            let top_offset = (ctx.si + 4) * 8;
+
+            result_instr.push(Instr::MovToStack(Reg::Rsp, frame_offset + 8));
+            result_instr.push(Instr::MovToStack(Reg::Rdi, frame_offset + 16));
+            result_instr.push(Instr::LeaLabel(Reg::R8, catch_label.clone()));
+            result_instr.push(Instr::MovToStack(Reg::R8, frame_offset + 24));
+            result_instr.push(Instr::MovFromReg(Reg::R8, Reg::Rsp));
+            result_instr.push(Instr::Sub(Reg::R8, frame_offset));
+            result_instr.push(Instr::MovToStack(Reg::R8, top_offset));
+            result_instr.push(Instr::CallRust(RustFn::PushHandler, vec![top_offset], top_offset));
+
+            let body_ctx = &mut Context { si: ctx.si + 4, env: ctx.env.clone(), handlers: ctx.handlers + 1, ..*ctx };
+            result_instr.extend(compile_expr_to_instr(body, body_ctx)?);
+            result_instr.extend(gen_pop_handlers(1, top_offset));
+            result_instr.push(Instr::Jmp(end_label.clone()));
+
+            result_instr.push(Instr::Label(catch_label));
+            result_instr.push(Instr::MovToStack(Reg::Rax, frame_offset));
+            let mut handler_env = ctx.env.clone();
+            handler_env.insert(name.name.clone(), ctx.si);
+            let handler_ctx = &mut Context { si: ctx.si + 1, env: handler_env, ..*ctx };
+            result_instr.extend(compile_expr_to_instr(handler, handler_ctx)?);
+            result_instr.push(Instr::Label(end_label));
+
+            Ok(result_instr)
+        },
+        Expr::Loop(label, e) => {
             let mut result_instr: Vec<Instr> = Vec::new();
 
             let id = next_id();
             let start_label = format!("loop_start{}", id);
             let end_label = format!("loop_end{}", id);
 
-            let inner_ctx = &mut Context { curr_break: id, env: ctx.env.clone(), ..*ctx };
+            let scope = LoopScope { id, label: label.clone(), parent: ctx.loops, handlers: ctx.handlers };
+            let inner_ctx = &mut Context { loops: Some(&scope), env: ctx.env.clone(), ..*ctx };
             let inner_instr = compile_expr_to_instr(e, inner_ctx)?;
 
             result_instr.push(Instr::Label(start_label.to_string()));
@@ -211,22 +514,30 @@ pub fn compile_expr_to_instr(e: &Expr, ctx: &mut Context<'_>) -> std::io::Result
 
             Ok(result_instr)
         },
-        Expr::Break(e) => {
-            if ctx.curr_break == 0 {
-                return Err(std::io::Error::new(std::io::ErrorKind::Other, "break outside of a loop"));
-            }
+        Expr::Break(target, e) => {
             let mut result_instr: Vec<Instr> = Vec::new();
 
-            let label = format!("loop_end{}", ctx.curr_break);
+            let scope = ctx.find_loop(target)?;
+            let label = format!("loop_end{}", scope.id);
 
             let inner_ctx = &mut Context { env: ctx.env.clone(), ..*ctx };
             let inner_instr = compile_expr_to_instr(e, inner_ctx)?;
 
             result_instr.extend(inner_instr);
+            result_instr.extend(gen_pop_handlers(ctx.handlers - scope.handlers, ctx.si * 8));
             result_instr.push(Instr::Jmp(label.to_string()));
 
             Ok(result_instr)
-        }, 
+        },
+        Expr::Continue(target) => {
+            if target.is_none() && ctx.loops.is_none() {
+                return Err(std::io::Error::other("continue outside of a loop"));
+            }
+            let scope = ctx.find_loop(target)?;
+            let mut result_instr = gen_pop_handlers(ctx.handlers - scope.handlers, ctx.si * 8);
+            result_instr.push(Instr::Jmp(format!("loop_start{}", scope.id)));
+            Ok(result_instr)
+        },
         Expr::Set(s, e) => {
             let mut result_instr: Vec<Instr> = Vec::new();
 
@@ -246,7 +557,14 @@ pub fn compile_expr_to_instr(e: &Expr, ctx: &mut Context<'_>) -> std::io::Result
                         result_instr.push(Instr::MovToPtrFromReg(Reg::Rdx, Reg::Rax));
                         Ok(result_instr)
                     }
-                    None => Err(std::io::Error::new(std::io::ErrorKind::Other, format!("Unbound variable identifier {}", s))),
+                    None => match ctx.globals.and_then(|globals| globals.get(s)) {
+                        Some(label) => {
+                            result_instr.push(Instr::LeaLabel(Reg::Rdx, label.clone()));
+                            result_instr.push(Instr::MovToPtrFromReg(Reg::Rdx, Reg::Rax));
+                            Ok(result_instr)
+                        }
+                        None => Err(std::io::Error::other(format!("Unbound variable identifier {}", s))),
+                    }
                 }
             }
         },
@@ -260,17 +578,17 @@ pub fn compile_expr_to_instr(e: &Expr, ctx: &mut Context<'_>) -> std::io::Result
 
             Ok(result_instr)
         },
-        Expr::Call(name, args) => {
+        Expr::Call(name, args, _) => {
             let mut result_instr: Vec<Instr> = Vec::new();
             let id = next_id();
             let aftercall_label = format!("after_call_{}_{}", name, id);
-            let function_label = format!("function_{}_call_label", name);
+            let function_label = function_label(name);
 
             result_instr.push(Instr::Comment(format!("START of call to {} [{} arg{}]", name, args.len(), if args.len() == 1 { "" } else { "s" })));
 
             result_instr.push(Instr::MovLabel(aftercall_label.clone(), ctx.si * 8));
 
-            let safe_si = 2 + ctx.si + args.len() as i32 + args.iter().map(|a| recursively_collet_depth(a)).max().unwrap_or(0);
+            let safe_si = 2 + ctx.si + args.len() as i32 + args.iter().map(recursively_collet_depth).max().unwrap_or(0);
             for (index, arg) in args.iter().enumerate() {
                 let arc_ctx = &mut Context { si: safe_si, env: ctx.env.clone(), ..*ctx };
                 let arg_instr = compile_expr_to_instr(arg, arc_ctx)?;
@@ -289,22 +607,237 @@ pub fn compile_expr_to_instr(e: &Expr, ctx: &mut Context<'_>) -> std::io::Result
     }
 }
 
+/* Jumps to the label when the condition evaluates to jump_on, falls through otherwise */
+fn compile_branch(cond: &Expr, label: &str, jump_on: bool, ctx: &mut Context<'_>) -> std::io::Result<Vec<Instr>> {
+    match cond {
+        Expr::And(_) | Expr::Or(_) | Expr::UnOp(Op1::Not, _) => {
+            if jump_on { compile_jump_if_true(cond, label, ctx) } else { compile_jump_if_false(cond, label, ctx) }
+        }
+        _ => {
+            let mut result_instr = compile_expr_to_instr(cond, ctx)?;
+            result_instr.push(Instr::Compare(Reg::Rax));
+            if jump_on {
+                result_instr.push(Instr::Je(label.to_string()));
+            } else {
+                result_instr.push(Instr::Jne(label.to_string()));
+            }
+            Ok(result_instr)
+        }
+    }
+}
+
+fn case_key_value(key: &Expr) -> std::io::Result<i64> {
+    match key {
+        Expr::Number(n) => Ok(n << 1),
+        Expr::Boolean(true) => Ok(3),
+        Expr::Boolean(false) => Ok(1),
+        _ => Err(std::io::Error::other("case keys should be number or boolean literals")),
+    }
+}
+
+/* Expects the scrutinee in rax. Dense numeric keys are dispatched through a table of
+   5 byte jumps indexed by the untagged value, everything else through a compare chain.
+   The default instructions run when no key matches. */
+fn compile_case(arms: &[(Vec<Expr>, Expr)], default_instr: Vec<Instr>, ctx: &mut Context<'_>) -> std::io::Result<Vec<Instr>> {
+    let mut result_instr: Vec<Instr> = Vec::new();
+
+    let id = next_id();
+    let default_label = format!("case_default{}", id);
+    let end_label = format!("case_end{}", id);
+
+    let mut keys: Vec<(i64, usize)> = Vec::new();
+    for (index, (literals, _)) in arms.iter().enumerate() {
+        for literal in literals {
+            let value = case_key_value(literal)?;
+            if keys.iter().any(|(k, _)| *k == value) {
+                return Err(std::io::Error::other("Duplicate case key"));
+            }
+            keys.push((value, index));
+        }
+    }
+
+    let all_numbers = arms.iter().all(|(literals, _)| literals.iter().all(|l| matches!(l, Expr::Number(_))));
+    let min = keys.iter().map(|(k, _)| k >> 1).min().unwrap_or(0);
+    let max = keys.iter().map(|(k, _)| k >> 1).max().unwrap_or(0);
+    let span = max as i128 - min as i128 + 1;
+    let dense = all_numbers && keys.len() >= 4 && span <= 2 * keys.len() as i128;
+
+    if dense {
+        let table_label = format!("case_table{}", id);
+
+        result_instr.push(Instr::Test(Reg::Rax, 1));
+        result_instr.push(Instr::Jne(default_label.clone()));
+        result_instr.push(Instr::MovFromReg(Reg::R11, Reg::Rax));
+        result_instr.push(Instr::ShiftArithmeticRight(Reg::R11, 1));
+        result_instr.push(Instr::Mov(Reg::R8, min));
+        result_instr.push(Instr::SubReg(Reg::R11, Reg::R8));
+        result_instr.push(Instr::Jo(default_label.clone()));
+        result_instr.push(Instr::CompareImm(Reg::R11, 0));
+        result_instr.push(Instr::Jl(default_label.clone()));
+        result_instr.push(Instr::CompareImm(Reg::R11, (span - 1) as i32));
+        result_instr.push(Instr::Jg(default_label.clone()));
+
+        /* index * 5 to step over whole jmp instructions */
+        result_instr.push(Instr::MovFromReg(Reg::R8, Reg::R11));
+        result_instr.push(Instr::ShiftLeft(Reg::R11, 2));
+        result_instr.push(Instr::AddReg(Reg::R11, Reg::R8));
+        result_instr.push(Instr::LeaLabel(Reg::R8, table_label.clone()));
+        result_instr.push(Instr::AddReg(Reg::R8, Reg::R11));
+        result_instr.push(Instr::JmpToReg(Reg::R8));
+
+        result_instr.push(Instr::Label(table_label));
+        for offset in 0..span as i64 {
+            let value = (min + offset) << 1;
+            match keys.iter().find(|(k, _)| *k == value) {
+                Some((_, index)) => result_instr.push(Instr::JumpTableEntry(format!("case_arm{}_{}", id, index))),
+                None => result_instr.push(Instr::JumpTableEntry(default_label.clone())),
+            }
+        }
+    } else {
+        for (value, index) in &keys {
+            result_instr.push(Instr::Mov(Reg::R8, *value));
+            result_instr.push(Instr::CompareRegs(Reg::Rax, Reg::R8));
+            result_instr.push(Instr::Je(format!("case_arm{}_{}", id, index)));
+        }
+        result_instr.push(Instr::Jmp(default_label.clone()));
+    }
+
+    for (index, (_, body)) in arms.iter().enumerate() {
+        result_instr.push(Instr::Label(format!("case_arm{}_{}", id, index)));
+        let body_ctx = &mut Context { env: ctx.env.clone(), ..*ctx };
+        result_instr.extend(compile_expr_to_instr(body, body_ctx)?);
+        result_instr.push(Instr::Jmp(end_label.clone()));
+    }
+
+    result_instr.push(Instr::Label(default_label));
+    result_instr.extend(default_instr);
+    result_instr.push(Instr::Label(end_label));
+
+    Ok(result_instr)
+}
+
+/* Short-circuit chains: each operand of and/or/not is checked to be a boolean
+   and jumps straight to the target label instead of producing a value first. */
+fn compile_jump_if_false(e: &Expr, false_label: &str, ctx: &mut Context<'_>) -> std::io::Result<Vec<Instr>> {
+    let mut result_instr: Vec<Instr> = Vec::new();
+    match e {
+        Expr::And(es) => {
+            for expr in es {
+                result_instr.extend(compile_jump_if_false(expr, false_label, ctx)?);
+            }
+        },
+        Expr::Or(es) => match es.split_last() {
+            Some((last, rest)) => {
+                let true_label = format!("or_true{}", next_id());
+                for expr in rest {
+                    result_instr.extend(compile_jump_if_true(expr, &true_label, ctx)?);
+                }
+                result_instr.extend(compile_jump_if_false(last, false_label, ctx)?);
+                result_instr.push(Instr::Label(true_label));
+            }
+            None => result_instr.push(Instr::Jmp(false_label.to_string())),
+        },
+        Expr::UnOp(Op1::Not, inner) => {
+            result_instr.extend(compile_jump_if_true(inner, false_label, ctx)?);
+        },
+        _ => {
+            let inner_ctx = &mut Context { env: ctx.env.clone(), ..*ctx };
+            result_instr.extend(compile_expr_to_instr(e, inner_ctx)?);
+            result_instr.extend(unary_bool_handler(ctx.si * 8));
+            result_instr.push(Instr::Compare(Reg::Rax));
+            result_instr.push(Instr::Jne(false_label.to_string()));
+        }
+    }
+    Ok(result_instr)
+}
+
+fn compile_jump_if_true(e: &Expr, true_label: &str, ctx: &mut Context<'_>) -> std::io::Result<Vec<Instr>> {
+    let mut result_instr: Vec<Instr> = Vec::new();
+    match e {
+        Expr::Or(es) => {
+            for expr in es {
+                result_instr.extend(compile_jump_if_true(expr, true_label, ctx)?);
+            }
+        },
+        Expr::And(es) => match es.split_last() {
+            Some((last, rest)) => {
+                let false_label = format!("and_false{}", next_id());
+                for expr in rest {
+                    result_instr.extend(compile_jump_if_false(expr, &false_label, ctx)?);
+                }
+                result_instr.extend(compile_jump_if_true(last, true_label, ctx)?);
+                result_instr.push(Instr::Label(false_label));
+            }
+            None => result_instr.push(Instr::Jmp(true_label.to_string())),
+        },
+        Expr::UnOp(Op1::Not, inner) => {
+            result_instr.extend(compile_jump_if_false(inner, true_label, ctx)?);
+        },
+        _ => {
+            let inner_ctx = &mut Context { env: ctx.env.clone(), ..*ctx };
+            result_instr.extend(compile_expr_to_instr(e, inner_ctx)?);
+            result_instr.extend(unary_bool_handler(ctx.si * 8));
+            result_instr.push(Instr::Compare(Reg::Rax));
+            result_instr.push(Instr::Je(true_label.to_string()));
+        }
+    }
+    Ok(result_instr)
+}
+
+/* Struct fields and vector elements are evaluated into consecutive slots first, the runtime only allocates
+   the object from the header words it is passed */
+fn compile_object(values: &[Expr], alloc: RustFn, header: &[i64], ctx: &mut Context<'_>) -> std::io::Result<Vec<Instr>> {
+    let mut result_instr: Vec<Instr> = Vec::new();
+
+    for (index, value) in values.iter().enumerate() {
+        let value_si = ctx.si + index as i32;
+        result_instr.extend(compile_expr_to_instr(value, &mut Context { si: value_si, env: ctx.env.clone(), ..*ctx })?);
+        result_instr.push(Instr::MovToStack(Reg::Rax, value_si * 8));
+    }
+
+    let header_offset = (ctx.si + values.len() as i32) * 8;
+    let mut args = Vec::new();
+    for (index, word) in header.iter().enumerate() {
+        let offset = header_offset + 8 * index as i32;
+        result_instr.push(Instr::Mov(Reg::R8, *word));
+        result_instr.push(Instr::MovToStack(Reg::R8, offset));
+        args.push(offset);
+    }
+    let frame = header_offset + 8 * (header.len() as i32 - 1);
+    result_instr.push(Instr::CallRust(alloc, args, frame));
+
+    /* rax is the tagged pointer, value i is at (rax - 5) + 16 + 8i */
+    for index in 0..values.len() as i32 {
+        result_instr.push(Instr::MovFromStack(Reg::R8, (ctx.si + index) * 8));
+        result_instr.push(Instr::MovToPtr(Reg::Rax, 11 + 8 * index, Reg::R8));
+    }
+
+    Ok(result_instr)
+}
+
+/* Where the code of a function starts, calls jump to it by name */
+pub fn function_label(name: &str) -> String {
+    format!("function_{}_call_label", name)
+}
+
 pub fn compile_defs_to_instr(defs: &Vec<Defenition>, ctx: &mut Context<'_>) -> std::io::Result<Vec<Instr>> {
     let mut result_instr: Vec<Instr> = Vec::new();
     for def in defs {
         match def {
-            Defenition::Fun(name, params, body) => {
-                result_instr.push(Instr::Comment(format!("START of function {}({})", name, params.join(", "))));
+            Defenition::Struct(_, _) | Defenition::Enum(_, _) => {
+                return Err(std::io::Error::other("struct and enum declarations have to be lowered before compilation"));
+            }
+            Defenition::Fun(name, params, _, body) => {
+                result_instr.push(Instr::Comment(format!("START of function {}({})", name, params.iter().map(|p| p.name.as_str()).collect::<Vec<&str>>().join(", "))));
 
-                let label = format!("function_{}_call_label", name.clone());
-                result_instr.push(Instr::Label(label));
+                result_instr.push(Instr::Label(function_label(name)));
 
                 let mut func_env: HashMap<String, i32> = HashMap::new();
                 for (index, param) in params.iter().enumerate() {
-                    func_env.insert(param.clone(), 1 + (index as i32));
+                    func_env.insert(param.name.clone(), 1 + (index as i32));
                 }
 
-                let body_ctx = &mut Context { si: 1 + (params.len() as i32), env: func_env, curr_break: 0, ..*ctx };
+                let body_ctx = &mut Context { si: 1 + (params.len() as i32), env: func_env, loops: None, handlers: 0, ..*ctx };
                 let body_instr = compile_expr_to_instr(body, body_ctx)?;
                 result_instr.extend(body_instr);
 
@@ -314,4 +847,52 @@ pub fn compile_defs_to_instr(defs: &Vec<Defenition>, ctx: &mut Context<'_>) -> s
         }
     }
     Ok(result_instr)
-}
\ No newline at end of file
+}
+/* The code a program starts with: the globals are set in the order they are defined, then main runs */
+pub fn program_entry(prog: &Program) -> Expr {
+    let mut body: Vec<Expr> = prog.globals.iter().map(|(name, e)| Expr::Set(name.clone(), Box::new(e.clone()))).collect();
+    body.push(prog.main.clone());
+    Expr::Block(body)
+}
+
+/* A whole program as nasm assembly, each global gets a slot in the data section */
+pub fn compile_prog_to_asm(prog: &Program, options: Options) -> std::io::Result<String> {
+    let define_env: HashMap<String, i64> = HashMap::new();
+    let define_ptrs: HashMap<String, i64> = HashMap::new();
+    let globals: HashMap<String, String> = prog.globals.iter().enumerate()
+        .map(|(index, (name, _))| (name.clone(), format!("global_{}", index)))
+        .collect();
+    let statics = RefCell::new(Vec::new());
+    let mut ctx = Context::new(&define_env, &define_ptrs).with_globals(&globals).with_statics(&statics).with_options(options);
+
+    let defs_instr = compile_defs_to_instr(&prog.defs, &mut ctx)?;
+    let main_instr = compile_expr_to_instr(&program_entry(prog), &mut ctx)?;
+
+    let mut externs: BTreeSet<&str> = BTreeSet::new();
+    for instr in defs_instr.iter().chain(&main_instr) {
+        match instr {
+            Instr::CallRust(f, _, _) => { externs.insert(rust_fn_symbol(f)); }
+            Instr::CallRustError(_, _) => { externs.insert("snek_error"); }
+            _ => {}
+        }
+    }
+
+    let mut lines = vec!["section .text".to_string()];
+    lines.extend(externs.iter().map(|symbol| format!("extern {}", symbol)));
+    lines.push("global our_code_starts_here".to_string());
+    lines.push(instrs_to_string(&defs_instr)?);
+    lines.push("our_code_starts_here:".to_string());
+    lines.push("\tpush rbx\n\tpush r12".to_string());
+    lines.push(instrs_to_string(&main_instr)?);
+    lines.push("\tpop r12\n\tpop rbx\n\tret".to_string());
+    lines.push("section .data".to_string());
+    lines.push("align 8".to_string());
+    /* Globals hold nothing until their initializer ran */
+    for index in 0..prog.globals.len() {
+        lines.push(format!("global_{}:\tdq 7", index));
+    }
+    for (label, words) in statics.into_inner() {
+        lines.push(format!("{}:\tdq {}", label, words.iter().map(|w| w.to_string()).collect::<Vec<String>>().join(", ")));
+    }
+    Ok(lines.join("\n"))
+}
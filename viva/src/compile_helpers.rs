@@ -1,8 +1,11 @@
 use std::collections::{HashMap, HashSet};
 
-use crate::instructions::{Instr, Reg};
+use crate::instructions::{Instr, Reg, Xmm, RustFn};
+use crate::runtime::{static_object, FLOAT_KIND, STRUCT_KIND};
 use crate::counter::next_id;
-use crate::expressions::{Expr};
+use crate::context::Context;
+use crate::expressions::{Expr, Op1, Op2};
+use crate::visit::{Visitor, walk_expr};
 
 #[derive(Debug, Clone, Copy)]
 pub enum CmpOp {
@@ -15,31 +18,279 @@ pub enum CmpOp {
 
 pub enum TypeOp {
     Bool,
-    Num
 }
 
+#[derive(Debug, Clone, Copy)]
+pub enum DivOp {
+    Modulo,
+    Remainder,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ShiftOp {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+/* Literal heap objects live outside of the code, in the data section of an assembly file or in the runtime
+   for jitted code, rax gets the tagged pointer */
+pub fn gen_static_object(words: Vec<i64>, ctx: &Context<'_>) -> Vec<Instr> {
+    match ctx.statics {
+        Some(statics) => {
+            let label = format!("static_object{}", next_id());
+            statics.borrow_mut().push((label.clone(), words));
+            vec![Instr::LeaLabel(Reg::Rax, label), Instr::Add(Reg::Rax, 5)]
+        }
+        None => vec![Instr::Mov(Reg::Rax, static_object(words))],
+    }
+}
+
+/* Expects a value in rax and jumps to fail_label unless it is an instance of the struct with the given id */
+pub fn gen_struct_check(id: i64, fail_label: &str) -> Vec<Instr> {
+    vec![
+        Instr::MovFromReg(Reg::R8, Reg::Rax),
+        Instr::Mov(Reg::R10, 7),
+        Instr::And(Reg::R8, Reg::R10),
+        Instr::CompareImm(Reg::R8, 5),
+        Instr::Jne(fail_label.to_string()),
+        Instr::MovFromPtr(Reg::R8, Reg::Rax, -5),
+        Instr::CompareImm(Reg::R8, STRUCT_KIND as i32),
+        Instr::Jne(fail_label.to_string()),
+        Instr::MovFromPtr(Reg::R8, Reg::Rax, 3),
+        Instr::CompareImm(Reg::R8, id as i32),
+        Instr::Jne(fail_label.to_string()),
+    ]
+}
 
-pub fn gen_compare(e1_instr: Vec<Instr>, e2_instr: Vec<Instr>, stack_offset: i32, op: CmpOp) -> Vec<Instr> {
+/* Replaces the value in rax by the id of its struct as a tagged number, or by -1 when it is not a struct */
+pub fn gen_struct_tag() -> Vec<Instr> {
+    let id = next_id();
+    let other_label = format!("no_struct_tag{}", id);
+    let end_label = format!("struct_tag_end{}", id);
+    vec![
+        Instr::MovFromReg(Reg::R8, Reg::Rax),
+        Instr::Mov(Reg::R10, 7),
+        Instr::And(Reg::R8, Reg::R10),
+        Instr::CompareImm(Reg::R8, 5),
+        Instr::Jne(other_label.clone()),
+        Instr::MovFromPtr(Reg::R8, Reg::Rax, -5),
+        Instr::CompareImm(Reg::R8, STRUCT_KIND as i32),
+        Instr::Jne(other_label.clone()),
+        Instr::MovFromPtr(Reg::Rax, Reg::Rax, 3),
+        Instr::ShiftLeft(Reg::Rax, 1),
+        Instr::Jmp(end_label.clone()),
+        Instr::Label(other_label),
+        Instr::Mov(Reg::Rax, -2),
+        Instr::Label(end_label),
+    ]
+}
+
+/* Value of a condition built only from literals, so that assertions that always hold can be left out.
+   There is no constant folding pass, anything else is left to the runtime. */
+pub fn constant_bool(e: &Expr) -> Option<bool> {
+    match e {
+        Expr::Boolean(b) => Some(*b),
+        Expr::UnOp(Op1::Not, e) => constant_bool(e).map(|b| !b),
+        Expr::And(es) => es.iter().map(constant_bool).collect::<Option<Vec<bool>>>().map(|bs| bs.iter().all(|b| *b)),
+        Expr::Or(es) => es.iter().map(constant_bool).collect::<Option<Vec<bool>>>().map(|bs| bs.iter().any(|b| *b)),
+        Expr::BinOp(op, e1, e2) => {
+            let (a, b) = (constant_number(e1)?, constant_number(e2)?);
+            match op {
+                Op2::Equal => Some(a == b),
+                Op2::Greater => Some(a > b),
+                Op2::GreaterEqual => Some(a >= b),
+                Op2::Less => Some(a < b),
+                Op2::LessEqual => Some(a <= b),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/* Only values that fit into a tagged integer, larger ones overflow at runtime */
+fn constant_number(e: &Expr) -> Option<i64> {
+    let value = match e {
+        Expr::Number(n) => Some(*n),
+        Expr::UnOp(Op1::Add1, e) => constant_number(e)?.checked_add(1),
+        Expr::UnOp(Op1::Sub1, e) => constant_number(e)?.checked_sub(1),
+        Expr::BinOp(Op2::Plus, e1, e2) => constant_number(e1)?.checked_add(constant_number(e2)?),
+        Expr::BinOp(Op2::Minus, e1, e2) => constant_number(e1)?.checked_sub(constant_number(e2)?),
+        Expr::BinOp(Op2::Times, e1, e2) => constant_number(e1)?.checked_mul(constant_number(e2)?),
+        _ => None,
+    }?;
+    if (-(1 << 62)..(1 << 62)).contains(&value) { Some(value) } else { None }
+}
+
+/* Removes the innermost handlers when break or continue leave try bodies, the value in rax is kept */
+pub fn gen_pop_handlers(count: i32, offset: i32) -> Vec<Instr> {
     let mut result: Vec<Instr> = Vec::new();
+    for _ in 0..count {
+        result.push(Instr::MovToStack(Reg::Rax, offset));
+        result.push(Instr::CallRust(RustFn::PopHandler, vec![offset], offset));
+    }
+    result
+}
 
-    result.extend(e2_instr);
-    result.push(Instr::MovToStack(Reg::Rax, stack_offset + 8));
+/* Loads a number into an SSE register as a double, integers are converted and boxed floats are unboxed.
+   Any other value jumps to other_label. */
+fn gen_load_float(offset: i32, xmm: Xmm, other_label: &str) -> Vec<Instr> {
+    let mut result: Vec<Instr> = Vec::new();
+    let id = next_id();
+    let boxed_label = format!("float_boxed{}", id);
+    let loaded_label = format!("float_loaded{}", id);
 
-    result.extend(e1_instr);
+    result.push(Instr::MovFromStack(Reg::Rax, offset));
+    result.push(Instr::Test(Reg::Rax, 1));
+    result.push(Instr::Jne(boxed_label.clone()));
+    result.push(Instr::ShiftArithmeticRight(Reg::Rax, 1));
+    result.push(Instr::Cvtsi2sd(xmm, Reg::Rax));
+    result.push(Instr::Jmp(loaded_label.clone()));
+
+    result.push(Instr::Label(boxed_label));
+    result.push(Instr::MovFromReg(Reg::R8, Reg::Rax));
+    result.push(Instr::Mov(Reg::R10, 7));
+    result.push(Instr::And(Reg::R8, Reg::R10));
+    result.push(Instr::CompareImm(Reg::R8, 5));
+    result.push(Instr::Jne(other_label.to_string()));
+    result.push(Instr::MovFromPtr(Reg::R8, Reg::Rax, -5));
+    result.push(Instr::CompareImm(Reg::R8, FLOAT_KIND as i32));
+    result.push(Instr::Jne(other_label.to_string()));
+    result.push(Instr::MovsdFromPtr(xmm, Reg::Rax, 3));
+    result.push(Instr::Label(loaded_label));
+
+    result
+}
+
+/* Arithmetic on the operands in the stack slots at stack_offset and stack_offset + 8. Two tagged integers are
+   handled inline, as soon as one operand is a float both are computed as doubles with SSE. In --bignum mode
+   overflows and boxed integers go to the runtime, otherwise they are errors. */
+pub fn gen_arith(stack_offset: i32, op: ArithOp, bignum: bool) -> Vec<Instr> {
+    let mut result: Vec<Instr> = Vec::new();
+    let id = next_id();
+    let float_label = format!("arith_float{}", id);
+    let other_label = format!("arith_other{}", id);
+    let done_label = format!("arith_done{}", id);
+
+    result.push(Instr::MovFromStack(Reg::Rax, stack_offset));
+    result.push(Instr::MovFromStack(Reg::R8, stack_offset + 8));
+    result.push(Instr::Or(Reg::R8, Reg::Rax));
+    result.push(Instr::Test(Reg::R8, 1));
+    result.push(Instr::Jne(float_label.clone()));
 
     match op {
-        CmpOp::Equal => {
-            result.extend(equal_type_handler(stack_offset + 8));
+        ArithOp::Add => result.push(Instr::AddRaxMemFromStack(stack_offset + 8)),
+        ArithOp::Sub => result.push(Instr::SubRaxMemFromStack(stack_offset + 8)),
+        ArithOp::Mul => {
+            result.push(Instr::ShiftArithmeticRight(Reg::Rax, 1));
+            result.push(Instr::MulRaxMemFromStack(stack_offset + 8));
         }
-        _ => {
-            result.extend(at_least_one_bool_handler(stack_offset + 8));
+        /* Both operands stay tagged, so the quotient comes out untagged */
+        ArithOp::Div => {
+            result.push(Instr::MovFromStack(Reg::R8, stack_offset + 8));
+            result.extend(division_by_zero_handler(Reg::R8, stack_offset + 8));
+            result.push(Instr::Cqo);
+            result.push(Instr::IDiv(Reg::R8));
+            result.push(Instr::ShiftLeft(Reg::Rax, 1));
         }
     }
+    match (bignum, op) {
+        (true, ArithOp::Add | ArithOp::Sub | ArithOp::Mul) => result.push(Instr::Jo(other_label.clone())),
+        _ => result.extend(overflow_handler(stack_offset + 8)),
+    }
+    result.push(Instr::Jmp(done_label.clone()));
+
+    result.push(Instr::Label(float_label));
+    result.extend(gen_load_float(stack_offset, Xmm::Xmm0, &other_label));
+    result.extend(gen_load_float(stack_offset + 8, Xmm::Xmm1, &other_label));
+    match op {
+        ArithOp::Add => result.push(Instr::Addsd(Xmm::Xmm0, Xmm::Xmm1)),
+        ArithOp::Sub => result.push(Instr::Subsd(Xmm::Xmm0, Xmm::Xmm1)),
+        ArithOp::Mul => result.push(Instr::Mulsd(Xmm::Xmm0, Xmm::Xmm1)),
+        ArithOp::Div => result.push(Instr::Divsd(Xmm::Xmm0, Xmm::Xmm1)),
+    }
+    result.push(Instr::MovqFromXmm(Reg::Rax, Xmm::Xmm0));
+    result.push(Instr::MovToStack(Reg::Rax, stack_offset));
+    result.push(Instr::CallRust(RustFn::BoxFloat, vec![stack_offset], stack_offset + 8));
+    result.push(Instr::Jmp(done_label.clone()));
+
+    result.push(Instr::Label(other_label));
+    match (bignum, op) {
+        (true, ArithOp::Add) => result.push(Instr::CallRust(RustFn::BigAdd, vec![stack_offset, stack_offset + 8], stack_offset + 8)),
+        (true, ArithOp::Sub) => result.push(Instr::CallRust(RustFn::BigSub, vec![stack_offset, stack_offset + 8], stack_offset + 8)),
+        (true, ArithOp::Mul) => result.push(Instr::CallRust(RustFn::BigMul, vec![stack_offset, stack_offset + 8], stack_offset + 8)),
+        _ => result.push(Instr::CallRustError(2, stack_offset + 8)),
+    }
+    result.push(Instr::Label(done_label));
+
+    result
+}
+
+/* Two tagged integers are compared inline. Otherwise equality asks the runtime, which answers with an untagged
+   number that is compared with 0 so that both paths share the conditional move at the end. Orderings compare
+   doubles with SSE, and in --bignum mode boxed integers are compared by the runtime like equality. */
+pub fn gen_compare(e1_instr: Vec<Instr>, e2_instr: Vec<Instr>, stack_offset: i32, op: CmpOp, bignum: bool) -> Vec<Instr> {
+    let mut result: Vec<Instr> = Vec::new();
+    let id = next_id();
+    let slow_label = format!("compare_slow{}", id);
+    let other_label = format!("compare_other{}", id);
+    let flags_label = format!("compare_flags{}", id);
+    let done_label = format!("compare_done{}", id);
+
+    result.extend(e1_instr);
+    result.push(Instr::MovToStack(Reg::Rax, stack_offset));
+    result.extend(e2_instr);
+    result.push(Instr::MovToStack(Reg::Rax, stack_offset + 8));
 
+    result.push(Instr::MovFromStack(Reg::R8, stack_offset));
+    result.push(Instr::Or(Reg::R8, Reg::Rax));
+    result.push(Instr::Test(Reg::R8, 1));
+    result.push(Instr::Jne(slow_label.clone()));
+    result.push(Instr::MovFromStack(Reg::Rax, stack_offset));
     result.push(Instr::CompareWithMemory(Reg::Rax, stack_offset + 8));
+    result.push(Instr::Jmp(flags_label.clone()));
+
+    result.push(Instr::Label(slow_label));
+    if let CmpOp::Equal = op {
+        result.push(Instr::CallRust(RustFn::Equal, vec![stack_offset, stack_offset + 8], stack_offset + 8));
+        result.push(Instr::CompareImm(Reg::Rax, 0));
+    } else {
+        /* ucomisd sets the flags like an unsigned compare and reports an unordered NaN as "below",
+           so less is checked as greater with swapped operands to keep every comparison with NaN false */
+        result.extend(gen_load_float(stack_offset, Xmm::Xmm0, &other_label));
+        result.extend(gen_load_float(stack_offset + 8, Xmm::Xmm1, &other_label));
+        match op {
+            CmpOp::Less | CmpOp::LessEqual => result.push(Instr::Ucomisd(Xmm::Xmm1, Xmm::Xmm0)),
+            _ => result.push(Instr::Ucomisd(Xmm::Xmm0, Xmm::Xmm1)),
+        }
+        result.push(Instr::Mov(Reg::Rax, 1));
+        result.push(Instr::Mov(Reg::R10, 3));
+        match op {
+            CmpOp::Greater | CmpOp::Less => result.push(Instr::Cmova(Reg::Rax, Reg::R10)),
+            _ => result.push(Instr::Cmovae(Reg::Rax, Reg::R10)),
+        }
+        result.push(Instr::Jmp(done_label.clone()));
+
+        result.push(Instr::Label(other_label));
+        if bignum {
+            result.push(Instr::CallRust(RustFn::BigCompare, vec![stack_offset, stack_offset + 8], stack_offset + 8));
+            result.push(Instr::CompareImm(Reg::Rax, 0));
+        } else {
+            result.push(Instr::CallRustError(2, stack_offset + 8));
+        }
+    }
+
+    result.push(Instr::Label(flags_label));
     result.push(Instr::Mov(Reg::Rax, 1));
     result.push(Instr::Mov(Reg::R10, 3));
-
     match op {
         CmpOp::Equal => result.push(Instr::Cmove(Reg::Rax, Reg::R10)),
         CmpOp::Greater => result.push(Instr::Cmovg(Reg::Rax, Reg::R10)),
@@ -47,6 +298,100 @@ pub fn gen_compare(e1_instr: Vec<Instr>, e2_instr: Vec<Instr>, stack_offset: i32
         CmpOp::Less => result.push(Instr::Cmovl(Reg::Rax, Reg::R10)),
         CmpOp::LessEqual => result.push(Instr::Cmovle(Reg::Rax, Reg::R10)),
     };
+    result.push(Instr::Label(done_label));
+
+    result
+}
+
+/* Both operands stay tagged: idiv of 2a by 2b gives the untagged quotient a / b in rax
+   and the already tagged remainder 2(a rem b) in rdx. */
+pub fn gen_divide(e1_instr: Vec<Instr>, e2_instr: Vec<Instr>, stack_offset: i32, op: DivOp) -> Vec<Instr> {
+    let mut result: Vec<Instr> = Vec::new();
+
+    result.extend(e1_instr);
+    result.push(Instr::MovToStack(Reg::Rax, stack_offset));
+    result.extend(e2_instr);
+
+    result.extend(at_least_one_bool_handler(stack_offset));
+
+    result.push(Instr::MovFromReg(Reg::R8, Reg::Rax));
+    result.extend(division_by_zero_handler(Reg::R8, stack_offset));
+
+    result.push(Instr::MovFromStack(Reg::Rax, stack_offset));
+    result.push(Instr::Cqo);
+    result.push(Instr::IDiv(Reg::R8));
+
+    match op {
+        DivOp::Remainder => {
+            result.push(Instr::MovFromReg(Reg::Rax, Reg::Rdx));
+        }
+        DivOp::Modulo => {
+            /* The remainder takes the sign of the dividend, the modulo takes the sign of the divisor */
+            let id = next_id();
+            let done_label = format!("modulo_done{}", id);
+
+            result.push(Instr::MovFromReg(Reg::Rax, Reg::Rdx));
+            result.push(Instr::CompareImm(Reg::Rax, 0));
+            result.push(Instr::Je(done_label.clone()));
+            result.push(Instr::MovFromReg(Reg::R11, Reg::Rax));
+            result.push(Instr::Xor(Reg::R11, Reg::R8));
+            result.push(Instr::CompareImm(Reg::R11, 0));
+            result.push(Instr::Jge(done_label.clone()));
+            result.push(Instr::AddReg(Reg::Rax, Reg::R8));
+            result.push(Instr::Label(done_label));
+        }
+    }
+
+    result
+}
+
+/* The shift count is untagged into rcx, negative counts are rejected and counts above 63 are clamped,
+   a left shift overflows when shifting the result back does not give the original value. */
+pub fn gen_shift(e1_instr: Vec<Instr>, e2_instr: Vec<Instr>, stack_offset: i32, op: ShiftOp) -> Vec<Instr> {
+    let mut result: Vec<Instr> = Vec::new();
+
+    let id = next_id();
+    let count_ok_label = format!("shift_count_ok{}", id);
+    let count_clamped_label = format!("shift_count_clamped{}", id);
+
+    result.extend(e1_instr);
+    result.push(Instr::MovToStack(Reg::Rax, stack_offset));
+    result.extend(e2_instr);
+
+    result.extend(at_least_one_bool_handler(stack_offset));
+
+    result.push(Instr::MovFromReg(Reg::Rcx, Reg::Rax));
+    result.push(Instr::ShiftArithmeticRight(Reg::Rcx, 1));
+    result.push(Instr::CompareImm(Reg::Rcx, 0));
+    result.push(Instr::Jge(count_ok_label.clone()));
+    result.push(Instr::CallRustError(2, stack_offset));
+    result.push(Instr::Label(count_ok_label));
+    result.push(Instr::CompareImm(Reg::Rcx, 63));
+    result.push(Instr::Jle(count_clamped_label.clone()));
+    result.push(Instr::Mov(Reg::Rcx, 63));
+    result.push(Instr::Label(count_clamped_label));
+
+    result.push(Instr::MovFromStack(Reg::Rax, stack_offset));
+
+    match op {
+        ShiftOp::Left => {
+            let no_overflow_label = format!("shift_no_overflow{}", id);
+
+            result.push(Instr::MovFromReg(Reg::R11, Reg::Rax));
+            result.push(Instr::ShiftLeftCl(Reg::Rax));
+            result.push(Instr::MovFromReg(Reg::R8, Reg::Rax));
+            result.push(Instr::ShiftArithmeticRightCl(Reg::R8));
+            result.push(Instr::CompareRegs(Reg::R8, Reg::R11));
+            result.push(Instr::Je(no_overflow_label.clone()));
+            result.push(Instr::CallRustError(1, stack_offset));
+            result.push(Instr::Label(no_overflow_label));
+        }
+        ShiftOp::Right => {
+            result.push(Instr::ShiftArithmeticRightCl(Reg::Rax));
+            result.push(Instr::Mov(Reg::R11, -2));
+            result.push(Instr::And(Reg::Rax, Reg::R11));
+        }
+    }
 
     result
 }
@@ -54,15 +399,48 @@ pub fn gen_compare(e1_instr: Vec<Instr>, e2_instr: Vec<Instr>, stack_offset: i32
 pub fn gen_istype(op: TypeOp) -> Vec<Instr> {
     let mut result: Vec<Instr> = Vec::new();
 
-    result.push(Instr::Test(Reg::Rax, 1));
+    match op {
+        /* Numbers end in a 0 bit. The odd values are false 0b001, true 0b011, heap pointers ending in 0b101,
+           nothing 0b0111 and characters ending in 0b1111, so setting bit 1 gives 3 for the booleans only */
+        TypeOp::Bool => {
+            result.push(Instr::Mov(Reg::R8, 2));
+            result.push(Instr::Or(Reg::Rax, Reg::R8));
+            result.push(Instr::CompareImm(Reg::Rax, 3));
+        }
+    }
 
     result.push(Instr::Mov(Reg::Rax, 1));
     result.push(Instr::Mov(Reg::R10, 3));
+    result.push(Instr::Cmove(Reg::Rax, Reg::R10));
 
-    match op {
-        TypeOp::Bool => result.push(Instr::Cmovne(Reg::Rax, Reg::R10)), 
-        TypeOp::Num => result.push(Instr::Cmove(Reg::Rax, Reg::R10)),
-    }
+    result
+}
+
+/* Tagged integers and the odd values that are not heap pointers are answered inline, only a heap object asks
+   the runtime whether it is a float or a bignum */
+pub fn gen_isnum(offset: i32) -> Vec<Instr> {
+    let mut result: Vec<Instr> = Vec::new();
+    let id = next_id();
+    let number_label = format!("isnum_number{}", id);
+    let heap_label = format!("isnum_heap{}", id);
+    let end_label = format!("isnum_end{}", id);
+
+    result.push(Instr::Test(Reg::Rax, 1));
+    result.push(Instr::Je(number_label.clone()));
+    result.push(Instr::MovFromReg(Reg::R11, Reg::Rax));
+    result.push(Instr::Mov(Reg::R8, 7));
+    result.push(Instr::And(Reg::R11, Reg::R8));
+    result.push(Instr::CompareImm(Reg::R11, 5));
+    result.push(Instr::Je(heap_label.clone()));
+    result.push(Instr::Mov(Reg::Rax, 1));
+    result.push(Instr::Jmp(end_label.clone()));
+    result.push(Instr::Label(number_label));
+    result.push(Instr::Mov(Reg::Rax, 3));
+    result.push(Instr::Jmp(end_label.clone()));
+    result.push(Instr::Label(heap_label));
+    result.push(Instr::MovToStack(Reg::Rax, offset));
+    result.push(Instr::CallRust(RustFn::IsNum, vec![offset], offset));
+    result.push(Instr::Label(end_label));
 
     result
 }
@@ -78,102 +456,71 @@ pub fn at_least_one_bool_handler(offset: i32) -> Vec<Instr> {
     result.push(Instr::Or(Reg::Rax, Reg::R8));
     result.push(Instr::Test(Reg::Rax, 1));
     result.push(Instr::Je(ok_label.clone()));
-    result.push(Instr::CallRustError(2));
+    result.push(Instr::CallRustError(2, offset));
     result.push(Instr::Label(ok_label));
     result.push(Instr::MovFromReg(Reg::Rax, Reg::R11));
 
     result
 }
 
-pub fn equal_type_handler(offset: i32) -> Vec<Instr> {
+pub fn division_by_zero_handler(divisor: Reg, frame: i32) -> Vec<Instr> {
     let mut result: Vec<Instr> = Vec::new();
-
     let id = next_id();
-    let ok_label = format!("equal_ok{}", id);
+    let nonzero_label = format!("divisor_nonzero{}", id);
 
-    result.push(Instr::MovFromReg(Reg::R11, Reg::Rax));
-    result.push(Instr::MovFromStack(Reg::R8, offset));
-    result.push(Instr::Xor(Reg::Rax, Reg::R8));
-    result.push(Instr::Test(Reg::Rax, 1));
-    result.push(Instr::Je(ok_label.clone()));
-    result.push(Instr::CallRustError(2));
-    result.push(Instr::Label(ok_label));
-    result.push(Instr::MovFromReg(Reg::Rax, Reg::R11));
+    result.push(Instr::CompareImm(divisor, 0));
+    result.push(Instr::Jne(nonzero_label.clone()));
+    result.push(Instr::CallRustError(3, frame));
+    result.push(Instr::Label(nonzero_label));
 
     result
 }
 
-pub fn unary_not_bool_handler() -> Vec<Instr> {
+/* The operand in rax has to be a boolean, the same check as isbool: only 1 and 3 give 3 when or-ed with 2 */
+pub fn unary_bool_handler(frame: i32) -> Vec<Instr> {
     let mut result: Vec<Instr> = Vec::new();
     let id = next_id();
-    let ok_label = format!("unop_ok{}", id);
+    let ok_label = format!("bool_operand_ok{}", id);
 
-    result.push(Instr::Test(Reg::Rax, 1));
+    result.push(Instr::MovFromReg(Reg::R11, Reg::Rax));
+    result.push(Instr::Mov(Reg::R8, 2));
+    result.push(Instr::Or(Reg::R11, Reg::R8));
+    result.push(Instr::CompareImm(Reg::R11, 3));
     result.push(Instr::Je(ok_label.clone()));
-    result.push(Instr::CallRustError(2));
-    result.push(Instr::Label(ok_label.clone()));
+    result.push(Instr::CallRustError(2, frame));
+    result.push(Instr::Label(ok_label));
 
     result
 }
 
-pub fn overflow_handler() -> Vec<Instr> {
+pub fn overflow_handler(frame: i32) -> Vec<Instr> {
     let mut result: Vec<Instr> = Vec::new();
     let id = next_id();
     let no_overflow_label = format!("no_overflow{}", id);
 
     result.push(Instr::Jno(no_overflow_label.to_string()));
-    result.push(Instr::CallRustError(1));
+    result.push(Instr::CallRustError(1, frame));
     result.push(Instr::Label(no_overflow_label.clone()));
 
     result
 }
 
-fn recursively_collet_set_identifiers(current: &Expr, result: &mut HashSet<String>) {
-    match current {
-        Expr::Number(_) => {},
-        Expr::Boolean(_) => {},
-        Expr::Id(_) => {},
-        Expr::UnOp(_, e) => {
-            recursively_collet_set_identifiers(e, result);
-        },
-        Expr::Loop(e) => {
-            recursively_collet_set_identifiers(e, result);
-        },
-        Expr::Break(e) => {
-            recursively_collet_set_identifiers(e, result);
-        },
-        Expr::BinOp(_, e1, e2) => {
-            recursively_collet_set_identifiers(e1, result);
-            recursively_collet_set_identifiers(e2, result);
-        },
-        Expr::If(e1, e2, e3) => {
-            recursively_collet_set_identifiers(e1, result);
-            recursively_collet_set_identifiers(e2, result);
-            recursively_collet_set_identifiers(e3, result);
-        },
-        Expr::Let(bs, body) => {
-            for (_, b) in bs {
-                recursively_collet_set_identifiers(b, result);
-            }
-            recursively_collet_set_identifiers(body, result);
-        },
-        Expr::Block(bs) => {
-            for expr in bs {
-                recursively_collet_set_identifiers(expr, result);
-            }
-        },
-        Expr::Set(name, e) => {
-            result.insert(name.clone());
-            recursively_collet_set_identifiers(e, result);
-        }
-        Expr::Call(_, args) => {
-            for arg in args {
-                recursively_collet_set_identifiers(arg, result);
-            }
+/* The variables assigned with set! anywhere in the expression */
+struct SetTargets<'a>(&'a mut HashSet<String>);
+
+impl Visitor for SetTargets<'_> {
+    fn visit_expr(&mut self, e: &Expr) {
+        if let Expr::Set(name, _) = e {
+            self.0.insert(name.clone());
         }
+        walk_expr(self, e);
     }
 }
 
+fn recursively_collet_set_identifiers(current: &Expr, result: &mut HashSet<String>) {
+    SetTargets(result).visit_expr(current);
+}
+
 pub fn allocate_define_ptrs_for_set_targets(
     expr: &Expr,
     define_env: &HashMap<String, i64>,
@@ -183,27 +530,45 @@ pub fn allocate_define_ptrs_for_set_targets(
 
     let mut result: HashMap<String, i64> = HashMap::new();
     for name in targets {
-        match define_env.get(&name) {
-            Some(&val) => {
-                let boxed = Box::new(val);
-                let ptr = Box::into_raw(boxed) as i64;
-                result.insert(name, ptr);
-            }
-            None => {}
+        if let Some(&val) = define_env.get(&name) {
+            let boxed = Box::new(val);
+            let ptr = Box::into_raw(boxed) as i64;
+            result.insert(name, ptr);
         }
     }
     result
 }
 
+/* The deepest of the direct children */
+struct Deepest(i32);
+
+impl Visitor for Deepest {
+    fn visit_expr(&mut self, e: &Expr) {
+        self.0 = self.0.max(recursively_collet_depth(e));
+    }
+}
+
+fn deepest_child(current: &Expr) -> i32 {
+    let mut deepest = Deepest(0);
+    walk_expr(&mut deepest, current);
+    deepest.0
+}
+
+/* The stack slots an expression needs above the current one, forms that keep values on the stack while
+   their children run add those to the deepest child */
 pub fn recursively_collet_depth(current: &Expr) -> i32 {
     match current {
-        Expr::Number(_) | Expr::Boolean(_) | Expr::Id(_) => 0,
         Expr::Let(bindings, body) => bindings.iter().enumerate().map(|(i, (_, e))| i as i32 + recursively_collet_depth(e)).max().unwrap_or(0).max(recursively_collet_depth(body) + bindings.len() as i32),
-        Expr::UnOp(_, e) => recursively_collet_depth(e),
-        Expr::BinOp(_, e1, e2) => 1 + recursively_collet_depth(e1).max(recursively_collet_depth(e2)),
-        Expr::If(c, t, f) => recursively_collet_depth(c).max(recursively_collet_depth(t)).max(recursively_collet_depth(f)),
-        Expr::Loop(e) | Expr::Break(e) | Expr::Set(_, e) => recursively_collet_depth(e),
-        Expr::Block(es) => es.iter().map(|e| recursively_collet_depth(e)).max().unwrap_or(0),
-        Expr::Call(_, args) => 1 + args.len() as i32 + args.iter().map(|a| recursively_collet_depth(a)).max().unwrap_or(0)
+        /* The functions are lifted out, they run on a stack of their own */
+        Expr::LetRec(_, body) => recursively_collet_depth(body),
+        Expr::BinOp(_, _, _) => 1 + deepest_child(current),
+        Expr::Substring(_, _, _) | Expr::SetField(_, _, _) => 2 + deepest_child(current),
+        Expr::MakeStruct(_, es) | Expr::MakeVector(es) => 2 + es.len() as i32 + deepest_child(current),
+        /* The scrutinee stays in a slot while the fields an arm binds are loaded above it */
+        Expr::Match(e, arms, _) => arms.iter().map(|(pattern, b)| 1 + pattern.bindings().len() as i32 + recursively_collet_depth(b)).max().unwrap_or(0).max(recursively_collet_depth(e)),
+        /* Four slots of handler frame and one for the result of the body while the handler is removed */
+        Expr::Try(body, _, handler) => (4 + recursively_collet_depth(body).max(1)).max(1 + recursively_collet_depth(handler)),
+        Expr::Call(_, args, _) => 1 + args.len() as i32 + deepest_child(current),
+        _ => deepest_child(current),
     }
-}
\ No newline at end of file
+}
@@ -3,40 +3,50 @@ use std::collections::HashMap;
 
 use dynasmrt::{dynasm, DynasmApi};
 
-use crate::compile::{compile_expr_to_instr, compile_defs_to_instr};
-use crate::context::Context;
+use crate::compile::{compile_expr_to_instr, compile_defs_to_instr, program_entry};
+use crate::context::{Context, Options};
 use crate::compile_helpers::{allocate_define_ptrs_for_set_targets};
-use crate::expressions::{Expr, ReplExpr, Defenition};
+use crate::expressions::{Expr, ReplExpr, Defenition, Program};
 use crate::instructions::{Instr, instr_to_dynasm};
-use crate::runtime::snek_print;
+use crate::runtime::{snek_print, snek_input};
+use crate::lift::{lift_def, lift_expr};
 
 pub fn compile_repl_to_instr(
     e: &ReplExpr, si: i32,
     define_env: &mut HashMap<String, i64>,
     ops: &mut dynasmrt::x64::Assembler,
     labels: &mut HashMap<String, dynasmrt::DynamicLabel>,
+    options: Options,
 ) -> std::io::Result<Vec<Instr>> {
     match e {
         ReplExpr::Define(v, e) => {
             if define_env.contains_key(v) {
-                return Err(std::io::Error::new(std::io::ErrorKind::Other, "Duplicate binding"));
+                return Err(std::io::Error::other("Duplicate binding"));
             }
-            let result = compile_repl_and_persist(e, si, define_env, ops, labels, false)?;
+            let result = compile_repl_and_persist(e, si, define_env, ops, labels, false, options)?;
             define_env.insert(v.clone(), result as i64);
             Ok(vec![])
         },
-        ReplExpr::Fun(name, params, body) => {
+        ReplExpr::Fun(_, _, _, _) | ReplExpr::Struct(_, _) | ReplExpr::Enum(_, _) => {
             let empty_ptrs: HashMap<String, i64> = HashMap::new();
-            let mut ctx = Context::new(&*define_env, &empty_ptrs).with_si(si);
-            let defs = vec![Defenition::Fun(name.clone(), params.clone(), body.clone())];
+            let mut ctx = Context::new(&*define_env, &empty_ptrs).with_si(si).with_options(options);
+            let def = match e {
+                ReplExpr::Fun(name, params, signature, body) => Defenition::Fun(name.clone(), params.clone(), signature.clone(), body.clone()),
+                ReplExpr::Struct(name, fields) => Defenition::Struct(name.clone(), fields.clone()),
+                ReplExpr::Enum(name, variants) => Defenition::Enum(name.clone(), variants.clone()),
+                _ => unreachable!(),
+            };
+            let defs = lift_def(&def)?;
             let def_instrs = compile_defs_to_instr(&defs, &mut ctx)?;
 
             instr_to_dynasm(ops, &def_instrs, labels)?;
             ops.commit().unwrap();
             Ok(vec![])
         },
+        /* Macros are expanded before parsing, there is nothing to compile */
+        ReplExpr::Syntax(_, _) => Ok(vec![]),
         ReplExpr::Expr(e) => {
-            let _ = compile_repl_and_persist(e, si, define_env, ops, labels, true)?;
+            let _ = compile_repl_and_persist(e, si, define_env, ops, labels, true, options)?;
             Ok(vec![])
         }
     }
@@ -49,17 +59,26 @@ pub fn compile_repl_and_persist(
     ops: &mut dynasmrt::x64::Assembler,
     labels: &mut HashMap<String, dynasmrt::DynamicLabel>,
     print_result: bool,
+    options: Options,
 ) -> std::io::Result<i64> {
-    let define_ptrs = allocate_define_ptrs_for_set_targets(e, define_env);
-    let mut ctx = Context::new(&*define_env, &define_ptrs).with_si(si);
-    let e_instr = compile_expr_to_instr(e, &mut ctx)?;
+    let (e, helpers) = lift_expr(e)?;
+    if !helpers.is_empty() {
+        let empty_ptrs: HashMap<String, i64> = HashMap::new();
+        let mut helpers_ctx = Context::new(&*define_env, &empty_ptrs).with_si(si).with_options(options);
+        let helpers_instr = compile_defs_to_instr(&helpers, &mut helpers_ctx)?;
+        instr_to_dynasm(ops, &helpers_instr, labels)?;
+        ops.commit().unwrap();
+    }
+
+    let define_ptrs = allocate_define_ptrs_for_set_targets(&e, define_env);
+    let mut ctx = Context::new(&*define_env, &define_ptrs).with_si(si).with_options(options);
+    let e_instr = compile_expr_to_instr(&e, &mut ctx)?;
 
     let start = ops.offset();
     dynasm!(ops ; .arch x64 ; push rbx ; push r12);
     instr_to_dynasm(ops, &e_instr, labels)?;
     if print_result {
-        let snek_print_ptr = snek_print as *const ();
-        let snek_print_addr = unsafe { std::mem::transmute::<* const (), fn() -> i32>(snek_print_ptr) } as i64;
+        let snek_print_addr = snek_print as *const () as i64;
         dynasm!(ops ; .arch x64 ; sub rsp, 8 ; mov rdi, rax ; mov rax, QWORD snek_print_addr ; call rax ; add rsp, 8 );
         dynasm!(ops ; .arch x64 ; pop r12 ; pop rbx ; ret);
     } else {
@@ -68,8 +87,8 @@ pub fn compile_repl_and_persist(
     ops.commit().unwrap();
     let reader = ops.reader();
     let buf = reader.lock();
-    let jitted_fn: extern "C" fn() -> i64 = unsafe { mem::transmute(buf.ptr(start)) };
-    let result = jitted_fn();
+    let jitted_fn: extern "C" fn(i64) -> i64 = unsafe { mem::transmute(buf.ptr(start)) };
+    let result = jitted_fn(snek_input());
 
     unsafe {
         for (name, ptr) in define_ptrs.into_iter() {
@@ -80,4 +99,39 @@ pub fn compile_repl_and_persist(
     }
 
     Ok(result)
-}
\ No newline at end of file
+}
+/* Runs a whole program, its globals live in cells allocated for the run like the defines of the REPL */
+pub fn run_prog(prog: &Program, options: Options) -> std::io::Result<i64> {
+    let mut cells: HashMap<String, i64> = HashMap::new();
+    for (name, _) in &prog.globals {
+        cells.insert(name.clone(), Box::into_raw(Box::new(7i64)) as i64);
+    }
+    let result = run_prog_with_cells(prog, &cells, options);
+    unsafe {
+        for ptr in cells.into_values() {
+            drop(Box::from_raw(ptr as *mut i64));
+        }
+    }
+    result
+}
+
+fn run_prog_with_cells(prog: &Program, cells: &HashMap<String, i64>, options: Options) -> std::io::Result<i64> {
+    let mut ops = dynasmrt::x64::Assembler::new().unwrap();
+    let mut labels: HashMap<String, dynasmrt::DynamicLabel> = HashMap::new();
+    let define_env: HashMap<String, i64> = HashMap::new();
+    let mut ctx = Context::new(&define_env, cells).with_options(options);
+
+    let defs_instr = compile_defs_to_instr(&prog.defs, &mut ctx)?;
+    let main_instr = compile_expr_to_instr(&program_entry(prog), &mut ctx)?;
+    instr_to_dynasm(&mut ops, &defs_instr, &mut labels)?;
+
+    let start = ops.offset();
+    dynasm!(ops ; .arch x64 ; push rbx ; push r12);
+    instr_to_dynasm(&mut ops, &main_instr, &mut labels)?;
+    dynasm!(ops ; .arch x64 ; pop r12 ; pop rbx ; ret);
+    ops.commit().unwrap();
+    let reader = ops.reader();
+    let buf = reader.lock();
+    let jitted_fn: extern "C" fn(i64) -> i64 = unsafe { mem::transmute(buf.ptr(start)) };
+    Ok(jitted_fn(snek_input()))
+}
@@ -1,16 +1,66 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
 
+/* Enclosing loops form a chain from the innermost one outwards, so break and continue can target any of them */
+pub struct LoopScope<'a> {
+    pub id: u64,
+    pub label: Option<String>,
+    pub parent: Option<&'a LoopScope<'a>>,
+    /* Number of try bodies of the function the loop is inside of, break and continue leave the ones above it */
+    pub handlers: i32,
+}
+
+/* Compiler switches chosen on the command line or per REPL session */
+#[derive(Clone, Copy, Default)]
+pub struct Options {
+    /* --bignum: + - * promote to heap allocated bignums instead of failing on overflow */
+    pub bignum: bool,
+    /* --no-contracts: assert and the :requires/:ensures clauses of functions are not checked */
+    pub no_contracts: bool,
+    /* --no-prelude: the functions of prelude.viva are not defined */
+    pub no_prelude: bool,
+}
+
+/* Heap objects laid out in the data section of an assembly file, by their label */
+pub type Statics = RefCell<Vec<(String, Vec<i64>)>>;
+
 pub struct Context<'a> {
     pub si: i32,
     pub env: HashMap<String, i32>,
     pub define_env: &'a HashMap<String, i64>,
     pub define_ptrs: &'a HashMap<String, i64>,
-    pub curr_break: u64,
+    /* Globals of a program compiled to assembly, by the label of their slot in the data section.
+       Jitted programs keep their globals in define_ptrs cells like the REPL. */
+    pub globals: Option<&'a HashMap<String, String>>,
+    /* Literals of a program compiled to assembly, by the label of their object in the data section. Jitted
+       code gets its literals from the runtime. */
+    pub statics: Option<&'a Statics>,
+    pub loops: Option<&'a LoopScope<'a>>,
+    /* Number of try bodies of the current function around the code, each one has a handler installed */
+    pub handlers: i32,
+    pub options: Options,
 }
 
 impl<'a> Context<'a> {
     pub fn new(define_env: &'a HashMap<String, i64>, define_ptrs: &'a HashMap<String, i64>) -> Self {
-        Self { si: 2, env: HashMap::new(), define_env, define_ptrs, curr_break: 0 }
+        Self { si: 2, env: HashMap::new(), define_env, define_ptrs, globals: None, statics: None, loops: None, handlers: 0, options: Options::default() }
     }
     pub fn with_si(mut self, si: i32) -> Self { self.si = si; self }
-}
\ No newline at end of file
+    pub fn with_options(mut self, options: Options) -> Self { self.options = options; self }
+    pub fn with_globals(mut self, globals: &'a HashMap<String, String>) -> Self { self.globals = Some(globals); self }
+    pub fn with_statics(mut self, statics: &'a Statics) -> Self { self.statics = Some(statics); self }
+
+    pub fn find_loop(&self, label: &Option<String>) -> std::io::Result<&'a LoopScope<'a>> {
+        let mut scope = self.loops;
+        while let Some(current) = scope {
+            if label.is_none() || current.label == *label {
+                return Ok(current);
+            }
+            scope = current.parent;
+        }
+        match label {
+            Some(name) => Err(std::io::Error::other(format!("unknown loop label :{}", name))),
+            None => Err(std::io::Error::other("break outside of a loop")),
+        }
+    }
+}
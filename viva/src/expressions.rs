@@ -1,10 +1,18 @@
+use crate::analysis::Span;
+use crate::macros::Macro;
+
 #[derive(Hash, Eq, PartialEq, Debug, Clone)]
 pub enum Op1 {
     Add1,
     Sub1,
     IsNum,
     IsBool,
-    Print
+    Not,
+    Print,
+    Arg,
+    StringLength,
+    ErrorCode,
+    VectorLength
 }
 
 #[derive(Hash, Eq, PartialEq, Debug, Clone)]
@@ -12,43 +20,182 @@ pub enum Op2 {
     Plus,
     Minus,
     Times,
+    Divide,
+    Modulo,
+    Remainder,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
     Equal,
     Greater,
     GreaterEqual,
     Less,
-    LessEqual
+    LessEqual,
+    StringAppend,
+    StringEqual,
+    VectorRef
+}
+
+/* A name that a let, a catch, a match pattern or a function parameter binds, with where it is written */
+#[derive(Hash, Eq, PartialEq, Debug, Clone)]
+pub struct Binder {
+    pub name: String,
+    pub span: Span,
+}
+
+impl Binder {
+    /* A name a pass binds, it is not written anywhere */
+    pub fn new(name: &str) -> Self {
+        Binder { name: name.to_string(), span: Span::default() }
+    }
+}
+
+/* One alternative of an enum, the id is its tag in the struct registry of the runtime */
+#[derive(Hash, Eq, PartialEq, Debug, Clone)]
+pub struct Variant {
+    pub name: String,
+    pub id: i64,
+    pub fields: Vec<String>,
+}
+
+#[derive(Hash, Eq, PartialEq, Debug, Clone)]
+pub enum Pattern {
+    /* Variant name, its id and the names bound to its fields, _ binds nothing. The parser leaves the id 0,
+       it is known once the enum is lowered. */
+    Variant(String, i64, Vec<Binder>, Span),
+    Wildcard,
+}
+
+impl Pattern {
+    /* The field indices and names a pattern binds, fields matched with _ are skipped */
+    pub fn bindings(&self) -> Vec<(usize, &Binder)> {
+        match self {
+            Pattern::Variant(_, _, names, _) => names.iter().enumerate().filter(|(_, n)| n.name != "_").collect(),
+            Pattern::Wildcard => vec![],
+        }
+    }
 }
 
 #[derive(Hash, Eq, PartialEq, Debug, Clone)]
 pub enum Expr {
     Number(i64),
+    /* The bits of the f64, so that expressions stay hashable and comparable */
+    Float(u64),
     Boolean(bool),
+    Str(String),
+    Char(char),
+    Nothing,
+    Argc,
+    ReadNum,
+    ReadBool,
     Id(String),
-    Let(Vec<(String, Expr)>, Box<Expr>),
+    Let(Vec<(Binder, Expr)>, Box<Expr>),
+    LetRec(Vec<Defenition>, Box<Expr>),
     UnOp(Op1, Box<Expr>),
     BinOp(Op2, Box<Expr>, Box<Expr>),
+    Substring(Box<Expr>, Box<Expr>, Box<Expr>),
+    /* Struct id from the runtime registry, then the field values in declaration order */
+    MakeStruct(i64, Vec<Expr>),
+    MakeVector(Vec<Expr>),
+    GetField(i64, usize, Box<Expr>),
+    IsStruct(i64, Box<Expr>),
+    /* The struct is only known at runtime, so the field is looked up by name */
+    SetField(Box<Expr>, String, Box<Expr>),
+    Match(Box<Expr>, Vec<(Pattern, Expr)>, Span),
+    Raise(Box<Expr>),
+    /* Condition and the message reported when it does not hold */
+    Assert(Box<Expr>, String),
+    /* Body, the name the raised value is bound to and the handler */
+    Try(Box<Expr>, Binder, Box<Expr>),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
     If(Box<Expr>, Box<Expr>, Box<Expr>),
-    Loop(Box<Expr>),
-    Break(Box<Expr>),
+    Cond(Vec<(Expr, Expr)>, Option<Box<Expr>>),
+    When(Box<Expr>, Box<Expr>),
+    Unless(Box<Expr>, Box<Expr>),
+    Case(Box<Expr>, Vec<(Vec<Expr>, Expr)>, Option<Box<Expr>>),
+    Loop(Option<String>, Box<Expr>),
+    Break(Option<String>, Box<Expr>),
+    Continue(Option<String>),
     Set(String, Box<Expr>),
     Block(Vec<Expr>),
-    Call(String, Vec<Expr>)
+    /* The span is where a wrong call is reported */
+    Call(String, Vec<Expr>, Span)
 }
 
 #[derive(Hash, Eq, PartialEq, Debug, Clone)]
 pub enum Defenition {
-    Fun(String, Vec<String>, Box<Expr>)
+    /* The signature is what calls of the function are checked and filled in against */
+    Fun(String, Vec<Binder>, Signature, Box<Expr>),
+    Struct(String, Vec<String>),
+    Enum(String, Vec<Variant>)
+}
+
+impl Defenition {
+    pub fn as_fun(&self) -> Option<(&String, &Vec<Binder>, &Expr)> {
+        match self {
+            Defenition::Fun(name, params, _, body) => Some((name, params, body)),
+            Defenition::Struct(_, _) | Defenition::Enum(_, _) => None,
+        }
+    }
+}
+
+/* What a call passes to a function: the parameters without a default come first, then the ones with a literal
+   default that the caller fills in, then optionally a &rest parameter that gets the remaining arguments as a
+   vector. The function itself always takes all of them. */
+#[derive(Hash, Eq, PartialEq, Debug, Clone, Default)]
+pub struct Signature {
+    pub required: usize,
+    pub defaults: Vec<Expr>,
+    pub rest: bool,
+}
+
+impl Signature {
+    pub fn fixed(count: usize) -> Self {
+        Signature { required: count, defaults: vec![], rest: false }
+    }
+
+    /* How many parameters the function itself takes */
+    pub fn params(&self) -> usize {
+        self.required + self.defaults.len() + usize::from(self.rest)
+    }
+
+    pub fn accepts(&self, argc: usize) -> bool {
+        argc >= self.required && (self.rest || argc <= self.required + self.defaults.len())
+    }
+
+    /* The number of arguments a call may pass, as it is put in the message of a wrong call */
+    pub fn expected(&self) -> String {
+        if self.rest {
+            format!("at least {}", self.required)
+        } else if self.defaults.is_empty() {
+            self.required.to_string()
+        } else {
+            format!("{} to {}", self.required, self.required + self.defaults.len())
+        }
+    }
 }
 
 #[derive(Hash, Eq, PartialEq, Debug)]
 pub enum ReplExpr {
     Define(String, Box<Expr>),
     Expr(Box<Expr>),
-    Fun(String, Vec<String>, Box<Expr>)
+    Fun(String, Vec<Binder>, Signature, Box<Expr>),
+    Struct(String, Vec<String>),
+    Enum(String, Vec<Variant>),
+    Syntax(String, Macro),
 }
 
 #[derive(Hash, Eq, PartialEq, Debug)]
 pub struct Program {
     pub defs: Vec<Defenition>,
-    pub main: Expr
+    /* Top-level (define name expr), initialized in this order before main runs */
+    pub globals: Vec<(String, Expr)>,
+    pub main: Expr,
+    /* Lowering turns the struct and enum declarations of defs into functions, they are kept here for printing */
+    pub declarations: Vec<Defenition>,
+    /* Reports of names that shadow a function, they don't stop the program from running */
+    pub warnings: Vec<String>,
 }
\ No newline at end of file
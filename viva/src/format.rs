@@ -0,0 +1,65 @@
+use crate::analysis::{Diagnostic, Span};
+use crate::parse::{lex_lenient, TokenKind};
+use crate::pretty::Doc;
+
+/* viva fmt: source files are re-indented in the layout the printer uses. The formatter reads the tokens of
+   the text with their comments and keeps every atom as it is written, even a literal the compiler rejects,
+   only the spacing between them changes. Blank lines between top-level forms are kept, one at most. */
+
+/* The top-level forms and comments, each with whether an empty line comes before it. A comment is trailing
+   when there is code in front of it on its line. */
+fn read(source: &str) -> Result<Vec<(Doc, bool)>, Diagnostic> {
+    let mut forms: Vec<(Doc, bool)> = Vec::new();
+    /* The open lists, where each starts and whether an empty line came before it */
+    let mut open: Vec<(Vec<Doc>, usize, bool)> = Vec::new();
+    let mut end = 0;
+    for (index, token) in lex_lenient(source)?.into_iter().enumerate() {
+        let newlines = source[end..token.span.start].matches('\n').count();
+        end = token.span.end;
+        let blank = newlines > 1;
+        let doc = match token.kind {
+            TokenKind::Open => {
+                open.push((Vec::new(), token.span.start, blank));
+                continue;
+            }
+            TokenKind::Close => match open.pop() {
+                Some((items, _, blank)) => {
+                    match open.last_mut() {
+                        Some((parent, _, _)) => parent.push(Doc::List(items)),
+                        None => forms.push((Doc::List(items), blank)),
+                    }
+                    continue;
+                }
+                None => return Err(Diagnostic::error("unexpected )".to_string(), "there is no list to close", Span(token.span))),
+            },
+            TokenKind::Atom(_) => Doc::Atom(source[token.span].to_string()),
+            TokenKind::Comment(text) => Doc::Comment(text, index > 0 && newlines == 0),
+        };
+        match open.last_mut() {
+            Some((parent, _, _)) => parent.push(doc),
+            None => forms.push((doc, blank)),
+        }
+    }
+    match open.last() {
+        Some((_, start, _)) => Err(Diagnostic::error("missing )".to_string(), "this list is not closed", Span(*start..*start + 1))),
+        None => Ok(forms),
+    }
+}
+
+/* The source in the fixed layout, it ends with a line break */
+pub fn format_source(source: &str) -> Result<String, Diagnostic> {
+    let forms = read(source)?;
+    let mut out = String::new();
+    for (index, (doc, blank)) in forms.iter().enumerate() {
+        match doc {
+            Doc::Comment(_, true) if index > 0 => out.push(' '),
+            _ if index > 0 => out.push_str(if *blank { "\n\n" } else { "\n" }),
+            _ => {}
+        }
+        out.push_str(&doc.render());
+    }
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    Ok(out)
+}
@@ -1,21 +1,44 @@
 use dynasmrt::{dynasm, DynasmApi};
 use dynasmrt::DynasmLabelApi;
 use std::collections::HashMap;
-use std::mem;
 
-use crate::runtime::{snek_error, snek_print};
+use crate::runtime::{
+    snek_error, snek_print, snek_arg, snek_argc, snek_read_num, snek_read_bool,
+    snek_string_length, snek_string_append, snek_substring, snek_string_equal,
+    snek_big_add, snek_big_sub, snek_big_mul, snek_big_compare, snek_equal, snek_is_num, snek_box_float,
+    snek_alloc_struct, snek_push_handler, snek_pop_handler, snek_raise, snek_error_code,
+    snek_assert_failed, snek_alloc_vector, snek_vector_length, snek_vector_ref,
+};
 
 #[derive(Debug, Clone)]
 pub enum Reg {
-    Rax, /* Rcx, */ Rdx, Rbx,
+    Rax, Rcx, Rdx, Rbx,
     Rsp, /* Rbp, */ /* Rsi, */ Rdi,
     R8, /* R9, */ R10, R11, 
     R12, /* R13, */ /* R14, */ /* R15, */
 }
 
+/* Only the scratch SSE registers used by float arithmetic */
+#[derive(Debug, Clone, Copy)]
+pub enum Xmm {
+    Xmm0, Xmm1,
+}
+
+pub fn xmm_to_number(xmm: &Xmm) -> u8 {
+    match xmm {
+        Xmm::Xmm0 => 0, Xmm::Xmm1 => 1,
+    }
+}
+
+pub fn xmm_to_string(xmm: &Xmm) -> &str {
+    match xmm {
+        Xmm::Xmm0 => "xmm0", Xmm::Xmm1 => "xmm1",
+    }
+}
+
 pub fn reg_to_number(reg: &Reg) -> u8 {
     match *reg {
-        Reg::Rax => 0,  /* Reg::Rcx => 1, */  Reg::Rdx => 2,  Reg::Rbx => 3,
+        Reg::Rax => 0,  Reg::Rcx => 1,  Reg::Rdx => 2,  Reg::Rbx => 3,
         Reg::Rsp => 4,  /* Reg::Rbp => 5, */  /* Reg::Rsi => 6,  */ Reg::Rdi => 7,
         Reg::R8  => 8,  /* Reg::R9  => 9, */  Reg::R10 => 10, Reg::R11 => 11,
         Reg::R12 => 12, /* Reg::R13 => 13, */ /* Reg::R14 => 14, */ /* Reg::R15 => 15, */
@@ -24,104 +47,264 @@ pub fn reg_to_number(reg: &Reg) -> u8 {
 
 pub fn reg_to_string(reg: &Reg) -> &str {
     match reg {
-        Reg::Rax => "rax", /* Reg::Rcx => "rcx", */ Reg::Rdx => "rdx", Reg::Rbx => "rbx",
+        Reg::Rax => "rax", Reg::Rcx => "rcx", Reg::Rdx => "rdx", Reg::Rbx => "rbx",
         Reg::Rsp => "rsp", /* Reg::Rbp => "rbp", */ /* Reg::Rsi => "rsi", */ Reg::Rdi => "rdi",
         Reg::R8  => "r8", /* Reg::R9  => "r9", */ Reg::R10 => "r10", Reg::R11 => "r11",
         Reg::R12 => "r12", /* Reg::R13 => "r13", */ /* Reg::R14 => "r14", */ /* Reg::R15 => "r15", */
     }
 }
 
+#[derive(Debug, Clone)]
+pub enum RustFn {
+    Print,
+    Arg,
+    Argc,
+    ReadNum,
+    ReadBool,
+    StringLength,
+    StringAppend,
+    Substring,
+    StringEqual,
+    BigAdd,
+    BigSub,
+    BigMul,
+    BigCompare,
+    Equal,
+    IsNum,
+    BoxFloat,
+    AllocStruct,
+    PushHandler,
+    PopHandler,
+    Raise,
+    ErrorCode,
+    AssertFailed,
+    AllocVector,
+    VectorLength,
+    VectorRef,
+}
+
+pub fn rust_fn_symbol(f: &RustFn) -> &str {
+    match f {
+        RustFn::Print => "snek_print",
+        RustFn::Arg => "snek_arg",
+        RustFn::Argc => "snek_argc",
+        RustFn::ReadNum => "snek_read_num",
+        RustFn::ReadBool => "snek_read_bool",
+        RustFn::StringLength => "snek_string_length",
+        RustFn::StringAppend => "snek_string_append",
+        RustFn::Substring => "snek_substring",
+        RustFn::StringEqual => "snek_string_equal",
+        RustFn::BigAdd => "snek_big_add",
+        RustFn::BigSub => "snek_big_sub",
+        RustFn::BigMul => "snek_big_mul",
+        RustFn::BigCompare => "snek_big_compare",
+        RustFn::Equal => "snek_equal",
+        RustFn::IsNum => "snek_is_num",
+        RustFn::BoxFloat => "snek_box_float",
+        RustFn::AllocStruct => "snek_alloc_struct",
+        RustFn::PushHandler => "snek_push_handler",
+        RustFn::PopHandler => "snek_pop_handler",
+        RustFn::Raise => "snek_raise",
+        RustFn::ErrorCode => "snek_error_code",
+        RustFn::AssertFailed => "snek_assert_failed",
+        RustFn::AllocVector => "snek_alloc_vector",
+        RustFn::VectorLength => "snek_vector_length",
+        RustFn::VectorRef => "snek_vector_ref",
+    }
+}
+
+pub fn rust_fn_address(f: &RustFn) -> i64 {
+    let ptr = match f {
+        RustFn::Print => snek_print as *const (),
+        RustFn::Arg => snek_arg as *const (),
+        RustFn::Argc => snek_argc as *const (),
+        RustFn::ReadNum => snek_read_num as *const (),
+        RustFn::ReadBool => snek_read_bool as *const (),
+        RustFn::StringLength => snek_string_length as *const (),
+        RustFn::StringAppend => snek_string_append as *const (),
+        RustFn::Substring => snek_substring as *const (),
+        RustFn::StringEqual => snek_string_equal as *const (),
+        RustFn::BigAdd => snek_big_add as *const (),
+        RustFn::BigSub => snek_big_sub as *const (),
+        RustFn::BigMul => snek_big_mul as *const (),
+        RustFn::BigCompare => snek_big_compare as *const (),
+        RustFn::Equal => snek_equal as *const (),
+        RustFn::IsNum => snek_is_num as *const (),
+        RustFn::BoxFloat => snek_box_float as *const (),
+        RustFn::AllocStruct => snek_alloc_struct as *const (),
+        RustFn::PushHandler => snek_push_handler as *const (),
+        RustFn::PopHandler => snek_pop_handler as *const (),
+        RustFn::Raise => snek_raise as *const (),
+        RustFn::ErrorCode => snek_error_code as *const (),
+        RustFn::AssertFailed => snek_assert_failed as *const (),
+        RustFn::AllocVector => snek_alloc_vector as *const (),
+        RustFn::VectorLength => snek_vector_length as *const (),
+        RustFn::VectorRef => snek_vector_ref as *const (),
+    };
+    ptr as i64
+}
+
 #[derive(Debug, Clone)]
 pub enum Instr {
     Mov(Reg, i64),
     MovFromReg(Reg, Reg),
     MovRaxFromRaxPtr,
     MovToPtrFromReg(Reg, Reg),
+    MovToPtr(Reg, i32, Reg),
+    MovFromPtr(Reg, Reg, i32),
     Add(Reg, i32),
     Sub(Reg, i32),
-    /* And(Reg, Reg), */
+    AddReg(Reg, Reg),
+    SubReg(Reg, Reg),
+    And(Reg, Reg),
     Or(Reg, Reg),
     Xor(Reg, Reg),
     AddRaxMemFromStack(i32),
     SubRaxMemFromStack(i32),
     MulRaxMemFromStack(i32),
+    Cqo,
+    IDiv(Reg),
     MovToStack(Reg, i32),
     MovFromStack(Reg, i32),
     MovLabel(String, i32),
+    LeaLabel(Reg, String),
     Label(String),
     Compare(Reg),
     CompareWithMemory(Reg, i32),
-    /* CompareRegs(Reg, Reg), */
+    CompareImm(Reg, i32),
+    CompareRegs(Reg, Reg),
     Test(Reg, i32),
     Jmp(String),
     JmpReg(Reg),
+    JmpToReg(Reg),
+    JumpTableEntry(String),
     Je(String),
     Jne(String),
-    /* Jl(String), */
-    /* Jle(String), */
-    /* Jg(String), */
-    /* Jge(String), */
+    Jl(String),
+    Jle(String),
+    Jg(String),
+    Jge(String),
     Jno(String),
+    Jo(String),
     Cmove(Reg, Reg),
     Cmovne(Reg, Reg),
     Cmovl(Reg, Reg),
     Cmovle(Reg, Reg),
     Cmovg(Reg, Reg),
     Cmovge(Reg, Reg),
+    Cmova(Reg, Reg),
+    Cmovae(Reg, Reg),
+    Cvtsi2sd(Xmm, Reg),
+    MovsdFromPtr(Xmm, Reg, i32),
+    MovqFromXmm(Reg, Xmm),
+    Addsd(Xmm, Xmm),
+    Subsd(Xmm, Xmm),
+    Mulsd(Xmm, Xmm),
+    Divsd(Xmm, Xmm),
+    Ucomisd(Xmm, Xmm),
     ShiftArithmeticRight(Reg, i8),
-    CallRustError(i8),
-    CallRustPrint(Reg),
+    ShiftLeft(Reg, i8),
+    ShiftArithmeticRightCl(Reg),
+    ShiftLeftCl(Reg),
+    /* Reports a runtime error, the frame protects the live stack slots like for CallRust since a
+       surrounding try continues in this function */
+    CallRustError(i8, i32),
+    /* Calls into the runtime with arguments read from the given stack offsets. The frame is the
+       part of the stack that is still in use and must not be overwritten by the callee. */
+    CallRust(RustFn, Vec<i32>, i32),
     Comment(String),
     /* Ret, */
 }
 
+const RUST_ARG_REGS: [&str; 4] = ["rdi", "rsi", "rdx", "rcx"];
+
 pub fn instr_to_string(instr: &Instr) -> String {
     match instr {
         Instr::Mov(reg, val) => format!("\tmov {}, {}", reg_to_string(reg), val),
         Instr::MovFromReg(regd, regs) => format!("\tmov {}, {}", reg_to_string(regd), reg_to_string(regs)),
         Instr::MovRaxFromRaxPtr => "\tmov rax, [rax]".to_string(),
         Instr::MovToPtrFromReg(ptr, src) => format!("\tmov [{}], {}", reg_to_string(ptr), reg_to_string(src)),
+        Instr::MovToPtr(ptr, offset, src) => format!("\tmov [{} + {}], {}", reg_to_string(ptr), offset, reg_to_string(src)),
+        Instr::MovFromPtr(dst, ptr, offset) => format!("\tmov {}, [{} + {}]", reg_to_string(dst), reg_to_string(ptr), offset),
         Instr::Add(reg, val) => format!("\tadd {}, {}", reg_to_string(reg), val),
         Instr::Sub(reg, val) => format!("\tsub {}, {}", reg_to_string(reg), val),
-        /* Instr::And(dst, src) => format!("\tand {}, {}", reg_to_string(dst), reg_to_string(src)), */ 
+        Instr::AddReg(dst, src) => format!("\tadd {}, {}", reg_to_string(dst), reg_to_string(src)),
+        Instr::SubReg(dst, src) => format!("\tsub {}, {}", reg_to_string(dst), reg_to_string(src)),
+        Instr::And(dst, src) => format!("\tand {}, {}", reg_to_string(dst), reg_to_string(src)),
         Instr::Or(dst, src) => format!("\tor {}, {}", reg_to_string(dst), reg_to_string(src)),
         Instr::Xor(dst, src) => format!("\txor {}, {}", reg_to_string(dst), reg_to_string(src)),
         Instr::AddRaxMemFromStack(offset) => format!("\tadd rax, [rsp - {}]", offset),
         Instr::SubRaxMemFromStack(offset) => format!("\tsub rax, [rsp - {}]", offset),
         Instr::MulRaxMemFromStack(offset) => format!("\timul rax, [rsp - {}]", offset),
+        Instr::Cqo => "\tcqo".to_string(),
+        Instr::IDiv(reg) => format!("\tidiv {}", reg_to_string(reg)),
         Instr::MovToStack(reg, offset) => format!("\tmov [rsp - {}], {}", offset, reg_to_string(reg)),
         Instr::MovFromStack(reg, offset) => format!("\tmov {}, [rsp - {}]", reg_to_string(reg), offset),
         /* Instr::MovLabel(label, offset) => format!("\tlea r8, [rel {}]\n\tmov [rsp - {}], r8", label, offset), */
         Instr::MovLabel(label, offset) => format!("\tlea rax, [rel {}]\n\tmov QWORD [rsp - {}], rax", label, offset),
+        Instr::LeaLabel(reg, label) => format!("\tlea {}, [rel {}]", reg_to_string(reg), label),
         Instr::Label(label) => format!("{}:", label),
         Instr::Compare(reg) => format!("\tcmp {}, 3", reg_to_string(reg)),
         Instr::CompareWithMemory(reg, offset) => format!("\tcmp {}, [rsp - {}]", reg_to_string(reg), offset),
-        /* Instr::CompareRegs(r1, r2) => format!("\tcmp {}, {}", reg_to_string(r1), reg_to_string(r2)), */
+        Instr::CompareImm(reg, val) => format!("\tcmp {}, {}", reg_to_string(reg), val),
+        Instr::CompareRegs(r1, r2) => format!("\tcmp {}, {}", reg_to_string(r1), reg_to_string(r2)),
         Instr::Test(reg, val) => format!("\ttest {}, {}", reg_to_string(reg), val),
         Instr::Jmp(label) => format!("\tjmp {}", label),
         Instr::JmpReg(reg) => format!("\tjmp QWORD [{}]", reg_to_string(reg)),
+        Instr::JmpToReg(reg) => format!("\tjmp {}", reg_to_string(reg)),
+        /* Every entry has to be a 5 byte jump so that the table can be indexed */
+        Instr::JumpTableEntry(label) => format!("\tjmp near {}", label),
         Instr::Je(label) => format!("\tje {}", label),
         Instr::Jne(label) => format!("\tjne {}", label),
-        /* Instr::Jl(label) => format!("\tjl {}", label), */
-        /* Instr::Jle(label) => format!("\tjle {}", label), */
-        /* Instr::Jg(label) => format!("\tjg {}", label), */
-        /* Instr::Jge(label) => format!("\tjge {}", label), */
+        Instr::Jl(label) => format!("\tjl {}", label),
+        Instr::Jle(label) => format!("\tjle {}", label),
+        Instr::Jg(label) => format!("\tjg {}", label),
+        Instr::Jge(label) => format!("\tjge {}", label),
         Instr::Jno(label) => format!("\tjno {}", label),
+        Instr::Jo(label) => format!("\tjo {}", label),
         Instr::Cmove(reg1, reg2) => format!("\tcmove {}, {}", reg_to_string(reg1), reg_to_string(reg2)),
         Instr::Cmovne(reg1, reg2) => format!("\tcmovne {}, {}", reg_to_string(reg1), reg_to_string(reg2)),
         Instr::Cmovl(reg1, reg2) => format!("\tcmovl {}, {}", reg_to_string(reg1), reg_to_string(reg2)),
         Instr::Cmovle(reg1, reg2) => format!("\tcmovle {}, {}", reg_to_string(reg1), reg_to_string(reg2)),
         Instr::Cmovg(reg1, reg2) => format!("\tcmovg {}, {}", reg_to_string(reg1), reg_to_string(reg2)),
         Instr::Cmovge(reg1, reg2) => format!("\tcmovge {}, {}", reg_to_string(reg1), reg_to_string(reg2)),
+        Instr::Cmova(reg1, reg2) => format!("\tcmova {}, {}", reg_to_string(reg1), reg_to_string(reg2)),
+        Instr::Cmovae(reg1, reg2) => format!("\tcmovae {}, {}", reg_to_string(reg1), reg_to_string(reg2)),
+        Instr::Cvtsi2sd(xmm, reg) => format!("\tcvtsi2sd {}, {}", xmm_to_string(xmm), reg_to_string(reg)),
+        Instr::MovsdFromPtr(xmm, ptr, offset) => format!("\tmovsd {}, [{} + {}]", xmm_to_string(xmm), reg_to_string(ptr), offset),
+        Instr::MovqFromXmm(reg, xmm) => format!("\tmovq {}, {}", reg_to_string(reg), xmm_to_string(xmm)),
+        Instr::Addsd(dst, src) => format!("\taddsd {}, {}", xmm_to_string(dst), xmm_to_string(src)),
+        Instr::Subsd(dst, src) => format!("\tsubsd {}, {}", xmm_to_string(dst), xmm_to_string(src)),
+        Instr::Mulsd(dst, src) => format!("\tmulsd {}, {}", xmm_to_string(dst), xmm_to_string(src)),
+        Instr::Divsd(dst, src) => format!("\tdivsd {}, {}", xmm_to_string(dst), xmm_to_string(src)),
+        Instr::Ucomisd(a, b) => format!("\tucomisd {}, {}", xmm_to_string(a), xmm_to_string(b)),
         Instr::ShiftArithmeticRight(reg, val) => format!("\tsar {}, {}", reg_to_string(reg), val),
-        Instr::CallRustError(err_code) => format!("\tmov rdi, {}\n\tcall snek_error", err_code),
-        Instr::CallRustPrint(reg) => format!("\tsub rsp, 8\n\tmov rdi, {}\n\tcall snek_print\n\tadd rsp, 8", reg_to_string(reg)),
+        Instr::ShiftLeft(reg, val) => format!("\tshl {}, {}", reg_to_string(reg), val),
+        Instr::ShiftArithmeticRightCl(reg) => format!("\tsar {}, cl", reg_to_string(reg)),
+        Instr::ShiftLeftCl(reg) => format!("\tshl {}, cl", reg_to_string(reg)),
+        Instr::CallRustError(err_code, frame) => format!("\tsub rsp, {}\n\tand rsp, -16\n\tmov rdi, {}\n\tcall snek_error", frame, err_code),
+        Instr::CallRust(f, args, frame) => {
+            let mut lines = vec![
+                "\tmov r11, rsp".to_string(),
+                format!("\tsub rsp, {}", frame),
+                "\tand rsp, -16".to_string(),
+                "\tpush r11".to_string(),
+                "\tpush rdi".to_string(),
+            ];
+            for (offset, reg) in args.iter().zip(RUST_ARG_REGS.iter()) {
+                lines.push(format!("\tmov {}, [r11 - {}]", reg, offset));
+            }
+            lines.push(format!("\tcall {}", rust_fn_symbol(f)));
+            lines.push("\tpop rdi".to_string());
+            lines.push("\tpop rsp".to_string());
+            lines.join("\n")
+        },
         Instr::Comment(s) => format!("; {}", s),
         /* Instr::Ret => format!("\tret"), */
     }
 }
 
-pub fn instrs_to_string(instrs: &Vec<Instr>) -> std::io::Result<String> {
+pub fn instrs_to_string(instrs: &[Instr]) -> std::io::Result<String> {
     Ok(instrs
         .iter()
         .map(instr_to_string)
@@ -131,7 +314,7 @@ pub fn instrs_to_string(instrs: &Vec<Instr>) -> std::io::Result<String> {
 
 pub fn instr_to_dynasm(
     ops: &mut dynasmrt::x64::Assembler,
-    instrs: &Vec<Instr>,
+    instrs: &[Instr],
     labels: &mut HashMap<String, dynasmrt::DynamicLabel>,
 ) -> std::io::Result<()> {
     for instr in instrs.iter() {
@@ -150,9 +333,13 @@ pub fn instr_to_dynasm(
             /* Think aout this, it is not good no hardcode the stuff */
             Instr::MovRaxFromRaxPtr => { dynasm!(ops; .arch x64; mov rax, [rax]); }
             Instr::MovToPtrFromReg(ptr, src) => { dynasm!(ops; .arch x64; mov [Rq(reg_to_number(ptr))], Rq(reg_to_number(src))); }
+            Instr::MovToPtr(ptr, offset, src) => { dynasm!(ops; .arch x64; mov [Rq(reg_to_number(ptr)) + *offset], Rq(reg_to_number(src))); }
+            Instr::MovFromPtr(dst, ptr, offset) => { dynasm!(ops; .arch x64; mov Rq(reg_to_number(dst)), [Rq(reg_to_number(ptr)) + *offset]); }
             Instr::Add(reg, val) => { dynasm!(ops; .arch x64; add Rq(reg_to_number(reg)), *val); }
             Instr::Sub(reg, val) => { dynasm!(ops; .arch x64; sub Rq(reg_to_number(reg)), *val); }
-            /* Instr::And(dst, src) => { dynasm!(ops; .arch x64; and Rq(reg_to_number(dst)), Rq(reg_to_number(src))); } */
+            Instr::AddReg(dst, src) => { dynasm!(ops; .arch x64; add Rq(reg_to_number(dst)), Rq(reg_to_number(src))); }
+            Instr::SubReg(dst, src) => { dynasm!(ops; .arch x64; sub Rq(reg_to_number(dst)), Rq(reg_to_number(src))); }
+            Instr::And(dst, src) => { dynasm!(ops; .arch x64; and Rq(reg_to_number(dst)), Rq(reg_to_number(src))); }
             Instr::Or(dst, src) => { dynasm!(ops; .arch x64; or Rq(reg_to_number(dst)), Rq(reg_to_number(src))); }
             Instr::Xor(dst, src) => { dynasm!(ops; .arch x64; xor Rq(reg_to_number(dst)), Rq(reg_to_number(src))); }
 
@@ -162,50 +349,74 @@ pub fn instr_to_dynasm(
             Instr::AddRaxMemFromStack(offset) => { dynasm!(ops; .arch x64; add rax, [rsp - *offset]); }
             Instr::SubRaxMemFromStack(offset) => { dynasm!(ops; .arch x64; sub rax, [rsp - *offset]); }
             Instr::MulRaxMemFromStack(offset) => { dynasm!(ops; .arch x64; imul rax, [rsp - *offset]); }
+            Instr::Cqo => { dynasm!(ops; .arch x64; cqo); }
+            Instr::IDiv(reg) => { dynasm!(ops; .arch x64; idiv Rq(reg_to_number(reg))); }
 
 
             Instr::MovToStack(reg, offset) => { dynasm!(ops; .arch x64; mov QWORD [rsp - *offset], Rq(reg_to_number(reg))); }
             Instr::MovFromStack(reg, offset) => { dynasm!(ops; .arch x64; mov Rq(reg_to_number(reg)), [rsp - *offset]); }
             /* Instr::MovLabel(label, offset) => { dynasm!(ops; .arch x64; lea r8, [=>labels[label]]; mov [rsp - *offset], r8); } */
             Instr::MovLabel(label, offset) => { dynasm!(ops; .arch x64; lea rax, [=>labels[label]]; mov QWORD [rsp - *offset], rax); }
-            Instr::Label(label) => { dynasm!(ops; .arch x64; =>labels[label].clone()); }
+            Instr::LeaLabel(reg, label) => { dynasm!(ops; .arch x64; lea Rq(reg_to_number(reg)), [=>labels[label]]); }
+            Instr::Label(label) => { dynasm!(ops; .arch x64; =>labels[label]); }
             Instr::Compare(reg) => { dynasm!(ops; .arch x64; cmp Rq(reg_to_number(reg)), 3); }
             Instr::CompareWithMemory(reg, offset) => { dynasm!(ops; .arch x64; cmp Rq(reg_to_number(reg)), [rsp - *offset])}
-            /* Instr::CompareRegs(reg1, reg2) => { dynasm!(ops; .arch x64; cmp Rq(reg_to_number(reg1)), Rq(reg_to_number(reg2))); } */
+            Instr::CompareImm(reg, val) => { dynasm!(ops; .arch x64; cmp Rq(reg_to_number(reg)), *val); }
+            Instr::CompareRegs(reg1, reg2) => { dynasm!(ops; .arch x64; cmp Rq(reg_to_number(reg1)), Rq(reg_to_number(reg2))); }
             Instr::Test(reg, val) => { dynasm!(ops; .arch x64; test Rq(reg_to_number(reg)), *val); },
-            Instr::Jmp(label) => { dynasm!(ops; .arch x64; jmp =>labels[label].clone()); },
+            Instr::Jmp(label) => { dynasm!(ops; .arch x64; jmp =>labels[label]); },
             Instr::JmpReg(reg) => { dynasm!(ops; .arch x64; jmp QWORD [Rq(reg_to_number(reg))]) },
-            Instr::Je(label) => {dynasm!(ops; .arch x64; je =>labels[label].clone()); },
-            Instr::Jne(label) => { dynasm!(ops; .arch x64; jne =>labels[label].clone()); },
-            /* Instr::Jl(label) => { dynasm!(ops; .arch x64; jl =>labels[label].clone()); }, */
-            /* Instr::Jle(label) => { dynasm!(ops; .arch x64; jle =>labels[label].clone()); }, */
-            /* Instr::Jg(label) => { dynasm!(ops; .arch x64; jg =>labels[label].clone()); }, */
-            /* Instr::Jge(label) => { dynasm!(ops; .arch x64; jge =>labels[label].clone()); }, */
-            Instr::Jno(label) => { dynasm!(ops; .arch x64; jno =>labels[label].clone()); },
+            Instr::JmpToReg(reg) => { dynasm!(ops; .arch x64; jmp Rq(reg_to_number(reg))) },
+            /* Jumps to dynamic labels are always encoded with a 32 bit displacement, so every entry is 5 bytes */
+            Instr::JumpTableEntry(label) => { dynasm!(ops; .arch x64; jmp =>labels[label]); },
+            Instr::Je(label) => {dynasm!(ops; .arch x64; je =>labels[label]); },
+            Instr::Jne(label) => { dynasm!(ops; .arch x64; jne =>labels[label]); },
+            Instr::Jl(label) => { dynasm!(ops; .arch x64; jl =>labels[label]); },
+            Instr::Jle(label) => { dynasm!(ops; .arch x64; jle =>labels[label]); },
+            Instr::Jg(label) => { dynasm!(ops; .arch x64; jg =>labels[label]); },
+            Instr::Jge(label) => { dynasm!(ops; .arch x64; jge =>labels[label]); },
+            Instr::Jno(label) => { dynasm!(ops; .arch x64; jno =>labels[label]); },
+            Instr::Jo(label) => { dynasm!(ops; .arch x64; jo =>labels[label]); },
             Instr::Cmove(dest, src) => { dynasm!(ops; .arch x64; cmove Rq(reg_to_number(dest)), Rq(reg_to_number(src))); },
             Instr::Cmovne(dest, src) => { dynasm!(ops; .arch x64; cmovne Rq(reg_to_number(dest)), Rq(reg_to_number(src))); },
             Instr::Cmovl(dest, src) => { dynasm!(ops; .arch x64; cmovl Rq(reg_to_number(dest)), Rq(reg_to_number(src))); },
             Instr::Cmovle(dest, src) => { dynasm!(ops; .arch x64; cmovle Rq(reg_to_number(dest)), Rq(reg_to_number(src))); },
             Instr::Cmovg(dest, src) => { dynasm!(ops; .arch x64; cmovg Rq(reg_to_number(dest)), Rq(reg_to_number(src))); },
             Instr::Cmovge(dest, src) => { dynasm!(ops; .arch x64; cmovge Rq(reg_to_number(dest)), Rq(reg_to_number(src))); },
-            Instr::ShiftArithmeticRight(reg, val) => { dynasm!(ops; .arch x64; sar Rq(reg_to_number(reg)), *val as i8); }
-            Instr::CallRustError(err_code) => {
-                let snek_error_ptr = snek_error as *const ();
-                let snek_error_addr = unsafe { mem::transmute::<* const (), fn() -> i32>(snek_error_ptr) } as i64;
-                dynasm!(ops; .arch x64; sub rsp, 8);
+            Instr::Cmova(dest, src) => { dynasm!(ops; .arch x64; cmova Rq(reg_to_number(dest)), Rq(reg_to_number(src))); },
+            Instr::Cmovae(dest, src) => { dynasm!(ops; .arch x64; cmovae Rq(reg_to_number(dest)), Rq(reg_to_number(src))); },
+            Instr::Cvtsi2sd(xmm, reg) => { dynasm!(ops; .arch x64; cvtsi2sd Rx(xmm_to_number(xmm)), Rq(reg_to_number(reg))); },
+            Instr::MovsdFromPtr(xmm, ptr, offset) => { dynasm!(ops; .arch x64; movsd Rx(xmm_to_number(xmm)), QWORD [Rq(reg_to_number(ptr)) + *offset]); },
+            Instr::MovqFromXmm(reg, xmm) => { dynasm!(ops; .arch x64; movq Rq(reg_to_number(reg)), Rx(xmm_to_number(xmm))); },
+            Instr::Addsd(dst, src) => { dynasm!(ops; .arch x64; addsd Rx(xmm_to_number(dst)), Rx(xmm_to_number(src))); },
+            Instr::Subsd(dst, src) => { dynasm!(ops; .arch x64; subsd Rx(xmm_to_number(dst)), Rx(xmm_to_number(src))); },
+            Instr::Mulsd(dst, src) => { dynasm!(ops; .arch x64; mulsd Rx(xmm_to_number(dst)), Rx(xmm_to_number(src))); },
+            Instr::Divsd(dst, src) => { dynasm!(ops; .arch x64; divsd Rx(xmm_to_number(dst)), Rx(xmm_to_number(src))); },
+            Instr::Ucomisd(a, b) => { dynasm!(ops; .arch x64; ucomisd Rx(xmm_to_number(a)), Rx(xmm_to_number(b))); },
+            Instr::ShiftArithmeticRight(reg, val) => { dynasm!(ops; .arch x64; sar Rq(reg_to_number(reg)), *val); }
+            Instr::ShiftLeft(reg, val) => { dynasm!(ops; .arch x64; shl Rq(reg_to_number(reg)), *val); }
+            Instr::ShiftArithmeticRightCl(reg) => { dynasm!(ops; .arch x64; sar Rq(reg_to_number(reg)), cl); }
+            Instr::ShiftLeftCl(reg) => { dynasm!(ops; .arch x64; shl Rq(reg_to_number(reg)), cl); }
+            Instr::CallRustError(err_code, frame) => {
+                let snek_error_addr = snek_error as *const () as i64;
+                dynasm!(ops; .arch x64; sub rsp, *frame; and rsp, -16);
                 dynasm!(ops; .arch x64; mov rdi, *err_code as i32);
                 dynasm!(ops; .arch x64; mov rax, QWORD snek_error_addr as _);
                 dynasm!(ops; .arch x64; call rax);
-                dynasm!(ops; .arch x64; add rsp, 8);
             },
-            Instr::CallRustPrint(reg) => {
-                let snek_print_ptr = snek_print as *const ();
-                let snek_print_addr = unsafe { mem::transmute::<* const (), fn() -> i32>(snek_print_ptr) } as i64;
-                dynasm!(ops; .arch x64; sub rsp, 8);
-                dynasm!(ops; .arch x64; mov rdi, Rq(reg_to_number(reg)));
-                dynasm!(ops; .arch x64; mov rax, QWORD snek_print_addr as _);
-                dynasm!(ops; .arch x64; call rax);
-                dynasm!(ops; .arch x64; add rsp, 8);
+            Instr::CallRust(f, args, frame) => {
+                /* Keep the old rsp and rdi (input) on the aligned stack, both survive the call */
+                dynasm!(ops; .arch x64; mov r11, rsp; sub rsp, *frame; and rsp, -16; push r11; push rdi);
+                for (index, offset) in args.iter().enumerate() {
+                    match index {
+                        0 => dynasm!(ops; .arch x64; mov rdi, [r11 - *offset]),
+                        1 => dynasm!(ops; .arch x64; mov rsi, [r11 - *offset]),
+                        2 => dynasm!(ops; .arch x64; mov rdx, [r11 - *offset]),
+                        3 => dynasm!(ops; .arch x64; mov rcx, [r11 - *offset]),
+                        _ => return Err(std::io::Error::other("too many arguments for a runtime call")),
+                    }
+                }
+                dynasm!(ops; .arch x64; mov rax, QWORD rust_fn_address(f); call rax; pop rdi; pop rsp);
             }
             Instr::Comment(_) => {},
             /* Instr::Ret => { dynasm!(ops; .arch x64; ret); } */
@@ -4,9 +4,19 @@ pub mod parse;
 pub mod compile;
 pub mod compile_helpers;
 pub mod compile_repl;
+pub mod lift;
+pub mod analysis;
+pub mod resolve;
+pub mod visit;
+pub mod pretty;
+pub mod format;
 pub mod counter;
 pub mod runtime;
+pub mod bignum;
 pub mod modes;
 pub mod context;
+pub mod macros;
+pub mod prelude;
 
-pub use crate::modes::{cli_mode, Repl};
+pub use crate::modes::{cli_mode, fmt_mode, parse_flags, Repl};
+pub use crate::context::Options;
@@ -0,0 +1,413 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::counter::next_id;
+use crate::expressions::{Binder, Expr, Defenition, Pattern, Program, ReplExpr, Variant, Signature};
+use crate::runtime::register_struct;
+use crate::visit::{fold_expr_children, Fold, Visitor, VisitorMut, walk_expr, walk_expr_mut};
+
+/* Functions defined with letrec are lifted to top level definitions under a fresh name.
+   Local variables of the enclosing scope that they use are passed as extra trailing
+   parameters, by value, so set! on a captured variable inside a helper stays local to it. */
+#[derive(Clone)]
+struct Lifted {
+    name: String,
+    captures: Vec<String>,
+}
+
+pub fn lift_program(mut prog: Program) -> std::io::Result<Program> {
+    let mut ids = VariantIds::new();
+    for def in &mut prog.defs {
+        if let Defenition::Enum(_, variants) = def {
+            register_enum(variants, &mut ids);
+        }
+    }
+    PatternIds(&ids).visit_program_mut(&mut prog);
+
+    let mut defs: Vec<Defenition> = Vec::new();
+    let mut declarations = prog.declarations;
+    for def in prog.defs {
+        defs.extend(lift_def(&def)?);
+        if !matches!(def, Defenition::Fun(_, _, _, _)) {
+            declarations.push(def);
+        }
+    }
+    let mut globals: Vec<(String, Expr)> = Vec::new();
+    for (name, e) in prog.globals {
+        let (lifted, helpers) = lift_expr(&e)?;
+        defs.extend(helpers);
+        globals.push((name, lifted));
+    }
+    let (main, helpers) = lift_expr(&prog.main)?;
+    defs.extend(helpers);
+    check_global_order(&defs, &globals)?;
+    Ok(Program { defs, globals, main, declarations, warnings: prog.warnings })
+}
+
+/* The globals an expression reads or assigns and the functions it calls */
+#[derive(Default)]
+struct GlobalUses {
+    globals: BTreeSet<String>,
+    calls: BTreeSet<String>,
+}
+
+/* A global has no value before its initializer ran, so an initializer may only use the globals defined
+   above it, directly or through the functions it calls. Main and the functions called from it see all of them. */
+fn check_global_order(defs: &[Defenition], globals: &[(String, Expr)]) -> std::io::Result<()> {
+    let names: HashSet<String> = globals.iter().map(|(name, _)| name.clone()).collect();
+    if names.is_empty() {
+        return Ok(());
+    }
+
+    let mut funs: HashMap<&String, GlobalUses> = HashMap::new();
+    for (name, params, body) in defs.iter().filter_map(Defenition::as_fun) {
+        let mut uses = GlobalUses::default();
+        global_uses(body, &params.iter().map(|p| p.name.clone()).collect(), &names, &mut uses);
+        funs.insert(name, uses);
+    }
+    /* Every function also uses what the functions it calls use */
+    let mut changed = true;
+    while changed {
+        changed = false;
+        let names: Vec<&String> = funs.keys().copied().collect();
+        for name in names {
+            let mut reached = BTreeSet::new();
+            for callee in &funs[name].calls {
+                if let Some(uses) = funs.get(callee) {
+                    reached.extend(uses.globals.iter().cloned());
+                }
+            }
+            let uses = funs.get_mut(name).unwrap();
+            let before = uses.globals.len();
+            uses.globals.extend(reached);
+            changed |= uses.globals.len() != before;
+        }
+    }
+
+    let mut defined: HashSet<&String> = HashSet::new();
+    for (name, e) in globals {
+        let mut uses = GlobalUses::default();
+        global_uses(e, &HashSet::new(), &names, &mut uses);
+        if let Some(used) = uses.globals.iter().find(|g| !defined.contains(g)) {
+            return Err(std::io::Error::other(format!("global {} uses {} before it is defined", name, used)));
+        }
+        for callee in &uses.calls {
+            if let Some(used) = funs.get(callee).and_then(|f| f.globals.iter().find(|g| !defined.contains(g))) {
+                return Err(std::io::Error::other(format!(
+                    "global {} calls {}, which uses {} before it is defined", name, callee, used
+                )));
+            }
+        }
+        defined.insert(name);
+    }
+    Ok(())
+}
+
+/* Walks with the names bound around the current expression, which hide the globals of the same name */
+struct Globals<'a> {
+    bound: HashSet<String>,
+    names: &'a HashSet<String>,
+    uses: &'a mut GlobalUses,
+}
+
+impl Globals<'_> {
+    fn with_bound(&mut self, names: impl Iterator<Item = String>, e: &Expr) {
+        let outer = self.bound.clone();
+        self.bound.extend(names);
+        self.visit_expr(e);
+        self.bound = outer;
+    }
+}
+
+impl Visitor for Globals<'_> {
+    fn visit_expr(&mut self, e: &Expr) {
+        match e {
+            Expr::Id(name) | Expr::Set(name, _) if self.names.contains(name) && !self.bound.contains(name) => {
+                self.uses.globals.insert(name.clone());
+            },
+            Expr::Call(name, _, _) => {
+                self.uses.calls.insert(name.clone());
+            },
+            Expr::Let(bindings, body) => {
+                let outer = self.bound.clone();
+                for (name, value) in bindings {
+                    self.visit_expr(value);
+                    self.bound.insert(name.name.clone());
+                }
+                self.visit_expr(body);
+                self.bound = outer;
+                return;
+            },
+            Expr::Try(body, name, handler) => {
+                self.visit_expr(body);
+                self.with_bound(std::iter::once(name.name.clone()), handler);
+                return;
+            },
+            Expr::Match(scrutinee, arms, _) => {
+                self.visit_expr(scrutinee);
+                for (pattern, body) in arms {
+                    self.with_bound(pattern.bindings().into_iter().map(|(_, name)| name.name.clone()), body);
+                }
+                return;
+            },
+            _ => {},
+        }
+        walk_expr(self, e);
+    }
+
+    fn visit_defenition(&mut self, def: &Defenition) {
+        if let Defenition::Fun(_, params, _, body) = def {
+            self.with_bound(params.iter().map(|p| p.name.clone()), body);
+        }
+    }
+}
+
+fn global_uses(e: &Expr, bound: &HashSet<String>, names: &HashSet<String>, uses: &mut GlobalUses) {
+    Globals { bound: bound.clone(), names, uses }.visit_expr(e);
+}
+
+/* Variant name to the id it was registered with */
+pub type VariantIds = HashMap<String, i64>;
+
+/* Enums are registered with the runtime when they are lowered, like structs, and every variant gets its own
+   struct id. The ids are kept by name for the patterns that match on the variants. */
+pub fn register_enum(variants: &mut [Variant], ids: &mut VariantIds) {
+    for variant in variants {
+        variant.id = register_struct(&variant.name, &variant.fields);
+        ids.insert(variant.name.clone(), variant.id);
+    }
+}
+
+/* The parser leaves the id of a variant pattern 0, it is filled in once the enum is registered */
+struct PatternIds<'a>(&'a VariantIds);
+
+impl VisitorMut for PatternIds<'_> {
+    fn visit_expr_mut(&mut self, e: &mut Expr) {
+        if let Expr::Match(_, arms, _) = e {
+            for (pattern, _) in arms.iter_mut() {
+                if let Pattern::Variant(name, id, _, _) = pattern {
+                    if let Some(registered) = self.0.get(name) {
+                        *id = *registered;
+                    }
+                }
+            }
+        }
+        walk_expr_mut(self, e);
+    }
+}
+
+/* A REPL input is lowered on its own, the enums of earlier inputs are found in ids */
+pub fn lower_repl_enums(expr: &mut ReplExpr, ids: &mut VariantIds) {
+    match expr {
+        ReplExpr::Enum(_, variants) => register_enum(variants, ids),
+        ReplExpr::Fun(_, _, _, e) | ReplExpr::Define(_, e) | ReplExpr::Expr(e) => PatternIds(ids).visit_expr_mut(e),
+        ReplExpr::Struct(_, _) | ReplExpr::Syntax(_, _) => {},
+    }
+}
+
+/* Returns the definition itself followed by every helper lifted out of its body */
+pub fn lift_def(def: &Defenition) -> std::io::Result<Vec<Defenition>> {
+    match def {
+        Defenition::Fun(name, params, signature, body) => {
+            let mut helpers: Vec<Defenition> = Vec::new();
+            let scope: HashSet<String> = params.iter().map(|p| p.name.clone()).collect();
+            let lifted_body = lift(body, scope, &mut helpers)?;
+
+            let mut result = vec![Defenition::Fun(name.clone(), params.clone(), signature.clone(), Box::new(lifted_body))];
+            result.extend(helpers);
+            Ok(result)
+        }
+        Defenition::Struct(name, fields) => Ok(struct_functions(name, fields)),
+        Defenition::Enum(_, variants) => Ok(variants.iter().map(variant_constructor).collect()),
+    }
+}
+
+/* Runs after register_enum gave the variant its id */
+fn variant_constructor(variant: &Variant) -> Defenition {
+    Defenition::Fun(
+        variant.name.clone(),
+        variant.fields.iter().map(|f| Binder::new(f)).collect(),
+        Signature::fixed(variant.fields.len()),
+        Box::new(Expr::MakeStruct(variant.id, variant.fields.iter().map(|f| Expr::Id(f.clone())).collect())),
+    )
+}
+
+/* A struct declaration becomes its constructor, one accessor per field named struct-field and the
+   predicate struct?, the struct itself is registered with the runtime so that values can be printed */
+fn struct_functions(name: &str, fields: &[String]) -> Vec<Defenition> {
+    let id = register_struct(name, fields);
+    let mut result = vec![Defenition::Fun(
+        name.to_string(),
+        fields.iter().map(|f| Binder::new(f)).collect(),
+        Signature::fixed(fields.len()),
+        Box::new(Expr::MakeStruct(id, fields.iter().map(|f| Expr::Id(f.clone())).collect())),
+    )];
+    for (index, field) in fields.iter().enumerate() {
+        result.push(Defenition::Fun(
+            format!("{}-{}", name, field),
+            vec![Binder::new("value")],
+            Signature::fixed(1),
+            Box::new(Expr::GetField(id, index, Box::new(Expr::Id("value".to_string())))),
+        ));
+    }
+    result.push(Defenition::Fun(
+        format!("{}?", name),
+        vec![Binder::new("value")],
+        Signature::fixed(1),
+        Box::new(Expr::IsStruct(id, Box::new(Expr::Id("value".to_string())))),
+    ));
+    result
+}
+
+pub fn lift_expr(e: &Expr) -> std::io::Result<(Expr, Vec<Defenition>)> {
+    let mut helpers: Vec<Defenition> = Vec::new();
+    let lifted = lift(e, HashSet::new(), &mut helpers)?;
+    Ok((lifted, helpers))
+}
+
+fn lift(e: &Expr, scope: HashSet<String>, out: &mut Vec<Defenition>) -> std::io::Result<Expr> {
+    let mut lifter = Lifter { scope, renames: HashMap::new(), out, error: None };
+    let lifted = lifter.fold_expr(e.clone());
+    match lifter.error {
+        Some(err) => Err(err),
+        None => Ok(lifted),
+    }
+}
+
+/* The variables in scope and the helpers lifted so far. A fold can't fail, the first error is kept and
+   returned once the fold is done. */
+struct Lifter<'a> {
+    scope: HashSet<String>,
+    renames: HashMap<String, Lifted>,
+    out: &'a mut Vec<Defenition>,
+    error: Option<std::io::Error>,
+}
+
+impl Lifter<'_> {
+    /* Folds e with the names bound on top of the current scope */
+    fn fold_in_scope<'n>(&mut self, names: impl Iterator<Item = &'n String>, e: Expr) -> Expr {
+        let outer = self.scope.clone();
+        self.scope.extend(names.cloned());
+        let folded = self.fold_expr(e);
+        self.scope = outer;
+        folded
+    }
+
+    fn letrec(&mut self, funs: Vec<Defenition>, body: Expr) -> Expr {
+        let mut captured: HashSet<String> = HashSet::new();
+        for (_, params, fun_body) in funs.iter().filter_map(Defenition::as_fun) {
+            let mut used = HashSet::new();
+            collect_identifiers(&self.renames, fun_body, &mut used);
+            for name in used {
+                if self.scope.contains(&name) && !params.iter().any(|p| p.name == name) {
+                    captured.insert(name);
+                }
+            }
+        }
+        let mut captures: Vec<String> = captured.into_iter().collect();
+        captures.sort();
+
+        let mut inner_renames = self.renames.clone();
+        for (name, _, _) in funs.iter().filter_map(Defenition::as_fun) {
+            let lifted_name = format!("{}#{}", name, next_id());
+            inner_renames.insert(name.clone(), Lifted { name: lifted_name, captures: captures.clone() });
+        }
+        let outer_renames = std::mem::replace(&mut self.renames, inner_renames);
+        let outer_scope = self.scope.clone();
+
+        for def in funs {
+            if let Defenition::Fun(name, params, _, fun_body) = def {
+                if let Some(param) = params.iter().find(|p| captures.contains(&p.name)) {
+                    self.error.get_or_insert(std::io::Error::other(format!(
+                        "parameter {} of {} shadows a variable captured by letrec", param.name, name
+                    )));
+                    continue;
+                }
+                let mut all_params = params;
+                all_params.extend(captures.iter().map(|c| Binder::new(c)));
+
+                self.scope = all_params.iter().map(|p| p.name.clone()).collect();
+                let lifted_body = self.fold_expr(*fun_body);
+                let signature = Signature::fixed(all_params.len());
+                self.out.push(Defenition::Fun(self.renames[&name].name.clone(), all_params, signature, Box::new(lifted_body)));
+            }
+        }
+
+        self.scope = outer_scope;
+        let lifted = self.fold_expr(body);
+        self.renames = outer_renames;
+        lifted
+    }
+}
+
+/* Only the forms that bind names and the calls of lifted helpers differ from the plain fold */
+impl Fold for Lifter<'_> {
+    fn fold_expr(&mut self, e: Expr) -> Expr {
+        match e {
+            Expr::Let(bindings, body) => {
+                let outer = self.scope.clone();
+                let mut lifted_bindings = Vec::new();
+                for (name, value) in bindings {
+                    let value = self.fold_expr(value);
+                    self.scope.insert(name.name.clone());
+                    lifted_bindings.push((name, value));
+                }
+                let body = self.fold_expr(*body);
+                self.scope = outer;
+                Expr::Let(lifted_bindings, Box::new(body))
+            }
+            Expr::LetRec(funs, body) => self.letrec(funs, *body),
+            Expr::Match(scrutinee, arms, span) => {
+                let scrutinee = Box::new(self.fold_expr(*scrutinee));
+                let mut lifted_arms = Vec::new();
+                for (pattern, body) in arms {
+                    let names: Vec<String> = pattern.bindings().into_iter().map(|(_, name)| name.name.clone()).collect();
+                    let body = self.fold_in_scope(names.iter(), body);
+                    lifted_arms.push((pattern, body));
+                }
+                Expr::Match(scrutinee, lifted_arms, span)
+            }
+            Expr::Try(body, name, handler) => {
+                let body = Box::new(self.fold_expr(*body));
+                let handler = Box::new(self.fold_in_scope(std::iter::once(&name.name), *handler));
+                Expr::Try(body, name, handler)
+            }
+            Expr::Call(name, args, span) => {
+                let mut lifted_args: Vec<Expr> = args.into_iter().map(|a| self.fold_expr(a)).collect();
+                match self.renames.get(&name) {
+                    Some(lifted) => {
+                        lifted_args.extend(lifted.captures.iter().map(|c| Expr::Id(c.clone())));
+                        Expr::Call(lifted.name.clone(), lifted_args, span)
+                    }
+                    None => Expr::Call(name, lifted_args, span),
+                }
+            }
+            e => fold_expr_children(self, e),
+        }
+    }
+}
+
+/* Every variable that is read or assigned anywhere inside the expression, including nested letrec helpers
+   and the captures that calls to already lifted helpers will pass along */
+struct Identifiers<'a> {
+    renames: &'a HashMap<String, Lifted>,
+    result: &'a mut HashSet<String>,
+}
+
+impl Visitor for Identifiers<'_> {
+    fn visit_expr(&mut self, e: &Expr) {
+        match e {
+            Expr::Id(name) | Expr::Set(name, _) => { self.result.insert(name.clone()); },
+            Expr::Call(name, _, _) => {
+                if let Some(lifted) = self.renames.get(name) {
+                    self.result.extend(lifted.captures.iter().cloned());
+                }
+            },
+            _ => {},
+        }
+        walk_expr(self, e);
+    }
+}
+
+fn collect_identifiers(renames: &HashMap<String, Lifted>, e: &Expr, result: &mut HashSet<String>) {
+    Identifiers { renames, result }.visit_expr(e);
+}
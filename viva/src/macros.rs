@@ -0,0 +1,380 @@
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+use crate::analysis::Span;
+use crate::counter::next_id;
+use crate::parse::{is_keyword, parse_err, Sexp};
+use crate::parse::Atom::*;
+
+/* A macro whose expansions keep producing uses of macros is cut off after this many steps */
+const MAX_EXPANSIONS: usize = 1000;
+
+/* A syntax-rules macro: the rules are tried in order and the use is replaced by the template of the first
+   pattern that matches. Patterns are stored without the leading macro keyword. */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Macro {
+    literals: Vec<String>,
+    rules: Vec<(Vec<Sexp>, Sexp)>,
+}
+
+/* Sexp has no Eq and Hash because of floats, the macro is compared and hashed by its source */
+impl Eq for Macro {}
+
+impl Hash for Macro {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.literals.hash(state);
+        for (pattern, template) in &self.rules {
+            for p in pattern {
+                p.to_string().hash(state);
+            }
+            template.to_string().hash(state);
+        }
+    }
+}
+
+impl Macro {
+    /* The define-syntax form of the macro, the patterns start with _ in place of the macro keyword */
+    pub fn definition(&self, name: &str) -> Sexp {
+        let atom = |s: &str| Sexp::Atom(S(s.to_string()), Span::default());
+        let list = |items: Vec<Sexp>| Sexp::List(items, Span::default());
+        let mut spec = vec![atom("syntax-rules"), list(self.literals.iter().map(|l| atom(l)).collect())];
+        for (pattern, template) in &self.rules {
+            let mut full = vec![atom("_")];
+            full.extend(pattern.iter().cloned());
+            spec.push(list(vec![list(full), template.clone()]));
+        }
+        list(vec![atom("define-syntax"), atom(name), list(spec)])
+    }
+}
+
+/* What a pattern variable matched, a variable under ... matches one binding per repetition */
+#[derive(Clone)]
+enum Binding {
+    One(Sexp),
+    Many(Vec<Binding>),
+}
+
+/* The macros visible in a file or in the REPL session */
+#[derive(Clone, Default)]
+pub struct Macros {
+    macros: HashMap<String, Macro>,
+}
+
+impl Macros {
+    pub fn define(&mut self, name: &str, m: Macro) -> std::io::Result<()> {
+        if self.macros.contains_key(name) {
+            return parse_err("Duplicate macro name");
+        }
+        self.macros.insert(name.to_string(), m);
+        Ok(())
+    }
+
+    /* Macro definitions can appear anywhere among the top-level forms, the other forms are returned expanded */
+    pub fn expand_items(&mut self, items: &[Sexp]) -> std::io::Result<Vec<Sexp>> {
+        let mut rest = Vec::new();
+        for item in items {
+            match parse_macro(item)? {
+                Some((name, m)) => self.define(&name, m)?,
+                None => rest.push(item),
+            }
+        }
+        rest.into_iter().map(|item| self.expand(item)).collect()
+    }
+
+    /* Replaces every use of a macro, what an expansion produces is expanded again */
+    pub fn expand(&self, s: &Sexp) -> std::io::Result<Sexp> {
+        self.expand_at(s, 0)
+    }
+
+    fn expand_at(&self, s: &Sexp, depth: usize) -> std::io::Result<Sexp> {
+        match s {
+            Sexp::List(items, span) => match items.first() {
+                Some(Sexp::Atom(S(op), _)) if op == "define-syntax" => Ok(s.clone()),
+                Some(Sexp::Atom(S(name), _)) if self.macros.contains_key(name) => {
+                    if depth == MAX_EXPANSIONS {
+                        return parse_err(&format!("expansion of {} doesn't terminate", name));
+                    }
+                    let expanded = self.macros[name].apply(name, &items[1..], span)?;
+                    self.expand_at(&expanded, depth + 1)
+                }
+                _ => {
+                    let mut expanded = Vec::new();
+                    for item in items {
+                        expanded.push(self.expand_at(item, depth)?);
+                    }
+                    Ok(Sexp::List(expanded, span.clone()))
+                }
+            },
+            _ => Ok(s.clone()),
+        }
+    }
+}
+
+impl Macro {
+    /* What the template writes itself is placed at the use of the macro */
+    fn apply(&self, name: &str, args: &[Sexp], at: &Span) -> std::io::Result<Sexp> {
+        for (pattern, template) in &self.rules {
+            let mut bindings = HashMap::new();
+            if match_list(pattern, args, &self.literals, &mut bindings) {
+                /* Hygiene: names the template itself binds get a fresh name on every expansion, so they can't
+                   capture or shadow the names used in the arguments */
+                let mut introduced = Vec::new();
+                binders(template, &mut introduced);
+                let renames = introduced.into_iter()
+                    .filter(|n| n != "_" && !bindings.contains_key(n))
+                    .map(|n| {
+                        let fresh = format!("#{}{}", n, next_id());
+                        (n, fresh)
+                    })
+                    .collect();
+                return instantiate(template, &bindings, &renames, at);
+            }
+        }
+        parse_err(&format!("no syntax-rules pattern of {} matches", name))
+    }
+}
+
+/* (define-syntax name (syntax-rules (literal*) ((_ pattern*) template)+)) */
+pub fn parse_macro(s: &Sexp) -> std::io::Result<Option<(String, Macro)>> {
+    let items = match s {
+        Sexp::List(items, _) => items,
+        _ => return Ok(None),
+    };
+    match &items[..] {
+        [Sexp::Atom(S(op), _), ..] if op == "define-syntax" => {}
+        _ => return Ok(None),
+    }
+    let (name, literals, rules) = match &items[..] {
+        [_, Sexp::Atom(S(name), _), Sexp::List(spec, _)] if !is_keyword(name) => match &spec[..] {
+            [Sexp::Atom(S(op), _), Sexp::List(literals, _), rules @ ..] if op == "syntax-rules" && !rules.is_empty() => (name, literals, rules),
+            _ => return parse_err("define-syntax needs (syntax-rules (literal*) (pattern template)+)"),
+        },
+        _ => return parse_err("define-syntax needs a name and syntax-rules"),
+    };
+
+    let mut literal_names = Vec::new();
+    for literal in literals {
+        match literal {
+            Sexp::Atom(S(l), _) if l != "_" && l != "..." => literal_names.push(l.clone()),
+            _ => return parse_err("syntax-rules literals have to be names"),
+        }
+    }
+
+    let mut parsed = Vec::new();
+    for rule in rules {
+        match rule {
+            Sexp::List(rule, _) => match &rule[..] {
+                [Sexp::List(pattern, _), template] if !pattern.is_empty() => {
+                    check_pattern(&pattern[1..], &literal_names, &mut Vec::new())?;
+                    parsed.push((pattern[1..].to_vec(), template.clone()));
+                }
+                _ => return parse_err("a syntax-rules rule is (pattern template)"),
+            },
+            _ => return parse_err("a syntax-rules rule is (pattern template)"),
+        }
+    }
+    Ok(Some((name.clone(), Macro { literals: literal_names, rules: parsed })))
+}
+
+fn is_ellipsis(s: &Sexp) -> bool {
+    matches!(s, Sexp::Atom(S(e), _) if e == "...")
+}
+
+fn check_pattern(patterns: &[Sexp], literals: &[String], seen: &mut Vec<String>) -> std::io::Result<()> {
+    let ellipses: Vec<usize> = patterns.iter().enumerate().filter(|(_, p)| is_ellipsis(p)).map(|(i, _)| i).collect();
+    if ellipses.len() > 1 || ellipses.first() == Some(&0) {
+        return parse_err("... has to follow a pattern, at most once per list");
+    }
+    for p in patterns {
+        match p {
+            Sexp::Atom(S(name), _) if name == "_" || name == "..." || literals.contains(name) => {}
+            Sexp::Atom(S(name), _) => {
+                if seen.contains(name) {
+                    return parse_err(&format!("pattern variable {} is used twice", name));
+                }
+                seen.push(name.clone());
+            }
+            Sexp::Atom(_, _) => {}
+            Sexp::List(inner, _) => check_pattern(inner, literals, seen)?,
+        }
+    }
+    Ok(())
+}
+
+fn pattern_vars(pattern: &Sexp, literals: &[String], vars: &mut Vec<String>) {
+    match pattern {
+        Sexp::Atom(S(name), _) if name != "_" && name != "..." && !literals.contains(name) => vars.push(name.clone()),
+        Sexp::List(items, _) => {
+            for item in items {
+                pattern_vars(item, literals, vars);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn match_pattern(pattern: &Sexp, form: &Sexp, literals: &[String], bindings: &mut HashMap<String, Binding>) -> bool {
+    match pattern {
+        Sexp::Atom(S(name), _) if name == "_" => true,
+        Sexp::Atom(S(name), _) if literals.contains(name) => form == pattern,
+        Sexp::Atom(S(name), _) => {
+            bindings.insert(name.clone(), Binding::One(form.clone()));
+            true
+        }
+        Sexp::Atom(_, _) => form == pattern,
+        Sexp::List(patterns, _) => match form {
+            Sexp::List(forms, _) => match_list(patterns, forms, literals, bindings),
+            _ => false,
+        },
+    }
+}
+
+/* The pattern before ... matches as many forms as are left after the patterns that follow it */
+fn match_list(patterns: &[Sexp], forms: &[Sexp], literals: &[String], bindings: &mut HashMap<String, Binding>) -> bool {
+    let at = match patterns.iter().position(is_ellipsis) {
+        Some(at) => at,
+        None => {
+            return patterns.len() == forms.len()
+                && patterns.iter().zip(forms).all(|(p, f)| match_pattern(p, f, literals, bindings));
+        }
+    };
+    let repeated = &patterns[at - 1];
+    let (before, after) = (&patterns[..at - 1], &patterns[at + 1..]);
+    if forms.len() < before.len() + after.len() {
+        return false;
+    }
+    let rest = forms.len() - after.len();
+    if !match_list(before, &forms[..before.len()], literals, bindings) || !match_list(after, &forms[rest..], literals, bindings) {
+        return false;
+    }
+
+    let mut matches = Vec::new();
+    for form in &forms[before.len()..rest] {
+        let mut matched = HashMap::new();
+        if !match_pattern(repeated, form, literals, &mut matched) {
+            return false;
+        }
+        matches.push(matched);
+    }
+    let mut vars = Vec::new();
+    pattern_vars(repeated, literals, &mut vars);
+    for var in vars {
+        let repetitions = matches.iter().map(|m| m[&var].clone()).collect();
+        bindings.insert(var, Binding::Many(repetitions));
+    }
+    true
+}
+
+/* The names the binding forms of a template introduce */
+fn binders(template: &Sexp, found: &mut Vec<String>) {
+    let items = match template {
+        Sexp::List(items, _) => items,
+        _ => return,
+    };
+    match &items[..] {
+        [Sexp::Atom(S(op), _), Sexp::List(bindings, _), ..] if op == "let" || op == "let*" => {
+            for binding in bindings {
+                if let Sexp::List(pair, _) = binding {
+                    if let Some(Sexp::Atom(S(name), _)) = pair.first() {
+                        found.push(name.clone());
+                    }
+                }
+            }
+        }
+        [Sexp::Atom(S(op), _), Sexp::List(funs, _), ..] if op == "letrec" => {
+            for f in funs {
+                if let Sexp::List(def, _) = f {
+                    if let Some(Sexp::List(header, _)) = def.get(1) {
+                        for name in header {
+                            if let Sexp::Atom(S(name), _) = name {
+                                found.push(name.clone());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        [Sexp::Atom(S(op), _), rest @ ..] if op == "for" => {
+            if let Some(Sexp::List(range, _)) = rest.iter().find(|r| matches!(r, Sexp::List(_, _))) {
+                if let Some(Sexp::Atom(S(name), _)) = range.first() {
+                    found.push(name.clone());
+                }
+            }
+        }
+        [Sexp::Atom(S(op), _), Sexp::Atom(S(name), _), ..] if op == "catch" => found.push(name.clone()),
+        [Sexp::Atom(S(op), _), _, arms @ ..] if op == "match" => {
+            for arm in arms {
+                if let Sexp::List(arm, _) = arm {
+                    if let Some(Sexp::List(pattern, _)) = arm.first() {
+                        for name in pattern.iter().skip(1) {
+                            if let Sexp::Atom(S(name), _) = name {
+                                found.push(name.clone());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+    for item in items {
+        binders(item, found);
+    }
+}
+
+fn instantiate(template: &Sexp, bindings: &HashMap<String, Binding>, renames: &HashMap<String, String>, at: &Span) -> std::io::Result<Sexp> {
+    match template {
+        Sexp::Atom(S(name), _) => match bindings.get(name) {
+            Some(Binding::One(s)) => Ok(s.clone()),
+            Some(Binding::Many(_)) => parse_err(&format!("{} has to be followed by ... in the template", name)),
+            None => Ok(Sexp::Atom(S(renames.get(name).unwrap_or(name).clone()), at.clone())),
+        },
+        Sexp::Atom(atom, _) => Ok(Sexp::Atom(atom.clone(), at.clone())),
+        Sexp::List(items, _) => {
+            let mut result = Vec::new();
+            let mut i = 0;
+            while i < items.len() {
+                if items.get(i + 1).is_some_and(is_ellipsis) {
+                    for repetition in repetitions(&items[i], bindings)? {
+                        result.push(instantiate(&items[i], &repetition, renames, at)?);
+                    }
+                    i += 2;
+                } else {
+                    result.push(instantiate(&items[i], bindings, renames, at)?);
+                    i += 1;
+                }
+            }
+            Ok(Sexp::List(result, at.clone()))
+        }
+    }
+}
+
+/* The bindings for each repetition of a template followed by ..., the variables it uses that matched a
+   sequence are stepped through together */
+fn repetitions(template: &Sexp, bindings: &HashMap<String, Binding>) -> std::io::Result<Vec<HashMap<String, Binding>>> {
+    let mut names = Vec::new();
+    pattern_vars(template, &[], &mut names);
+    let mut repeated = Vec::new();
+    for name in names {
+        if let Some(Binding::Many(values)) = bindings.get(&name) {
+            repeated.push((name, values));
+        }
+    }
+    let count = match repeated.first() {
+        Some((_, values)) => values.len(),
+        None => return parse_err("... follows a template without pattern variables matched by ..."),
+    };
+    if repeated.iter().any(|(_, values)| values.len() != count) {
+        return parse_err("pattern variables used under the same ... matched different numbers of forms");
+    }
+
+    let mut result = Vec::new();
+    for i in 0..count {
+        let mut repetition = bindings.clone();
+        for (name, values) in &repeated {
+            repetition.insert(name.clone(), values[i].clone());
+        }
+        result.push(repetition);
+    }
+    Ok(result)
+}
@@ -1,21 +1,110 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io;
 use std::io::prelude::*;
 
-use sexp::*;
 // no explicit dynasm usage here; compilation happens in helpers
 
-use crate::parse::parse_repl_expr;
+use crate::parse::{fun_name, parse_repl_expr, read_forms, DefNames, Sexp};
+use crate::analysis::render;
+use crate::compile::function_label;
 use crate::compile_repl::{compile_repl_and_persist, compile_repl_to_instr};
 use crate::expressions::ReplExpr;
+use crate::resolve::resolve_repl_expr;
+use crate::lift::{lower_repl_enums, VariantIds};
+use crate::context::Options;
+use crate::format::format_source;
+use crate::macros::{parse_macro, Macros};
+use crate::prelude::prelude_items;
+use crate::runtime::{parse_arg, set_args, set_stdin, take_stdin, format_value};
 
-pub fn cli_mode() -> std::io::Result<()> {
-    let mut ops = dynasmrt::x64::Assembler::new().unwrap();
-    let mut labels: HashMap<String, dynasmrt::DynamicLabel> = HashMap::new();
-    let mut define_env: HashMap<String, i64> = HashMap::new();
-    let mut func_names: HashSet<String> = HashSet::new();
+/* Splits the command line switches from the remaining arguments */
+pub fn parse_flags(args: &[String]) -> std::io::Result<(Options, Vec<String>)> {
+    let mut options = Options::default();
+    let mut rest = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--bignum" => options.bignum = true,
+            "--no-contracts" => options.no_contracts = true,
+            "--no-prelude" => options.no_prelude = true,
+            flag if flag.starts_with("--") => {
+                return Err(std::io::Error::other(format!("Unknown flag {}", flag)));
+            }
+            _ => rest.push(arg.clone()),
+        }
+    }
+    Ok((options, rest))
+}
+
+/* viva fmt [--check] <file>...: rewrites the files in the fixed layout. With --check the files are left as
+   they are and the ones that would change are reported as an error, so a CI step fails on them. */
+pub fn fmt_mode(args: &[String]) -> std::io::Result<()> {
+    let check = args.iter().any(|arg| arg == "--check");
+    let files: Vec<&String> = args.iter().filter(|arg| *arg != "--check").collect();
+    if let Some(flag) = files.iter().find(|arg| arg.starts_with("--")) {
+        return Err(std::io::Error::other(format!("Unknown flag {}", flag)));
+    }
+    if files.is_empty() {
+        return Err(std::io::Error::other("fmt needs the files to format"));
+    }
+
+    let mut unformatted = Vec::new();
+    for file in files {
+        let source = std::fs::read_to_string(file)?;
+        let formatted = format_source(&source)
+            .map_err(|d| std::io::Error::other(render(&d, Some((file, &source)))))?;
+        if formatted == source {
+            continue;
+        }
+        if check {
+            unformatted.push(format!("{} is not formatted", file));
+        } else {
+            std::fs::write(file, formatted)?;
+        }
+    }
+    if !unformatted.is_empty() {
+        return Err(std::io::Error::other(unformatted.join("\n")));
+    }
+    Ok(())
+}
+
+/* Compiles the prelude functions into a REPL session and makes them callable, returns their names */
+fn load_prelude(
+    func_names: &mut DefNames,
+    define_env: &mut HashMap<String, i64>,
+    ops: &mut dynasmrt::x64::Assembler,
+    labels: &mut HashMap<String, dynasmrt::DynamicLabel>,
+    options: Options,
+) -> std::io::Result<HashSet<String>> {
+    let mut names = HashSet::new();
+    for item in prelude_items()? {
+        let mut expr = parse_repl_expr(&item, func_names)?;
+        resolve_repl_expr(&mut expr, func_names, HashSet::new(), "")?;
+        if let ReplExpr::Fun(name, _, signature, _) = &expr {
+            func_names.insert(name.clone(), signature.clone());
+            names.insert(name.clone());
+        }
+        compile_repl_to_instr(&expr, 2, define_env, ops, labels, options)?;
+    }
+    Ok(names)
+}
+
+/* A fun of the session may replace a prelude function, once. The code compiled before it keeps calling
+   the prelude version, the new label only binds what is compiled afterwards. */
+fn replace_prelude_fun(name: &str, prelude: &mut HashSet<String>, labels: &mut HashMap<String, dynasmrt::DynamicLabel>) {
+    if prelude.remove(name) {
+        labels.remove(&function_label(name));
+    }
+}
 
-    let mut reader = io::stdin().lock();
+/* The names a form is parsed with, without the prelude function it replaces */
+fn names_for(sexp: &Sexp, func_names: &DefNames, prelude: &HashSet<String>) -> Option<DefNames> {
+    fun_name(sexp).filter(|name| prelude.contains(name)).map(|name| func_names.without(&name))
+}
+
+/* The interactive REPL of the command line, every line is fed to one session */
+pub fn cli_mode(options: Options) -> std::io::Result<()> {
+    let mut repl = Repl::with_options(options)?;
+    repl.use_process_stdin();
     println!("Press ^D, exit or quit to exit the REPL interative mode.");
 
     loop {
@@ -23,68 +112,21 @@ pub fn cli_mode() -> std::io::Result<()> {
         io::stdout().flush()?;
 
         let mut buffer = String::new();
-        match reader.read_line(&mut buffer) {
+        match io::stdin().read_line(&mut buffer) {
             Ok(0) => {
                 println!("\nThanks for you business with us!");
                 break Ok(());
             }
             Ok(_) => {
                 let input = buffer.trim();
-
-                if input.is_empty() {
-                    continue;
+                match repl.feed(input) {
+                    Ok(Some(output)) => println!("{}", output),
+                    Ok(None) => {}
+                    Err(err) => println!("{}", err),
                 }
-
-                let command = input.to_lowercase();
-                if command == "exit" || command == "quit" {
-                    println!("Thanks for you business with us!");
+                if input == "exit" || input == "quit" {
                     break Ok(());
                 }
-
-                let sexp = match parse(&command) {
-                    Ok(s) => s,
-                    Err(_) => {
-                        println!("Invalid: parse error");
-                        continue;
-                    }
-                };
-
-                let expr = match parse_repl_expr(&sexp, &func_names) {
-                    Ok(e) => e,
-                    Err(_) => {
-                        println!("Invalid: parse error");
-                        continue;
-                    }
-                };
-
-                match &expr {
-                    ReplExpr::Fun(name, _, _) => {
-                        // Register the function name first to prevent future duplicates
-                        func_names.insert(name.clone());
-                        if let Err(err) = compile_repl_to_instr(&expr, 2, &mut define_env, &mut ops, &mut labels) {
-                            println!("{}", err);
-                        }
-                    }
-                    ReplExpr::Define(name, inner) => {
-                        if define_env.contains_key(name.as_str()) {
-                            println!("Duplicate binding");
-                            continue;
-                        }
-                        match compile_repl_and_persist(inner.as_ref(), 2, &mut define_env, &mut ops, &mut labels, false) {
-                            Ok(result) => {
-                                define_env.insert(name.clone(), result as i64);
-                            }
-                            Err(err) => println!("{}", err),
-                        }
-                    }
-                    ReplExpr::Expr(inner) => {
-                        // Print the result using runtime printer
-                        match compile_repl_and_persist(inner.as_ref(), 2, &mut define_env, &mut ops, &mut labels, true) {
-                            Ok(_) => {}
-                            Err(err) => println!("{}", err),
-                        }
-                    }
-                }
             }
             Err(e) => {
                 eprintln!("Error reading input {}", e);
@@ -94,31 +136,84 @@ pub fn cli_mode() -> std::io::Result<()> {
     }
 }
 
-fn format_viva_value(result: i64) -> String {
-    if result == 3 {
-        "true".to_string()
-    } else if result == 1 {
-        "false".to_string()
-    } else {
-        format!("{}", result >> 1)
+/* What a form of an input gave, a form that failed ends the input */
+enum Fed {
+    Output(Option<String>),
+    Failed(String),
+}
+
+/* An error after the lines the input gave before it, which are kept in front of it */
+fn after(lines: Vec<String>, err: io::Error) -> io::Error {
+    if lines.is_empty() {
+        return err;
     }
+    io::Error::new(err.kind(), lines.into_iter().chain([err.to_string()]).collect::<Vec<String>>().join("\n"))
 }
 
 pub struct Repl {
     ops: dynasmrt::x64::Assembler,
     labels: HashMap<String, dynasmrt::DynamicLabel>,
     define_env: HashMap<String, i64>,
-    func_names: HashSet<String>,
+    func_names: DefNames,
+    macros: Macros,
+    variant_ids: VariantIds,
+    /* Prelude functions the session has not replaced yet */
+    prelude: HashSet<String>,
+    inputs: Vec<i64>,
+    /* None reads the process stdin */
+    stdin: Option<VecDeque<String>>,
+    options: Options,
 }
 
 impl Repl {
-    pub fn new() -> Self {
-        Repl {
+    pub fn new() -> std::io::Result<Self> {
+        Self::with_options(Options::default())
+    }
+
+    /* The prelude is compiled when the session starts, so --no-prelude only has an effect here */
+    pub fn with_options(options: Options) -> std::io::Result<Self> {
+        let mut repl = Repl {
             ops: dynasmrt::x64::Assembler::new().unwrap(),
             labels: HashMap::new(),
             define_env: HashMap::new(),
-            func_names: HashSet::new(),
+            func_names: DefNames::default(),
+            macros: Macros::default(),
+            variant_ids: VariantIds::new(),
+            prelude: HashSet::new(),
+            inputs: Vec::new(),
+            stdin: Some(VecDeque::new()),
+            options,
+        };
+        if !options.no_prelude {
+            repl.prelude = load_prelude(&mut repl.func_names, &mut repl.define_env, &mut repl.ops, &mut repl.labels, options)?;
         }
+        Ok(repl)
+    }
+
+    /* Applies to everything fed afterwards, functions that are already compiled keep their mode */
+    pub fn set_options(&mut self, options: Options) {
+        self.options = options;
+    }
+
+    /* Arguments available through input, (arg i) and (argc) for everything fed afterwards */
+    pub fn set_inputs(&mut self, inputs: &[String]) -> std::io::Result<()> {
+        let mut parsed = Vec::new();
+        for input in inputs {
+            parsed.push(parse_arg(input)?);
+        }
+        self.inputs = parsed;
+        Ok(())
+    }
+
+    /* Text that read-num and read-bool consume line by line instead of the process stdin,
+       lines that are not read stay available for the following inputs */
+    pub fn set_stdin(&mut self, text: &str) {
+        self.stdin = Some(text.lines().map(|l| l.to_string()).collect());
+    }
+
+    /* Without stdin a session is at the end of its input, only the command line REPL reads the process stdin */
+    pub fn use_process_stdin(&mut self) {
+        self.stdin = None;
     }
 
     pub fn feed(&mut self, raw: &str) -> std::io::Result<Option<String>> {
@@ -128,44 +223,111 @@ impl Repl {
             return Ok(None);
         }
 
-        let command = input.to_lowercase();
-        if command == "exit" || command == "quit" {
+        if input == "exit" || input == "quit" {
             return Ok(Some("Thanks for you business with us!".to_string()));
         }
 
-        let sexp = match parse(&command) {
-            Ok(s) => s,
-            Err(_) => {
-                return Ok(Some("Invalid: parse error".to_string()));
+        let forms = match read_forms("input", input) {
+            Ok(forms) => forms,
+            Err(err) => return Ok(Some(format!("{}", err))),
+        };
+
+        /* An input can hold several forms, what each of them gives is on its own line. The first one that fails
+           ends the input, the forms after it are not run. */
+        let mut outputs = Vec::new();
+        for (sexp, _) in forms {
+            match self.feed_form(&sexp, input) {
+                Ok(Fed::Output(output)) => outputs.extend(output),
+                Ok(Fed::Failed(message)) => {
+                    outputs.push(message);
+                    break;
+                }
+                Err(err) => return Err(after(outputs, err)),
             }
+        }
+        if outputs.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(outputs.join("\n")))
+    }
+
+    /* The spans of the form are places in the whole input it was read from */
+    fn feed_form(&mut self, sexp: &Sexp, input: &str) -> std::io::Result<Fed> {
+        /* What is wrong with a macro definition or a use of it is said, unlike other parse errors */
+        let sexp = match self.macros.expand(sexp).and_then(|s| parse_macro(&s).map(|_| s)) {
+            Ok(s) => s,
+            Err(err) => return Ok(Fed::Failed(err.to_string())),
         };
 
-        let expr = match parse_repl_expr(&sexp, &self.func_names) {
+        let replacing = names_for(&sexp, &self.func_names, &self.prelude);
+        let mut expr = match parse_repl_expr(&sexp, replacing.as_ref().unwrap_or(&self.func_names)) {
             Ok(e) => e,
             Err(_) => {
-                return Ok(Some("Invalid: parse error".to_string()));
+                return Ok(Fed::Failed("Invalid: parse error".to_string()));
             }
         };
 
-        match &expr {
-            ReplExpr::Fun(name, _, _) => {
-                self.func_names.insert(name.clone());
-                if let Err(err) = compile_repl_to_instr(&expr, 2, &mut self.define_env, &mut self.ops, &mut self.labels) {
-                    return Ok(Some(format!("{}", err)));
+        let warnings = match resolve_repl_expr(&mut expr, &self.func_names, self.define_env.keys().cloned().collect(), input) {
+            Ok(warnings) => warnings,
+            Err(err) => return Ok(Fed::Failed(format!("{}", err))),
+        };
+        lower_repl_enums(&mut expr, &mut self.variant_ids);
+
+        set_args(self.inputs.clone());
+        set_stdin(self.stdin.take());
+        let result = self.run(&expr);
+        self.stdin = take_stdin();
+
+        /* Warnings come before what the input printed or evaluated to */
+        match result {
+            Ok(Fed::Output(None)) if warnings.is_empty() => Ok(Fed::Output(None)),
+            Ok(Fed::Output(output)) => Ok(Fed::Output(Some(warnings.into_iter().chain(output).collect::<Vec<String>>().join("\n")))),
+            Ok(Fed::Failed(message)) => Ok(Fed::Failed(warnings.into_iter().chain([message]).collect::<Vec<String>>().join("\n"))),
+            Err(err) => Err(after(warnings, err)),
+        }
+    }
+
+    fn run(&mut self, expr: &ReplExpr) -> std::io::Result<Fed> {
+        match expr {
+            ReplExpr::Fun(name, _, signature, _) => {
+                self.func_names.insert(name.clone(), signature.clone());
+                replace_prelude_fun(name, &mut self.prelude, &mut self.labels);
+                if let Err(err) = compile_repl_to_instr(expr, 2, &mut self.define_env, &mut self.ops, &mut self.labels, self.options) {
+                    return Ok(Fed::Failed(format!("{}", err)));
+                }
+                Ok(Fed::Output(None))
+            }
+            ReplExpr::Struct(name, fields) => {
+                self.func_names.add_struct(name, fields);
+                if let Err(err) = compile_repl_to_instr(expr, 2, &mut self.define_env, &mut self.ops, &mut self.labels, self.options) {
+                    return Ok(Fed::Failed(format!("{}", err)));
+                }
+                Ok(Fed::Output(None))
+            }
+            ReplExpr::Enum(name, variants) => {
+                self.func_names.add_enum(name, variants);
+                if let Err(err) = compile_repl_to_instr(expr, 2, &mut self.define_env, &mut self.ops, &mut self.labels, self.options) {
+                    return Ok(Fed::Failed(format!("{}", err)));
+                }
+                Ok(Fed::Output(None))
+            }
+            ReplExpr::Syntax(name, m) => {
+                if let Err(err) = self.macros.define(name, m.clone()) {
+                    return Ok(Fed::Failed(format!("{}", err)));
                 }
-                Ok(None)
+                Ok(Fed::Output(None))
             }
             ReplExpr::Define(name, inner) => {
                 if self.define_env.contains_key(name.as_str()) {
-                    return Ok(Some("Duplicate binding".to_string()));
+                    return Ok(Fed::Failed("Duplicate binding".to_string()));
                 }
-                let result = compile_repl_and_persist(inner.as_ref(), 2, &mut self.define_env, &mut self.ops, &mut self.labels, false)?;
+                let result = compile_repl_and_persist(inner.as_ref(), 2, &mut self.define_env, &mut self.ops, &mut self.labels, false, self.options)?;
                 self.define_env.insert(name.clone(), result as i64);
-                Ok(None)
+                Ok(Fed::Output(None))
             }
             ReplExpr::Expr(inner) => {
-                let result = compile_repl_and_persist(inner.as_ref(), 2, &mut self.define_env, &mut self.ops, &mut self.labels, false)?;
-                Ok(Some(format_viva_value(result)))
+                let result = compile_repl_and_persist(inner.as_ref(), 2, &mut self.define_env, &mut self.ops, &mut self.labels, false, self.options)?;
+                Ok(Fed::Output(Some(format_value(result))))
             }
         }
     }
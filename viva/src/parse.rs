@@ -1,16 +1,139 @@
-use sexp::*;
-use sexp::Atom::*;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 
-use crate::expressions::{Op1, Op2, Expr, ReplExpr, Program, Defenition};
+use crate::expressions::{Op1, Op2, Expr, ReplExpr, Program, Defenition, Variant, Pattern, Signature, Binder};
+use crate::counter::next_id;
+use crate::lift::lift_program;
+use crate::macros::{Macros, parse_macro};
+use crate::prelude::prelude_items;
+use crate::context::Options;
+use crate::resolve::resolve_program;
+use crate::analysis::{render, Diagnostic, Span};
+use Atom::*;
 
-fn parse_fun_header(params: &[Sexp]) -> std::io::Result<(String, Vec<String>)> {
+/* Names known to the parser: the functions that can be called and the enums that can be matched on */
+#[derive(Clone, Default)]
+pub struct DefNames {
+    /* Function name to what a call of it has to pass */
+    funs: HashMap<String, Signature>,
+    /* Variant name to the enum it belongs to */
+    variants: HashMap<String, String>,
+    /* Enum name to its variants in declaration order */
+    enums: HashMap<String, Vec<Variant>>,
+    /* Inside a module its own functions and variants can be used without the module/ prefix */
+    aliases: HashMap<String, String>,
+}
+
+impl DefNames {
+    pub fn contains(&self, name: &str) -> bool {
+        self.funs.contains_key(name)
+    }
+
+    pub fn insert(&mut self, name: String, signature: Signature) -> bool {
+        self.funs.insert(name, signature).is_none()
+    }
+
+    /* The names without a function, so that a definition can take its place */
+    pub fn without(&self, name: &str) -> DefNames {
+        let mut names = self.clone();
+        names.funs.remove(name);
+        names
+    }
+
+    /* The constructor, the accessors and the predicate of the struct */
+    pub fn add_struct(&mut self, name: &str, fields: &[String]) {
+        self.funs.extend(struct_signatures(name, fields));
+    }
+
+    /* The variant constructors become callable functions */
+    pub fn add_enum(&mut self, name: &str, variants: &[Variant]) {
+        for variant in variants {
+            self.funs.insert(variant.name.clone(), Signature::fixed(variant.fields.len()));
+            self.variants.insert(variant.name.clone(), name.to_string());
+        }
+        self.enums.insert(name.to_string(), variants.to_vec());
+    }
+
+    /* Declares a function of the program, or of a module when the prefix is not empty */
+    fn declare(&mut self, prefix: &str, name: &str, signature: Signature) -> std::io::Result<()> {
+        if !self.insert(format!("{}{}", prefix, name), signature) {
+            return parse_err("Duplicate function name");
+        }
+        self.alias(prefix, name);
+        Ok(())
+    }
+
+    fn alias(&mut self, prefix: &str, name: &str) {
+        if !prefix.is_empty() {
+            self.aliases.insert(name.to_string(), format!("{}{}", prefix, name));
+        }
+    }
+
+    /* The function a call refers to, if it is a known one, and what it takes */
+    pub fn resolve(&self, name: &str) -> Option<(String, Signature)> {
+        let name = match self.aliases.get(name) {
+            Some(qualified) if !self.funs.contains_key(name) => qualified.as_str(),
+            _ => name,
+        };
+        self.funs.get(name).map(|signature| (name.to_string(), signature.clone()))
+    }
+
+    pub fn enum_variants(&self, name: &str) -> &[Variant] {
+        self.enums.get(name).map_or(&[], |variants| variants.as_slice())
+    }
+
+    /* Every name a call can use */
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.funs.keys().chain(self.aliases.keys())
+    }
+
+    /* What an import of a module makes visible: its own names, always with the prefix. What the module
+       imported itself is not passed on. */
+    fn exported(&self, prefix: &str) -> DefNames {
+        let own = |name: &String| name.starts_with(prefix);
+        DefNames {
+            funs: self.funs.iter().filter(|(k, _)| own(k)).map(|(k, v)| (k.clone(), v.clone())).collect(),
+            variants: self.variants.iter().filter(|(k, _)| own(k)).map(|(k, v)| (k.clone(), v.clone())).collect(),
+            enums: self.enums.iter().filter(|(k, _)| own(k)).map(|(k, v)| (k.clone(), v.clone())).collect(),
+            aliases: HashMap::new(),
+        }
+    }
+
+    /* The names inside a module with the prelude functions it doesn't define itself */
+    fn with_prelude(&self, prelude: &DefNames) -> DefNames {
+        let mut names = self.clone();
+        for (name, signature) in &prelude.funs {
+            if !self.aliases.contains_key(name) {
+                names.funs.entry(name.clone()).or_insert_with(|| signature.clone());
+            }
+        }
+        names
+    }
+
+    fn merge(&mut self, other: &DefNames) {
+        self.funs.extend(other.funs.iter().map(|(k, v)| (k.clone(), v.clone())));
+        self.variants.extend(other.variants.iter().map(|(k, v)| (k.clone(), v.clone())));
+        self.enums.extend(other.enums.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+
+    /* The enum a variant belongs to and the variant */
+    pub fn variant(&self, name: &str) -> Option<(&String, &Variant)> {
+        let name = self.aliases.get(name).map_or(name, |n| n.as_str());
+        let enum_name = self.variants.get(name)?;
+        let variant = self.enums[enum_name].iter().find(|v| v.name == name)?;
+        Some((enum_name, variant))
+    }
+}
+
+/* (name required* (optional literal)* &rest name?) */
+fn parse_fun_header(params: &[Sexp]) -> std::io::Result<(String, Vec<Binder>, Signature)> {
     if params.is_empty() {
         return parse_err("there is no function name");
     }
 
     let fname = match &params[0] {
-        Sexp::Atom(S(name)) => {
+        Sexp::Atom(S(name), _) => {
             if is_keyword(name) {
                 return parse_err(&format!(
                     "'{}' is a keyword, and it can't be a function name",
@@ -23,212 +146,1053 @@ fn parse_fun_header(params: &[Sexp]) -> std::io::Result<(String, Vec<String>)> {
     };
 
     let mut seen: HashSet<String> = HashSet::new();
-    let mut ps: Vec<String> = Vec::new();
-    for param in &params[1..] {
-        match param {
-            Sexp::Atom(S(name)) => {
-                if is_keyword(name) {
-                    return parse_err(&format!(
-                        "'{}' is a keyword, and it can't be the name of a parameter",
-                        name
-                    ));
+    let mut ps: Vec<Binder> = Vec::new();
+    let mut signature = Signature::default();
+    let mut index = 1;
+    while index < params.len() {
+        let (name, span) = match &params[index] {
+            Sexp::Atom(S(rest), _) if rest == "&rest" => match &params[index + 1..] {
+                [Sexp::Atom(S(name), span)] => {
+                    signature.rest = true;
+                    (name, span)
                 }
-                if seen.contains(name) {
-                    return parse_err("Duplicate parameter name");
+                _ => return parse_err("&rest should be followed by the name of the last parameter"),
+            },
+            Sexp::Atom(S(name), span) => {
+                if !signature.defaults.is_empty() {
+                    return parse_err(&format!("parameter {} without a default follows one with a default", name));
                 }
-                seen.insert(name.clone());
-                ps.push(name.clone());
+                signature.required += 1;
+                (name, span)
             }
+            Sexp::List(optional, _) => match &optional[..] {
+                [Sexp::Atom(S(name), span), default] => {
+                    signature.defaults.push(parse_default(default)?);
+                    (name, span)
+                }
+                _ => return parse_err("a parameter with a default should be (name literal)"),
+            },
             _ => return parse_err("parameter name should be a String"),
+        };
+        if is_keyword(name) {
+            return parse_err(&format!(
+                "'{}' is a keyword, and it can't be the name of a parameter",
+                name
+            ));
         }
+        if seen.contains(name) {
+            return parse_err("Duplicate parameter name");
+        }
+        seen.insert(name.clone());
+        ps.push(Binder { name: name.clone(), span: span.clone() });
+        index += if signature.rest { 2 } else { 1 };
     }
 
-    Ok((fname, ps))
+    Ok((fname, ps, signature))
 }
 
+/* Defaults are evaluated by the caller, so they are limited to literals that mean the same everywhere */
+fn parse_default(s: &Sexp) -> std::io::Result<Expr> {
+    match parse_expr(s) {
+        Ok(e @ (Expr::Number(_) | Expr::Float(_) | Expr::Boolean(_) | Expr::Str(_) | Expr::Char(_) | Expr::Nothing)) => Ok(e),
+        _ => parse_err("the default of a parameter should be a literal"),
+    }
+}
+
+/* Imports of a program given as a value are resolved relative to the working directory */
 pub fn parse_prog(s: &Sexp) -> std::io::Result<Program> {
+    parse_prog_in(s, Path::new("."), Options::default(), None)
+}
+
+/* Imports are resolved relative to the directory of the file */
+pub fn parse_prog_file(path: &Path, options: Options) -> std::io::Result<Program> {
+    let source = read_source(path)?;
+    let items = read_items(path, &source)?;
+    let name = path.display().to_string();
+    parse_prog_in(&Sexp::List(items, Span(0..source.len())), path.parent().unwrap_or(Path::new(".")), options, Some((&name, &source)))
+}
+
+/* The source, a file name and its text, is where the analysis points at wrong calls */
+fn parse_prog_in(s: &Sexp, dir: &Path, options: Options, source: Option<(&str, &str)>) -> std::io::Result<Program> {
     match s {
-        Sexp::List(items) => {
+        Sexp::List(items, _) => {
+            let items = Macros::default().expand_items(items)?;
             if items.is_empty() {
                 return parse_err("empty program");
             }
 
-            if items.len() == 1 {
-                return Ok(Program { defs: vec![], main: parse_expr(&items[0], HashSet::new())? });
-            }
-
             let (last, rest) = items.split_last().unwrap();
+            if let Some((name, _)) = global_define(last) {
+                return parse_err(&format!("a program ends with the expression it evaluates, not with the define of {}", name));
+            }
 
-            /* First pass: collect all function names to allow mutual and self recursion */
-            let mut def_names: HashSet<String> = HashSet::new();
-            for item in rest {
-                match item {
-                    Sexp::List(vec) => match &vec[..] {
-                        [Sexp::Atom(S(op)), Sexp::List(params), _body] if op == "fun" => {
-                            let (name, _ps) = parse_fun_header(params)?;
-                            if !def_names.insert(name) {
-                                return parse_err("Duplicate function name");
-                            }
+            /* The prelude is parsed together with the program, so its functions can be replaced by the program's own */
+            let mut def_items = Vec::new();
+            let mut modules = Modules::default();
+            if !options.no_prelude {
+                let defined: HashSet<String> = rest.iter().filter_map(fun_name).collect();
+                def_items.extend(prelude_items()?.into_iter().filter(|item| match fun_name(item) {
+                    Some(name) => !defined.contains(&name),
+                    None => true,
+                }));
+                for item in &def_items {
+                    if let Sexp::List(vec, _) = item {
+                        if let [_, Sexp::List(params, _), ..] = &vec[..] {
+                            let (name, _, signature) = parse_fun_header(params)?;
+                            modules.prelude.insert(name, signature);
                         }
-                        _ => return parse_err("one of the function definitions is wrong"),
-                    },
-                    _ => return parse_err("one of the function definitions is wrong"),
+                    }
+                }
+            }
+            let (defines, rest): (Vec<&Sexp>, Vec<&Sexp>) = rest.iter().partition(|item| global_define(item).is_some());
+            def_items.extend(rest.into_iter().cloned());
+
+            let (mut defs, def_names) = parse_defs(&def_items, "", dir, &mut modules)?;
+
+            let mut globals: Vec<(String, Expr)> = Vec::new();
+            for (name, e) in defines.into_iter().filter_map(global_define) {
+                if is_keyword(name) {
+                    return Err(std::io::Error::other(format!("'{}' is a keyword", name)));
                 }
+                if name == "input" || globals.iter().any(|(n, _)| n == name) {
+                    return parse_err(&format!("Duplicate global {}", name));
+                }
+                globals.push((name.clone(), parse_expr(e)?));
             }
 
-            /* Second pass: parse full function definitions with knowledge of all names */
-            let mut defs: Vec<Defenition> = Vec::new();
-            for item in rest {
-                match parse_fun_def(item, def_names.clone())? {
-                    Some(d) => defs.push(d),
-                    None => return parse_err("one of the function definitions is wrong"),
+            let mut main = parse_expr(last)?;
+            let mut warnings = std::mem::take(&mut modules.warnings);
+            warnings.extend(resolve_program(&mut defs, &mut globals, &mut main, &def_names, source)?);
+
+            let mut all_defs = modules.defs;
+            all_defs.extend(defs);
+            lift_program(Program { defs: all_defs, globals, main, declarations: vec![], warnings })
+        }
+        _ => {
+            let mut main = parse_expr(s)?;
+            let warnings = resolve_program(&mut [], &mut [], &mut main, &DefNames::default(), source)?;
+            lift_program(Program { defs: vec![], globals: vec![], main, declarations: vec![], warnings })
+        }
+    }
+}
+
+/* (define name expr) at the top level of a program declares a global */
+fn global_define(item: &Sexp) -> Option<(&String, &Sexp)> {
+    match item {
+        Sexp::List(vec, _) => match &vec[..] {
+            [Sexp::Atom(S(op), _), Sexp::Atom(S(name), _), e] if op == "define" => Some((name, e)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+pub fn fun_name(item: &Sexp) -> Option<String> {
+    match item {
+        Sexp::List(vec, _) => match &vec[..] {
+            [Sexp::Atom(S(op), _), Sexp::List(header, _), ..] if op == "fun" => match header.first() {
+                Some(Sexp::Atom(S(name), _)) => Some(name.clone()),
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/* The modules imported while parsing one program. Every file is parsed once, its definitions are
+   collected here and the names it exports are shared by all imports of it. */
+#[derive(Default)]
+struct Modules {
+    defs: Vec<Defenition>,
+    loaded: HashMap<PathBuf, DefNames>,
+    /* Module name to its file, two files can't share a namespace */
+    namespaces: HashMap<String, PathBuf>,
+    /* Files whose imports are being loaded, in order, to report cycles */
+    loading: Vec<PathBuf>,
+    /* The prelude functions the program keeps, modules can call them too */
+    prelude: DefNames,
+    warnings: Vec<String>,
+}
+
+fn read_source(path: &Path) -> std::io::Result<String> {
+    std::fs::read_to_string(path).map_err(|e| std::io::Error::other(format!("Invalid: can't read {}: {}", path.display(), e)))
+}
+
+fn read_items(path: &Path, source: &str) -> std::io::Result<Vec<Sexp>> {
+    Ok(read_forms(&path.display().to_string(), source)?.into_iter().map(|(form, _)| form).collect())
+}
+
+/* The forms of a source, a read error is shown in the source under the given name */
+pub fn read_forms(name: &str, source: &str) -> std::io::Result<Vec<(Sexp, Range<usize>)>> {
+    read(source).map_err(|d| std::io::Error::other(render(&d, Some((name, source)))))
+}
+
+/* Source text that is a single form */
+pub fn read_sexp(source: &str) -> std::io::Result<Sexp> {
+    let mut forms = read_forms("input", source)?;
+    match forms.len() {
+        1 => Ok(forms.remove(0).0),
+        _ => parse_err("expected a single form"),
+    }
+}
+
+/* (import "file.viva") loads the definitions of the file, they are named after the file: (math/gcd a b) */
+fn import_path(item: &Sexp) -> std::io::Result<Option<String>> {
+    match item {
+        Sexp::List(vec, _) => match &vec[..] {
+            [Sexp::Atom(S(op), _), rest @ ..] if op == "import" => match rest {
+                [Sexp::Atom(Str(file), _)] => Ok(Some(file.clone())),
+                _ => parse_err("import needs a file name string"),
+            },
+            _ => Ok(None),
+        },
+        _ => Ok(None),
+    }
+}
+
+fn load_module(path: &Path, modules: &mut Modules) -> std::io::Result<DefNames> {
+    let path = path.canonicalize().map_err(|e| std::io::Error::other(format!("Invalid: can't import {}: {}", path.display(), e)))?;
+    if let Some(position) = modules.loading.iter().position(|p| *p == path) {
+        let cycle: Vec<String> = modules.loading[position..].iter().chain(std::iter::once(&path))
+            .map(|p| p.file_name().unwrap_or_default().to_string_lossy().to_string())
+            .collect();
+        return parse_err(&format!("import cycle {}", cycle.join(" -> ")));
+    }
+    if let Some(names) = modules.loaded.get(&path) {
+        return Ok(names.clone());
+    }
+
+    let namespace = match path.file_stem().and_then(|stem| stem.to_str()) {
+        Some(stem) if !stem.is_empty() && !is_keyword(stem) => stem.to_string(),
+        _ => return parse_err(&format!("{} can't be used as a module name", path.display())),
+    };
+    if let Some(other) = modules.namespaces.insert(namespace.clone(), path.clone()) {
+        return parse_err(&format!("modules {} and {} are both named {}", other.display(), path.display(), namespace));
+    }
+
+    let source = read_source(&path)?;
+    let items = Macros::default().expand_items(&read_items(&path, &source)?)?;
+    modules.loading.push(path.clone());
+    let dir = path.parent().unwrap_or(Path::new(".")).to_path_buf();
+    let (mut defs, names) = parse_defs(&items, &format!("{}/", namespace), &dir, modules)?;
+    modules.loading.pop();
+    let visible = names.with_prelude(&modules.prelude);
+    let warnings = resolve_program(&mut defs, &mut [], &mut Expr::Nothing, &visible, Some((&path.display().to_string(), &source)))?;
+    modules.warnings.extend(warnings);
+
+    modules.defs.extend(defs);
+    let exported = names.exported(&format!("{}/", namespace));
+    modules.loaded.insert(path, exported.clone());
+    Ok(exported)
+}
+
+/* The definitions of a program or a module, the ones of a module are named prefix/name.
+   First pass: collect all function names, including the ones generated for structs and the ones of imported
+   modules, to allow mutual and self recursion. Imports come first so that a clash with a definition of the
+   file itself is reported as a duplicate. */
+fn parse_defs(items: &[Sexp], prefix: &str, dir: &Path, modules: &mut Modules) -> std::io::Result<(Vec<Defenition>, DefNames)> {
+    let mut def_names: DefNames = DefNames::default();
+    for item in items {
+        if let Some(file) = import_path(item)? {
+            def_names.merge(&load_module(&dir.join(file), modules)?);
+        }
+    }
+
+    for item in items {
+        match item {
+            Sexp::List(vec, _) => match &vec[..] {
+                [Sexp::Atom(S(op), _), ..] if op == "import" => {}
+                [Sexp::Atom(S(op), _), Sexp::List(params, _), _, ..] if op == "fun" => {
+                    let (name, _ps, signature) = parse_fun_header(params)?;
+                    def_names.declare(prefix, &name, signature)?;
+                }
+                [Sexp::Atom(S(op), _), name, Sexp::List(fields, _)] if op == "struct" => {
+                    let (name, fields) = parse_struct_header(name, fields)?;
+                    for (generated, signature) in struct_signatures(&name, &fields) {
+                        def_names.declare(prefix, &generated, signature)?;
+                    }
+                }
+                [Sexp::Atom(S(op), _), name, variants @ ..] if op == "enum" => {
+                    let (name, variants) = parse_enum_decl(name, variants, &def_names, prefix)?;
+                    def_names.add_enum(&name, &variants);
+                    for variant in &variants {
+                        def_names.alias(prefix, &variant.name[prefix.len()..]);
+                    }
+                }
+                _ => return parse_err("one of the function definitions is wrong"),
+            },
+            _ => return parse_err("one of the function definitions is wrong"),
+        }
+    }
+
+    /* Second pass: parse full function definitions with knowledge of all names */
+    let mut defs: Vec<Defenition> = Vec::new();
+    for item in items {
+        if import_path(item)?.is_some() {
+            continue;
+        }
+        match parse_fun_def(item, &def_names, prefix)? {
+            Some(d) => defs.push(d),
+            None => return parse_err("one of the function definitions is wrong"),
+        }
+    }
+
+    Ok((defs, def_names))
+}
+
+fn parse_struct_header(name: &Sexp, fields: &[Sexp]) -> std::io::Result<(String, Vec<String>)> {
+    let name = match name {
+        Sexp::Atom(S(name), _) if !is_keyword(name) => name.clone(),
+        _ => return parse_err("struct name is in the wrong format"),
+    };
+
+    let mut result: Vec<String> = Vec::new();
+    for field in fields {
+        match field {
+            Sexp::Atom(S(f), _) if !is_keyword(f) => {
+                if result.contains(f) {
+                    return parse_err("Duplicate field name");
                 }
+                result.push(f.clone());
             }
+            _ => return parse_err("field name should be a String"),
+        }
+    }
+
+    Ok((name, result))
+}
+
+/* (enum Name (Variant field*)+), every variant is laid out like a struct with its own id. The ids are
+   given out when the enum is lowered, until then they are 0. */
+fn parse_enum_decl(name: &Sexp, variants: &[Sexp], def_names: &DefNames, prefix: &str) -> std::io::Result<(String, Vec<Variant>)> {
+    let name = match name {
+        Sexp::Atom(S(name), _) if !is_keyword(name) => format!("{}{}", prefix, name),
+        _ => return parse_err("enum name is in the wrong format"),
+    };
+    if variants.is_empty() {
+        return parse_err("an enum needs at least one variant");
+    }
 
-            Ok(Program { defs, main: parse_expr(last, def_names)? })
+    let mut result: Vec<(String, Vec<String>)> = Vec::new();
+    for variant in variants {
+        match variant {
+            Sexp::List(items, _) if !items.is_empty() => {
+                let (variant_name, fields) = parse_struct_header(&items[0], &items[1..])?;
+                let variant_name = format!("{}{}", prefix, variant_name);
+                if result.iter().any(|(n, _)| *n == variant_name) {
+                    return parse_err("Duplicate variant name");
+                }
+                result.push((variant_name, fields));
+            }
+            _ => return parse_err("enum variants should be (name field*)"),
         }
-        _ => Ok(Program { defs: vec![], main: parse_expr(s, HashSet::new())? }),
     }
+
+    if def_names.enums.contains_key(&name) {
+        return parse_err("Duplicate enum name");
+    }
+    if result.iter().any(|(v, _)| def_names.contains(v)) {
+        return parse_err("Duplicate function name");
+    }
+    let variants = result.into_iter().map(|(v, fields)| Variant { name: v, id: 0, fields }).collect();
+    Ok((name, variants))
+}
+
+/* Every function a struct declaration generates: the constructor, the accessors and the predicate */
+pub fn struct_names(name: &str, fields: &[String]) -> Vec<String> {
+    let mut names = vec![name.to_string(), format!("{}?", name)];
+    names.extend(fields.iter().map(|f| format!("{}-{}", name, f)));
+    names
+}
+
+/* The constructor takes every field, the predicate and the accessors the struct */
+fn struct_signatures(name: &str, fields: &[String]) -> Vec<(String, Signature)> {
+    struct_names(name, fields).into_iter().enumerate()
+        .map(|(index, generated)| (generated, Signature::fixed(if index == 0 { fields.len() } else { 1 })))
+        .collect()
 }
 
-fn parse_fun_def(item: &Sexp, def_names: HashSet<String>) -> std::io::Result<Option<Defenition>> {
+/* Definitions of a module get the prefix of the module in front of their name */
+fn parse_fun_def(item: &Sexp, def_names: &DefNames, prefix: &str) -> std::io::Result<Option<Defenition>> {
     match item {
-        Sexp::List(vec) => match &vec[..] {
-            [Sexp::Atom(S(op)), Sexp::List(params), body] if op == "fun" => {
-                let (fname, ps) = parse_fun_header(params)?;
-                let body_expr = parse_expr(body, def_names.clone())?;
-                Ok(Some(Defenition::Fun(fname, ps, Box::new(body_expr))))
+        Sexp::List(vec, _) => match &vec[..] {
+            [Sexp::Atom(S(op), _), name, Sexp::List(fields, _)] if op == "struct" => {
+                let (name, fields) = parse_struct_header(name, fields)?;
+                Ok(Some(Defenition::Struct(format!("{}{}", prefix, name), fields)))
             }
+            [Sexp::Atom(S(op), _), Sexp::Atom(S(name), _), ..] if op == "enum" => match def_names.enums.get(&format!("{}{}", prefix, name)) {
+                Some(variants) => Ok(Some(Defenition::Enum(format!("{}{}", prefix, name), variants.clone()))),
+                None => Ok(None),
+            },
+            [Sexp::Atom(S(op), _), Sexp::List(params, _), body @ ..] if op == "fun" && !body.is_empty() => Ok(Some(parse_fun(params, body, prefix)?)),
             _ => Ok(None),
         },
         _ => Ok(None),
     }
 }
 
-pub fn parse_expr(s: &Sexp, def_names: HashSet<String>) -> std::io::Result<Expr> {
+fn parse_fun(params: &[Sexp], body: &[Sexp], prefix: &str) -> std::io::Result<Defenition> {
+    let (fname, ps, signature) = parse_fun_header(params)?;
+    let fname = format!("{}{}", prefix, fname);
+    let body_expr = parse_fun_body(&fname, &ps, body)?;
+    Ok(Defenition::Fun(fname, ps, signature, Box::new(body_expr)))
+}
+
+/* The body of a fun may be preceded by :requires and :ensures clauses. They become assertions before and
+   after the body that name the function and the clause, an ensures clause sees the return value as result,
+   so no parameter may be called result then. */
+fn parse_fun_body(fname: &str, params: &[Binder], items: &[Sexp]) -> std::io::Result<Expr> {
+    let (body, clauses) = match items.split_last() {
+        Some(split) => split,
+        None => return parse_err("function body is missing"),
+    };
+    if clauses.len() % 2 != 0 {
+        return parse_err("contract clauses should be :requires <expr> or :ensures <expr>");
+    }
+
+    let mut requires: Vec<Expr> = Vec::new();
+    let mut ensures: Vec<Expr> = Vec::new();
+    for clause in clauses.chunks(2) {
+        let (kind, condition) = (&clause[0], &clause[1]);
+        let check = Expr::Assert(Box::new(parse_expr(condition)?), format!("{} {} {}", fname, kind, condition));
+        match kind {
+            Sexp::Atom(S(k), _) if k == ":requires" => requires.push(check),
+            Sexp::Atom(S(k), _) if k == ":ensures" => ensures.push(check),
+            _ => return parse_err("contract clauses should be :requires <expr> or :ensures <expr>"),
+        }
+    }
+
+    let mut body_expr = parse_expr(body)?;
+    if !ensures.is_empty() {
+        if params.iter().any(|p| p.name == "result") {
+            return parse_err(&format!("the parameter result of {} hides the value its :ensures clauses check", fname));
+        }
+        ensures.push(Expr::Id("result".to_string()));
+        body_expr = Expr::Let(vec![(Binder::new("result"), body_expr)], Box::new(Expr::Block(ensures)));
+    }
+    if requires.is_empty() {
+        return Ok(body_expr);
+    }
+    requires.push(body_expr);
+    Ok(Expr::Block(requires))
+}
+
+/* Parsing only looks at the shape of the code: (name arg*) is a call whatever name is, what it refers to
+   is found out by the resolver */
+pub fn parse_expr(s: &Sexp) -> std::io::Result<Expr> {
     match s {
-        Sexp::Atom(I(n)) => Ok(Expr::Number(i64::try_from(*n).unwrap())),
-        Sexp::Atom(S(s)) => {
+        Sexp::Atom(I(n), _) => Ok(Expr::Number(*n)),
+        Sexp::Atom(F(f), _) => Ok(Expr::Float(f.to_bits())),
+        Sexp::Atom(Str(s), _) => Ok(Expr::Str(s.clone())),
+        Sexp::Atom(Char(c), _) => Ok(Expr::Char(*c)),
+        Sexp::Atom(S(s), _) => {
             match s.as_str() {
                 "true" => Ok(Expr::Boolean(true)),
                 "false" => Ok(Expr::Boolean(false)),
                 _ => {
                     if is_keyword(s) {
-                        return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("'{}' is a keyword", s)));
+                        return Err(std::io::Error::other(format!("'{}' is a keyword", s)));
                     }
                     Ok(Expr::Id(s.clone()))
                 }
             }
         }
-        Sexp::List(vec) => {
+        Sexp::List(vec, _) => {
             match &vec[..] {
-                [Sexp::Atom(S(op)), Sexp::List(bindings), body] if op == "let" => {
+                [Sexp::Atom(S(op), _), Sexp::List(bindings, _), body] if op == "let" => {
                     let mut bs = Vec::new();
                     for b in bindings {
                         match b {
-                            Sexp::List(pair) => {
+                            Sexp::List(pair, _) => {
                                 match &pair[..] {
-                                    [Sexp::Atom(S(name)), e] => {
+                                    [Sexp::Atom(S(name), span), e] => {
                                         if is_keyword(name) {
-                                            return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("'{}' is a keyword", name)));
+                                            return Err(std::io::Error::other(format!("'{}' is a keyword", name)));
                                         }
-                                        let parsed = parse_expr(e, def_names.clone())?;
-                                        let pair = (name.clone(), parsed);
+                                        let parsed = parse_expr(e)?;
+                                        let pair = (Binder { name: name.clone(), span: span.clone() }, parsed);
                                         bs.push(pair);
                                     }
-                                    _ => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Invalid: parse error")),
+                                    _ => return Err(std::io::Error::other("Invalid: parse error")),
                                 }
                             }
-                            _ => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Invalid: parse error")),
+                            _ => return Err(std::io::Error::other("Invalid: parse error")),
+                        }
+                    }
+                    Ok(Expr::Let(bs, Box::new(parse_expr(body)?)))
+                }
+                /* (let* ...) binds in sequence and allows rebinding, so it becomes one let per binding */
+                [Sexp::Atom(S(op), _), Sexp::List(bindings, _), body] if op == "let*" => {
+                    let mut bs = Vec::new();
+                    for b in bindings {
+                        match b {
+                            Sexp::List(pair, _) => match &pair[..] {
+                                [Sexp::Atom(S(name), span), e] => {
+                                    if is_keyword(name) {
+                                        return Err(std::io::Error::other(format!("'{}' is a keyword", name)));
+                                    }
+                                    bs.push((Binder { name: name.clone(), span: span.clone() }, parse_expr(e)?));
+                                }
+                                _ => return Err(std::io::Error::other("Invalid: parse error")),
+                            },
+                            _ => return Err(std::io::Error::other("Invalid: parse error")),
+                        }
+                    }
+                    let mut result = parse_expr(body)?;
+                    for binding in bs.into_iter().rev() {
+                        result = Expr::Let(vec![binding], Box::new(result));
+                    }
+                    Ok(result)
+                }
+
+                /* (letrec ((fun (f x) ...) (fun (g y) ...)) body) defines local, possibly mutually recursive, functions */
+                [Sexp::Atom(S(op), _), Sexp::List(funs, _), body] if op == "letrec" => {
+                    let mut defs = Vec::new();
+                    let mut seen: HashSet<String> = HashSet::new();
+                    for f in funs {
+                        match f {
+                            Sexp::List(vec, _) => match &vec[..] {
+                                [Sexp::Atom(S(kw), _), Sexp::List(params, _), body @ ..] if kw == "fun" && !body.is_empty() => {
+                                    let def = parse_fun(params, body, "")?;
+                                    if !seen.insert(def.as_fun().unwrap().0.clone()) {
+                                        return parse_err("Duplicate function name");
+                                    }
+                                    defs.push(def);
+                                }
+                                _ => return parse_err("letrec bindings should be function definitions"),
+                            },
+                            _ => return parse_err("letrec bindings should be function definitions"),
+                        }
+                    }
+                    Ok(Expr::LetRec(defs, Box::new(parse_expr(body)?)))
+                }
+
+                [Sexp::Atom(S(op), _), e1, e2, e3] if op == "if" => Ok(Expr::If(Box::new(parse_expr(e1)?), Box::new(parse_expr(e2)?), Box::new(parse_expr(e3)?))),
+
+                [Sexp::Atom(S(op), _), clauses @ ..] if op == "cond" => {
+                    let mut arms = Vec::new();
+                    let mut default = None;
+                    for (index, clause) in clauses.iter().enumerate() {
+                        match clause {
+                            Sexp::List(parts, _) => match &parts[..] {
+                                [Sexp::Atom(S(kw), _), body @ ..] if kw == "else" && !body.is_empty() => {
+                                    if index != clauses.len() - 1 {
+                                        return parse_err("else has to be the last clause of cond");
+                                    }
+                                    default = Some(Box::new(parse_body(body)?));
+                                }
+                                [test, body @ ..] if !body.is_empty() => {
+                                    arms.push((parse_expr(test)?, parse_body(body)?));
+                                }
+                                _ => return parse_err("cond clause should have a test and a body"),
+                            },
+                            _ => return parse_err("cond clause should be a list"),
+                        }
+                    }
+                    Ok(Expr::Cond(arms, default))
+                }
+
+                [Sexp::Atom(S(op), _), cond, body @ ..] if (op == "when" || op == "unless") && !body.is_empty() => {
+                    let cond_expr = Box::new(parse_expr(cond)?);
+                    let body_expr = Box::new(parse_body(body)?);
+                    if op == "when" { Ok(Expr::When(cond_expr, body_expr)) } else { Ok(Expr::Unless(cond_expr, body_expr)) }
+                }
+
+                [Sexp::Atom(S(op), _), e, clauses @ ..] if op == "case" => {
+                    let mut arms = Vec::new();
+                    let mut default = None;
+                    for (index, clause) in clauses.iter().enumerate() {
+                        match clause {
+                            Sexp::List(parts, _) => match &parts[..] {
+                                [Sexp::Atom(S(kw), _), body @ ..] if kw == "else" && !body.is_empty() => {
+                                    if index != clauses.len() - 1 {
+                                        return parse_err("else has to be the last clause of case");
+                                    }
+                                    default = Some(Box::new(parse_body(body)?));
+                                }
+                                [Sexp::List(keys, _), body @ ..] if !body.is_empty() => {
+                                    let mut literals = Vec::new();
+                                    for key in keys {
+                                        literals.push(parse_case_literal(key)?);
+                                    }
+                                    arms.push((literals, parse_body(body)?));
+                                }
+                                [key, body @ ..] if !body.is_empty() => {
+                                    arms.push((vec![parse_case_literal(key)?], parse_body(body)?));
+                                }
+                                _ => return parse_err("case clause should have keys and a body"),
+                            },
+                            _ => return parse_err("case clause should be a list"),
+                        }
+                    }
+                    Ok(Expr::Case(Box::new(parse_expr(e)?), arms, default))
+                }
+
+                [Sexp::Atom(S(op), _), e] if op == "loop" => Ok(Expr::Loop(None, Box::new(parse_expr(e)?))),
+                [Sexp::Atom(S(op), _), l, e] if op == "loop" && loop_label(l).is_some() => Ok(Expr::Loop(loop_label(l), Box::new(parse_expr(e)?))),
+
+                [Sexp::Atom(S(op), _), e] if op == "break" => Ok(Expr::Break(None, Box::new(parse_expr(e)?))),
+                [Sexp::Atom(S(op), _), l, e] if op == "break" && loop_label(l).is_some() => Ok(Expr::Break(loop_label(l), Box::new(parse_expr(e)?))),
+
+                [Sexp::Atom(S(op), _)] if op == "continue" => Ok(Expr::Continue(None)),
+                [Sexp::Atom(S(op), _), l] if op == "continue" && loop_label(l).is_some() => Ok(Expr::Continue(loop_label(l))),
+
+                /* (while cond body) loops until cond is false and evaluates to nothing */
+                [Sexp::Atom(S(op), _), rest @ ..] if op == "while" => {
+                    let (label, rest) = match rest.split_first() {
+                        Some((l, tail)) if loop_label(l).is_some() => (loop_label(l), tail),
+                        _ => (None, rest),
+                    };
+                    match rest {
+                        [cond, body @ ..] if !body.is_empty() => {
+                            let cond_expr = parse_expr(cond)?;
+                            let exit = Expr::Unless(Box::new(cond_expr), Box::new(Expr::Break(None, Box::new(Expr::Nothing))));
+                            Ok(Expr::Loop(label, Box::new(Expr::Block(vec![exit, parse_body(body)?]))))
                         }
+                        _ => parse_err("while should have a condition and a body"),
                     }
-                    Ok(Expr::Let(bs, Box::new(parse_expr(body, def_names.clone())?)))
                 }
-                [Sexp::Atom(S(op)), e1, e2, e3] if op == "if" => Ok(Expr::If(Box::new(parse_expr(e1, def_names.clone())?), Box::new(parse_expr(e2, def_names.clone())?), Box::new(parse_expr(e3, def_names.clone())?))),
 
-                [Sexp::Atom(S(op)), e] if op == "loop" => Ok(Expr::Loop(Box::new(parse_expr(e, def_names.clone())?))),
+                /* (for (i start end) body) runs body with i from start up to, but not including, end.
+                   The next index lives in a hidden variable so that continue and set! on i cannot skip the step. */
+                [Sexp::Atom(S(op), _), rest @ ..] if op == "for" => {
+                    let (label, rest) = match rest.split_first() {
+                        Some((l, tail)) if loop_label(l).is_some() => (loop_label(l), tail),
+                        _ => (None, rest),
+                    };
+                    match rest {
+                        [Sexp::List(range, _), body @ ..] if !body.is_empty() => match &range[..] {
+                            [Sexp::Atom(S(var), var_span), start, end] => {
+                                if is_keyword(var) {
+                                    return Err(std::io::Error::other(format!("'{}' is a keyword", var)));
+                                }
+                                let id = next_id();
+                                let next_var = format!("#for_next{}", id);
+                                let end_var = format!("#for_end{}", id);
 
-                [Sexp::Atom(S(op)), e] if op == "break" => Ok(Expr::Break(Box::new(parse_expr(e, def_names.clone())?))),
+                                let exit = Expr::When(
+                                    Box::new(Expr::BinOp(Op2::GreaterEqual, Box::new(Expr::Id(next_var.clone())), Box::new(Expr::Id(end_var.clone())))),
+                                    Box::new(Expr::Break(None, Box::new(Expr::Nothing))),
+                                );
+                                let step = Expr::Block(vec![
+                                    Expr::Set(var.clone(), Box::new(Expr::Id(next_var.clone()))),
+                                    Expr::Set(next_var.clone(), Box::new(Expr::UnOp(Op1::Add1, Box::new(Expr::Id(next_var.clone()))))),
+                                ]);
+                                let bindings = vec![
+                                    (Binder::new(&next_var), parse_expr(start)?),
+                                    (Binder::new(&end_var), parse_expr(end)?),
+                                    (Binder { name: var.clone(), span: var_span.clone() }, Expr::Number(0)),
+                                ];
+                                let body_expr = Expr::Block(vec![exit, step, parse_body(body)?]);
+                                Ok(Expr::Let(bindings, Box::new(Expr::Loop(label, Box::new(body_expr)))))
+                            }
+                            _ => parse_err("for should have a (variable start end) range"),
+                        },
+                        _ => parse_err("for should have a range and a body"),
+                    }
+                }
 
                 
-                [Sexp::Atom(S(op)), Sexp::Atom(S(s)), e] if op == "set!" => {
+                [Sexp::Atom(S(op), _), Sexp::Atom(S(s), _), e] if op == "set!" => {
                     if is_keyword(s) {
-                        return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("'{}' is a keyword", s)));
+                        return Err(std::io::Error::other(format!("'{}' is a keyword", s)));
                     }
-                    Ok(Expr::Set(s.clone(), Box::new(parse_expr(e, def_names.clone())?)))
+                    Ok(Expr::Set(s.clone(), Box::new(parse_expr(e)?)))
+                },
+                [Sexp::Atom(S(op), _), e, Sexp::Atom(S(field), _), v] if op == "set-field!" => {
+                    Ok(Expr::SetField(Box::new(parse_expr(e)?), field.clone(), Box::new(parse_expr(v)?)))
                 },
                 
-                [Sexp::Atom(S(op)), rest @ ..] if op == "block" => {
+                [Sexp::Atom(S(op), _), rest @ ..] if op == "and" || op == "or" => {
+                    let mut es = Vec::new();
+                    for e in rest {
+                        es.push(parse_expr(e)?);
+                    }
+                    if op == "and" { Ok(Expr::And(es)) } else { Ok(Expr::Or(es)) }
+                }
+
+                /* An empty block is nothing, the value while and for evaluate to */
+                [Sexp::Atom(S(op), _)] if op == "block" => Ok(Expr::Nothing),
+                [Sexp::Atom(S(op), _), rest @ ..] if op == "block" => {
                     let mut bs = Vec::new();
                     for b in rest {
-                        let parsed = parse_expr(b, def_names.clone())?;
+                        let parsed = parse_expr(b)?;
                         bs.push(parsed);
                     }
                     Ok(Expr::Block(bs))
                 }
 
-                [Sexp::Atom(S(name)), args @ ..] if !is_keyword(name) && def_names.contains(name) => {
+                [Sexp::Atom(S(op), _), e, arms @ ..] if op == "match" && !arms.is_empty() => {
+                    let parsed_arms = parse_match_arms(arms)?;
+                    Ok(Expr::Match(Box::new(parse_expr(e)?), parsed_arms, s.span().clone()))
+                }
+                [Sexp::Atom(S(op), _), cond, message] if op == "assert" => match parse_expr(message)? {
+                    Expr::Str(message) => Ok(Expr::Assert(Box::new(parse_expr(cond)?), message)),
+                    _ => parse_err("the message of an assert should be a string literal"),
+                },
+                /* (try body (catch x handler)) runs the handler with x bound to the raised value */
+                [Sexp::Atom(S(op), _), body, Sexp::List(catch, _)] if op == "try" => match &catch[..] {
+                    [Sexp::Atom(S(kw), _), Sexp::Atom(S(name), span), handler @ ..] if kw == "catch" && !handler.is_empty() => {
+                        if is_keyword(name) {
+                            return parse_err(&format!("'{}' is a keyword, and it can't be a variable name", name));
+                        }
+                        let binder = Binder { name: name.clone(), span: span.clone() };
+                        Ok(Expr::Try(Box::new(parse_expr(body)?), binder, Box::new(parse_body(handler)?)))
+                    }
+                    _ => parse_err("try needs a (catch <name> <expr>+) clause"),
+                },
+                [Sexp::Atom(S(name), _), args @ ..] if !is_keyword(name) => {
                     let mut parsed_args = Vec::new();
                     for a in args {
-                        parsed_args.push(parse_expr(a, def_names.clone())?);
+                        parsed_args.push(parse_expr(a)?);
                     }
-                    Ok(Expr::Call(name.clone(), parsed_args))
+                    Ok(Expr::Call(name.clone(), parsed_args, s.span().clone()))
                 },
 
-                [Sexp::Atom(S(op)), e] if op == "add1" => Ok(Expr::UnOp(Op1::Add1, Box::new(parse_expr(e, def_names.clone())?))),
-                [Sexp::Atom(S(op)), e] if op == "sub1" => Ok(Expr::UnOp(Op1::Sub1, Box::new(parse_expr(e, def_names.clone())?))),
-                [Sexp::Atom(S(op)), e] if op == "isnum" => Ok(Expr::UnOp(Op1::IsNum, Box::new(parse_expr(e, def_names.clone())?))),
-                [Sexp::Atom(S(op)), e] if op == "isbool" => Ok(Expr::UnOp(Op1::IsBool, Box::new(parse_expr(e, def_names.clone())?))),
-                [Sexp::Atom(S(op)), e] if op == "print" => Ok(Expr::UnOp(Op1::Print, Box::new(parse_expr(e, def_names.clone())?))),
+                _ => Err(std::io::Error::other("Invalid: parse error")),
+            }
+        },
+    }
+}
+
+/* Clause bodies with several expressions are evaluated in sequence like a block */
+fn parse_body(body: &[Sexp]) -> std::io::Result<Expr> {
+    let mut es = Vec::new();
+    for b in body {
+        es.push(parse_expr(b)?);
+    }
+    if es.len() == 1 {
+        Ok(es.remove(0))
+    } else {
+        Ok(Expr::Block(es))
+    }
+}
 
-                [Sexp::Atom(S(op)), e1, e2] if op == "+" => Ok(Expr::BinOp(Op2::Plus, Box::new(parse_expr(e1, def_names.clone())?), Box::new(parse_expr(e2, def_names.clone())?))),
-                [Sexp::Atom(S(op)), e1, e2] if op == "-" => Ok(Expr::BinOp(Op2::Minus, Box::new(parse_expr(e1, def_names.clone())?), Box::new(parse_expr(e2, def_names.clone())?))),
-                [Sexp::Atom(S(op)), e1, e2] if op == "*" => Ok(Expr::BinOp(Op2::Times, Box::new(parse_expr(e1, def_names.clone())?), Box::new(parse_expr(e2, def_names.clone())?))),
-                [Sexp::Atom(S(op)), e1, e2] if op == "=" => Ok(Expr::BinOp(Op2::Equal, Box::new(parse_expr(e1, def_names.clone())?), Box::new(parse_expr(e2, def_names.clone())?))),
-                [Sexp::Atom(S(op)), e1, e2] if op == ">" => Ok(Expr::BinOp(Op2::Greater, Box::new(parse_expr(e1, def_names.clone())?), Box::new(parse_expr(e2, def_names.clone())?))),
-                [Sexp::Atom(S(op)), e1, e2] if op == ">=" => Ok(Expr::BinOp(Op2::GreaterEqual, Box::new(parse_expr(e1, def_names.clone())?), Box::new(parse_expr(e2, def_names.clone())?))),
-                [Sexp::Atom(S(op)), e1, e2] if op == "<" => Ok(Expr::BinOp(Op2::Less, Box::new(parse_expr(e1, def_names.clone())?), Box::new(parse_expr(e2, def_names.clone())?))),
-                [Sexp::Atom(S(op)), e1, e2] if op == "<=" => Ok(Expr::BinOp(Op2::LessEqual, Box::new(parse_expr(e1, def_names.clone())?), Box::new(parse_expr(e2, def_names.clone())?))),
+/* Which enum the variants belong to, and whether the arms cover all of it, is checked by the resolver */
+fn parse_match_arms(arms: &[Sexp]) -> std::io::Result<Vec<(Pattern, Expr)>> {
+    let mut result: Vec<(Pattern, Expr)> = Vec::new();
 
+    for arm in arms {
+        if result.iter().any(|(p, _)| *p == Pattern::Wildcard) {
+            return parse_err("the wildcard pattern has to be the last arm of a match");
+        }
+        let (pattern, body) = match arm {
+            Sexp::List(items, _) if items.len() >= 2 => (&items[0], &items[1..]),
+            _ => return parse_err("match arms should be (pattern body)"),
+        };
 
-                _ => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Invalid: parse error")),
+        let pattern = match pattern {
+            Sexp::Atom(S(w), _) if w == "_" => Pattern::Wildcard,
+            Sexp::List(items, span) => match &items[..] {
+                [Sexp::Atom(S(name), _), bindings @ ..] => {
+                    let mut names: Vec<Binder> = Vec::new();
+                    for binding in bindings {
+                        match binding {
+                            Sexp::Atom(S(b), span) if !is_keyword(b) => {
+                                if b != "_" && names.iter().any(|n| n.name == *b) {
+                                    return parse_err("Duplicate binding in pattern");
+                                }
+                                names.push(Binder { name: b.clone(), span: span.clone() });
+                            }
+                            _ => return parse_err("pattern bindings should be names"),
+                        }
+                    }
+                    Pattern::Variant(name.clone(), 0, names, span.clone())
+                }
+                _ => return parse_err("patterns should be (variant name*) or _"),
+            },
+            _ => return parse_err("patterns should be (variant name*) or _"),
+        };
+        result.push((pattern, parse_body(body)?));
+    }
+
+    Ok(result)
+}
+
+/* Reading source text. Every token keeps the byte range it was read from, so that a read error points at
+   its place in the source, and comments are tokens too, for the formatter. Names are case-sensitive. */
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Atom {
+    /* A name */
+    S(String),
+    I(i64),
+    F(f64),
+    Str(String),
+    Char(char),
+}
+
+/* Every form has the span it was read from, forms a macro wrote have the span of the use of the macro */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sexp {
+    Atom(Atom, Span),
+    List(Vec<Sexp>, Span),
+}
+
+impl Sexp {
+    pub fn span(&self) -> &Span {
+        match self {
+            Sexp::Atom(_, span) | Sexp::List(_, span) => span,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    Open,
+    Close,
+    Atom(Atom),
+    /* The text of the comment from the ; on, without the line break */
+    Comment(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Range<usize>,
+}
+
+/* Integers are tagged with one bit, a literal has to fit into the remaining 63 */
+const SMALLEST_NUMBER: i64 = -(1 << 62);
+const LARGEST_NUMBER: i64 = (1 << 62) - 1;
+
+fn read_err<T>(message: String, label: &str, span: Range<usize>) -> Result<T, Diagnostic> {
+    Err(Diagnostic::error(message, label, Span(span)))
+}
+
+/* Where the atom that goes on at from ends. A " starts a string even right after an atom. */
+fn atom_end(source: &str, from: usize) -> usize {
+    source[from..]
+        .find(|c: char| c.is_whitespace() || matches!(c, '(' | ')' | ';' | '"'))
+        .map_or(source.len(), |at| from + at)
+}
+
+pub fn lex(source: &str) -> Result<Vec<Token>, Diagnostic> {
+    tokens(source, false)
+}
+
+/* Like lex, but an atom that is not a valid literal, like an integer out of range, is read as a name. The
+   formatter keeps the text of every atom and leaves those errors to the compiler. */
+pub fn lex_lenient(source: &str) -> Result<Vec<Token>, Diagnostic> {
+    tokens(source, true)
+}
+
+fn tokens(source: &str, lenient: bool) -> Result<Vec<Token>, Diagnostic> {
+    let lenient_atom = |atom: Result<Atom, Diagnostic>, text: &str| match atom {
+        Err(_) if lenient => Ok(S(text.to_string())),
+        atom => atom,
+    };
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while let Some(c) = source[pos..].chars().next() {
+        let start = pos;
+        pos += c.len_utf8();
+        let kind = match c {
+            _ if c.is_whitespace() => continue,
+            '(' => TokenKind::Open,
+            ')' => TokenKind::Close,
+            ';' => {
+                pos = source[start..].find('\n').map_or(source.len(), |at| start + at);
+                TokenKind::Comment(source[start..pos].trim_end().to_string())
             }
-        },
-        _ => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Invalid: parse error")),
+            '"' => {
+                let (text, end) = lex_string(source, start)?;
+                pos = end;
+                TokenKind::Atom(Str(text))
+            }
+            /* The character right after #\ belongs to the literal, even a ( or a ; */
+            '#' if source[pos..].starts_with('\\') => {
+                let first = match source[pos + 1..].chars().next() {
+                    Some(first) => first,
+                    None => return read_err("unfinished character literal".to_string(), "a character has to follow", start..source.len()),
+                };
+                pos = atom_end(source, pos + 1 + first.len_utf8());
+                TokenKind::Atom(lenient_atom(lex_char(&source[start + 2..pos], start..pos).map(Char), &source[start..pos])?)
+            }
+            _ => {
+                pos = atom_end(source, pos);
+                TokenKind::Atom(lenient_atom(lex_atom(&source[start..pos], start..pos), &source[start..pos])?)
+            }
+        };
+        tokens.push(Token { kind, span: start..pos });
     }
+    Ok(tokens)
 }
 
-pub fn parse_repl_expr(s: &Sexp, def_names: &HashSet<String>) -> std::io::Result<ReplExpr> {
+/* The contents of the string literal that starts at start, and where it ends */
+fn lex_string(source: &str, start: usize) -> Result<(String, usize), Diagnostic> {
+    let mut text = String::new();
+    let mut chars = source[start + 1..].char_indices().map(|(at, c)| (start + 1 + at, c));
+    while let Some((at, c)) = chars.next() {
+        match c {
+            '"' => return Ok((text, at + 1)),
+            '\\' => match chars.next() {
+                Some((_, 'n')) => text.push('\n'),
+                Some((_, 't')) => text.push('\t'),
+                Some((_, escaped @ ('"' | '\\'))) => text.push(escaped),
+                Some((end, other)) => {
+                    return read_err(format!("unknown escape \\{} in string literal", other), "escapes are \\n \\t \\\" and \\\\", at..end + other.len_utf8())
+                }
+                None => break,
+            },
+            _ => text.push(c),
+        }
+    }
+    read_err("unterminated string literal".to_string(), "the string starts here", start..start + 1)
+}
+
+fn lex_char(name: &str, span: Range<usize>) -> Result<char, Diagnostic> {
+    match name {
+        "space" => Ok(' '),
+        "newline" => Ok('\n'),
+        "tab" => Ok('\t'),
+        _ => {
+            let mut chars = name.chars();
+            match (chars.next(), chars.next()) {
+                (Some(single), None) => Ok(single),
+                _ => read_err(format!("unknown character literal #\\{}", name), "not a character", span),
+            }
+        }
+    }
+}
+
+/* <digits>.<digits> or <digits> with an exponent like e-7, or both */
+fn is_float_literal(unsigned: &str) -> bool {
+    let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    let (mantissa, exponent) = match unsigned.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, Some(exponent.strip_prefix(['+', '-']).unwrap_or(exponent))),
+        None => (unsigned, None),
+    };
+    let mantissa_ok = match mantissa.split_once('.') {
+        Some((whole, fraction)) => digits(whole) && digits(fraction),
+        None => digits(mantissa) && exponent.is_some(),
+    };
+    mantissa_ok && exponent.map_or(true, digits)
+}
+
+/* Digits with an optional sign are an integer, a float is written like 2.5 or 1e-7. Other text that starts
+   like a number is an error, anything else is a name, so inf and nan are names. */
+fn lex_atom(text: &str, span: Range<usize>) -> Result<Atom, Diagnostic> {
+    let unsigned = text.strip_prefix(['+', '-']).unwrap_or(text);
+    if !unsigned.is_empty() && unsigned.bytes().all(|b| b.is_ascii_digit()) {
+        return match text.parse::<i64>() {
+            Ok(n) if (SMALLEST_NUMBER..=LARGEST_NUMBER).contains(&n) => Ok(I(n)),
+            _ => read_err(
+                format!("integer literal {} is out of range", text),
+                &format!("numbers are from {} to {}", SMALLEST_NUMBER, LARGEST_NUMBER),
+                span,
+            ),
+        };
+    }
+    if is_float_literal(unsigned) {
+        return match text.parse::<f64>() {
+            Ok(f) if f.is_finite() => Ok(F(f)),
+            _ => read_err(format!("float literal {} is out of range", text), "too large for a double", span),
+        };
+    }
+    let numeric = unsigned.starts_with(|c: char| c.is_ascii_digit())
+        || unsigned.strip_prefix('.').is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_digit()));
+    if numeric {
+        return read_err(format!("malformed number {}", text), "numbers are written like 12, 2.5 or 1e-7", span);
+    }
+    Ok(S(text.to_string()))
+}
+
+/* The top-level forms of the source with the byte range each one was read from */
+pub fn read(source: &str) -> Result<Vec<(Sexp, Range<usize>)>, Diagnostic> {
+    let mut forms = Vec::new();
+    /* The open lists, where each starts and what was read of it */
+    let mut open: Vec<(usize, Vec<Sexp>)> = Vec::new();
+    for token in lex(source)? {
+        let mut span = token.span.clone();
+        let sexp = match token.kind {
+            TokenKind::Open => {
+                open.push((span.start, Vec::new()));
+                continue;
+            }
+            TokenKind::Close => match open.pop() {
+                Some((start, items)) => {
+                    span = start..span.end;
+                    Sexp::List(items, Span(span.clone()))
+                }
+                None => return read_err("unexpected )".to_string(), "there is no list to close", span),
+            },
+            TokenKind::Atom(atom) => Sexp::Atom(atom, Span(span.clone())),
+            TokenKind::Comment(_) => continue,
+        };
+        match open.last_mut() {
+            Some((_, items)) => items.push(sexp),
+            None => forms.push((sexp, span)),
+        }
+    }
+    match open.last() {
+        Some((start, _)) => read_err("missing )".to_string(), "this list is not closed", *start..*start + 1),
+        None => Ok(forms),
+    }
+}
+fn loop_label(s: &Sexp) -> Option<String> {
     match s {
-        Sexp::List(vec) => {
+        Sexp::Atom(S(l), _) if l.len() > 1 && l.starts_with(':') => Some(l[1..].to_string()),
+        _ => None,
+    }
+}
+
+fn parse_case_literal(s: &Sexp) -> std::io::Result<Expr> {
+    match s {
+        Sexp::Atom(I(n), _) => Ok(Expr::Number(*n)),
+        Sexp::Atom(S(b), _) if b == "true" => Ok(Expr::Boolean(true)),
+        Sexp::Atom(S(b), _) if b == "false" => Ok(Expr::Boolean(false)),
+        _ => parse_err("case keys should be number or boolean literals"),
+    }
+}
+
+pub fn parse_repl_expr(s: &Sexp, def_names: &DefNames) -> std::io::Result<ReplExpr> {
+    match s {
+        Sexp::List(vec, _) => {
             match &vec[..] {
-                [Sexp::Atom(S(op)), Sexp::Atom(S(v)), e] if op == "define" => Ok(ReplExpr::Define(v.clone(), Box::new(parse_expr(e, HashSet::new())?))),
-                [Sexp::Atom(S(op)), Sexp::List(params), body] if op == "fun" => {
-                    let (fname, ps) = parse_fun_header(params)?;
+                [Sexp::Atom(S(op), _), ..] if op == "define-syntax" => match parse_macro(s)? {
+                    Some((name, m)) => Ok(ReplExpr::Syntax(name, m)),
+                    None => parse_err("wrong macro definition"),
+                },
+                [Sexp::Atom(S(op), _), Sexp::Atom(S(v), _), e] if op == "define" => Ok(ReplExpr::Define(v.clone(), Box::new(parse_expr(e)?))),
+                [Sexp::Atom(S(op), _), name, Sexp::List(fields, _)] if op == "struct" => {
+                    let (name, fields) = parse_struct_header(name, fields)?;
+                    if struct_names(&name, &fields).iter().any(|n| def_names.contains(n)) {
+                        return parse_err("Duplicate function name");
+                    }
+                    Ok(ReplExpr::Struct(name, fields))
+                }
+                [Sexp::Atom(S(op), _), name, variants @ ..] if op == "enum" => {
+                    let (name, variants) = parse_enum_decl(name, variants, def_names, "")?;
+                    Ok(ReplExpr::Enum(name, variants))
+                }
+                [Sexp::Atom(S(op), _), Sexp::List(params, _), body @ ..] if op == "fun" && !body.is_empty() => {
+                    let (fname, ps, signature) = parse_fun_header(params)?;
                     if def_names.contains(&fname) {
                         return parse_err("Duplicate function name");
-                    } 
-                    let body_expr = parse_expr(body, def_names.clone())?;
-                    Ok(ReplExpr::Fun(fname, ps, Box::new(body_expr)))
+                    }
+                    let body_expr = parse_fun_body(&fname, &ps, body)?;
+                    Ok(ReplExpr::Fun(fname, ps, signature, Box::new(body_expr)))
                 }
-                _ => Ok(ReplExpr::Expr(Box::new(parse_expr(s, def_names.clone())?))),
+                _ => Ok(ReplExpr::Expr(Box::new(parse_expr(s)?))),
             }
         }
-        _ => Ok(ReplExpr::Expr(Box::new(parse_expr(s, def_names.clone())?))),
+        _ => Ok(ReplExpr::Expr(Box::new(parse_expr(s)?))),
     }
 }
 
-fn is_keyword(s: &str) -> bool {
+/* The special forms, their names can't be used for anything else. The builtin operations like print or +
+   are ordinary names that a program can define again, see resolve. */
+pub fn is_keyword(s: &str) -> bool {
     matches!(s,
-        "let" | "if" | "loop" | "break" | "set!" | "block" |
-        "add1" | "sub1" | "isnum" | "isbool" | "print" | "define" | "fun" |
-        "+" | "-" | "*" | "=" | ">" | ">=" | "<" | "<=" | "true" | "false"
+        "let" | "let*" | "letrec" | "if" | "cond" | "when" | "unless" | "case" | "else" |
+        "loop" | "break" | "continue" | "while" | "for" | "set!" | "block" | "and" | "or" |
+        "struct" | "set-field!" | "enum" | "match" | "try" | "catch" | "assert" | "import" |
+        "define" | "fun" | "define-syntax" | "syntax-rules" | "true" | "false"
     )
 }
 
-fn parse_err<T>(name: &str) -> std::io::Result<T> {
-    Err(std::io::Error::new(
-        std::io::ErrorKind::Other,
-        format!("Invalid: parse error: {}.", name),
-    ))
+pub fn parse_err<T>(name: &str) -> std::io::Result<T> {
+    Err(std::io::Error::other(format!("Invalid: parse error: {}.", name)))
 }
\ No newline at end of file
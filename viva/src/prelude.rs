@@ -0,0 +1,18 @@
+use crate::analysis::Span;
+use crate::parse::{read_forms, Sexp};
+
+/* Functions written in Viva that are available without being defined, see prelude.viva */
+pub const PRELUDE: &str = include_str!("prelude.viva");
+
+/* The fun definitions of the prelude as top-level forms. They are parsed with the source of a program or
+   a REPL input, so they have no place in it. */
+pub fn prelude_items() -> std::io::Result<Vec<Sexp>> {
+    Ok(read_forms("prelude.viva", PRELUDE)?.into_iter().map(|(form, _)| unplaced(form)).collect())
+}
+
+fn unplaced(form: Sexp) -> Sexp {
+    match form {
+        Sexp::Atom(atom, _) => Sexp::Atom(atom, Span::default()),
+        Sexp::List(items, _) => Sexp::List(items.into_iter().map(unplaced).collect(), Span::default()),
+    }
+}
@@ -0,0 +1,427 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::expressions::{Binder, Op1, Op2, Expr, Defenition, Pattern, Program, ReplExpr, Signature, Variant};
+use crate::parse::{struct_names, Sexp};
+use crate::parse::Atom::*;
+use crate::runtime::struct_declaration;
+use crate::visit::{fold_expr_children, Fold};
+
+/* Printing the tree back as Viva source. Every expression is written the way the parser reads it, so parsing
+   the text gives the same expression again. The forms the resolver and lifting produce are written as the
+   calls they come from: (+ a b) for an addition and (point-x p) for a field access, and a program is written
+   with its struct and enum declarations instead of the functions lowering made of them. */
+
+/* Lists that don't fit in this many columns are broken over several lines */
+const WIDTH: usize = 80;
+
+/* The layout of an s-expression before it is written */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Doc {
+    Atom(String),
+    List(Vec<Doc>),
+    /* The text of a ; comment, and whether it follows code on the same line. Only the formatter keeps them. */
+    Comment(String, bool),
+}
+
+impl Doc {
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.write(&mut out, 0);
+        out
+    }
+
+    fn flat(&self, out: &mut String) {
+        match self {
+            Doc::Atom(s) | Doc::Comment(s, _) => out.push_str(s),
+            Doc::List(items) => {
+                out.push('(');
+                for (index, item) in items.iter().enumerate() {
+                    if index > 0 {
+                        out.push(' ');
+                    }
+                    item.flat(out);
+                }
+                out.push(')');
+            }
+        }
+    }
+
+    fn has_comment(&self) -> bool {
+        match self {
+            Doc::Atom(_) => false,
+            Doc::Comment(_, _) => true,
+            Doc::List(items) => items.iter().any(Doc::has_comment),
+        }
+    }
+
+    /* A list that fits stays on one line. Otherwise a form keeps its head and the items that go with it, like
+       the bindings of a let, on the first line and puts the others below it, indented by two. A list that
+       doesn't start with a name, like the bindings themselves, puts its items below each other. A comment
+       runs to the end of the line, so whatever follows it starts on the next one. */
+    fn write(&self, out: &mut String, column: usize) {
+        let mut flat = String::new();
+        self.flat(&mut flat);
+        let items = match self {
+            Doc::List(items) if self.has_comment() || (items.len() > 1 && (column + flat.chars().count() > WIDTH || always_broken(&items[0]))) => items,
+            _ => return out.push_str(&flat),
+        };
+
+        let (indent, first_line) = match &items[0] {
+            Doc::Atom(head) => (column + 2, 1 + kept_on_first_line(head, items)),
+            _ => (column + 1, 1),
+        };
+        out.push('(');
+        let mut line_ended = false;
+        /* A :requires or :ensures on a line of its own keeps its condition next to it */
+        let mut keyword = false;
+        for (index, item) in items.iter().enumerate() {
+            match item {
+                _ if index == 0 => item.write(out, column + 1),
+                Doc::Comment(text, true) if !line_ended => {
+                    out.push(' ');
+                    out.push_str(text);
+                }
+                Doc::Atom(_) | Doc::List(_) if (index < first_line || keyword) && !line_ended => {
+                    keyword = false;
+                    out.push(' ');
+                    item.write(out, current_column(out));
+                }
+                _ => {
+                    keyword = matches!(item, Doc::Atom(a) if a.starts_with(':'));
+                    newline(out, indent);
+                    item.write(out, indent);
+                }
+            }
+            line_ended = matches!(item, Doc::Comment(_, _));
+        }
+        if line_ended {
+            newline(out, column);
+        }
+        out.push(')');
+    }
+}
+
+fn current_column(out: &str) -> usize {
+    out.rsplit('\n').next().unwrap_or("").chars().count()
+}
+
+fn newline(out: &mut String, column: usize) {
+    out.push('\n');
+    out.push_str(&" ".repeat(column));
+}
+
+/* A function puts its body below the header even when it is short */
+fn always_broken(head: &Doc) -> bool {
+    matches!(head, Doc::Atom(h) if h == "fun")
+}
+
+/* How many items after the head of a form stay on its first line when the form is broken, a loop label
+   stays with the head */
+fn kept_on_first_line(head: &str, items: &[Doc]) -> usize {
+    let labelled = matches!(items.get(1), Some(Doc::Atom(l)) if l.starts_with(':'));
+    match head {
+        "loop" | "break" => usize::from(labelled),
+        "while" | "for" => 1 + usize::from(labelled),
+        "let" | "let*" | "letrec" | "if" | "when" | "unless" | "case" | "match" | "try" | "catch" | "fun" |
+        "define" | "define-syntax" | "set!" | "enum" | "struct" | "assert" => 1,
+        "set-field!" => 2,
+        _ => 0,
+    }
+}
+
+fn atom(s: impl Into<String>) -> Doc {
+    Doc::Atom(s.into())
+}
+
+fn form(head: impl Into<String>, items: impl IntoIterator<Item = Doc>) -> Doc {
+    let mut list = vec![atom(head)];
+    list.extend(items);
+    Doc::List(list)
+}
+
+pub fn string_literal(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            _ => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+pub fn char_literal(c: char) -> String {
+    match c {
+        ' ' => "#\\space".to_string(),
+        '\n' => "#\\newline".to_string(),
+        '\t' => "#\\tab".to_string(),
+        _ => format!("#\\{}", c),
+    }
+}
+
+/* Debug writes the shortest text that reads back as the same double, and always with a . or an exponent */
+fn float_literal(bits: u64) -> String {
+    format!("{:?}", f64::from_bits(bits))
+}
+
+fn op1_name(op: &Op1) -> &'static str {
+    match op {
+        Op1::Add1 => "add1",
+        Op1::Sub1 => "sub1",
+        Op1::IsNum => "isnum",
+        Op1::IsBool => "isbool",
+        Op1::Not => "not",
+        Op1::Print => "print",
+        Op1::Arg => "arg",
+        Op1::StringLength => "string-length",
+        Op1::ErrorCode => "error-code",
+        Op1::VectorLength => "vector-length",
+    }
+}
+
+fn op2_name(op: &Op2) -> &'static str {
+    match op {
+        Op2::Plus => "+",
+        Op2::Minus => "-",
+        Op2::Times => "*",
+        Op2::Divide => "/",
+        Op2::Modulo => "mod",
+        Op2::Remainder => "rem",
+        Op2::BitAnd => "bit-and",
+        Op2::BitOr => "bit-or",
+        Op2::BitXor => "bit-xor",
+        Op2::ShiftLeft => "shl",
+        Op2::ShiftRight => "shr",
+        Op2::Equal => "=",
+        Op2::Greater => ">",
+        Op2::GreaterEqual => ">=",
+        Op2::Less => "<",
+        Op2::LessEqual => "<=",
+        Op2::StringAppend => "string-append",
+        Op2::StringEqual => "string=?",
+        Op2::VectorRef => "vector-ref",
+    }
+}
+
+/* The name a struct was declared with, the functions generated for it are named after it */
+fn struct_name(id: i64) -> (String, Vec<String>) {
+    struct_declaration(id).unwrap_or_else(|| (format!("#struct{}", id), Vec::new()))
+}
+
+fn label(l: &Option<String>) -> Option<Doc> {
+    l.as_ref().map(|l| atom(format!(":{}", l)))
+}
+
+fn exprs(es: &[Expr]) -> Vec<Doc> {
+    es.iter().map(expr_doc).collect()
+}
+
+pub fn expr_doc(e: &Expr) -> Doc {
+    match e {
+        Expr::Number(n) => atom(n.to_string()),
+        Expr::Float(bits) => atom(float_literal(*bits)),
+        Expr::Boolean(b) => atom(b.to_string()),
+        Expr::Str(s) => atom(string_literal(s)),
+        Expr::Char(c) => atom(char_literal(*c)),
+        Expr::Nothing => form("block", []),
+        Expr::Argc => form("argc", []),
+        Expr::ReadNum => form("read-num", []),
+        Expr::ReadBool => form("read-bool", []),
+        Expr::Id(name) => atom(name.as_str()),
+        Expr::Let(bindings, body) => {
+            let bindings = bindings.iter().map(|(name, value)| Doc::List(vec![atom(name.name.as_str()), expr_doc(value)])).collect();
+            form("let", [Doc::List(bindings), expr_doc(body)])
+        }
+        Expr::LetRec(funs, body) => form("letrec", [Doc::List(funs.iter().map(defenition_doc).collect()), expr_doc(body)]),
+        Expr::UnOp(op, e) => form(op1_name(op), [expr_doc(e)]),
+        Expr::BinOp(op, e1, e2) => form(op2_name(op), [expr_doc(e1), expr_doc(e2)]),
+        Expr::Substring(s, start, end) => form("substring", [expr_doc(s), expr_doc(start), expr_doc(end)]),
+        Expr::MakeStruct(id, es) => form(struct_name(*id).0, exprs(es)),
+        Expr::MakeVector(es) => form("vector", exprs(es)),
+        Expr::GetField(id, index, e) => {
+            let (name, fields) = struct_name(*id);
+            let field = fields.get(*index).cloned().unwrap_or_else(|| index.to_string());
+            form(format!("{}-{}", name, field), [expr_doc(e)])
+        }
+        Expr::IsStruct(id, e) => form(format!("{}?", struct_name(*id).0), [expr_doc(e)]),
+        Expr::SetField(e, field, value) => form("set-field!", [expr_doc(e), atom(field.as_str()), expr_doc(value)]),
+        Expr::Match(e, arms, _) => {
+            let arms = arms.iter().map(|(pattern, body)| Doc::List(vec![pattern_doc(pattern), expr_doc(body)]));
+            form("match", std::iter::once(expr_doc(e)).chain(arms))
+        }
+        Expr::Raise(e) => form("raise", [expr_doc(e)]),
+        Expr::Assert(e, message) => form("assert", [expr_doc(e), atom(string_literal(message))]),
+        Expr::Try(body, name, handler) => form("try", [expr_doc(body), form("catch", [atom(name.name.as_str()), expr_doc(handler)])]),
+        Expr::And(es) => form("and", exprs(es)),
+        Expr::Or(es) => form("or", exprs(es)),
+        Expr::If(c, t, f) => form("if", [expr_doc(c), expr_doc(t), expr_doc(f)]),
+        Expr::Cond(arms, default) => {
+            let arms = arms.iter().map(|(test, body)| Doc::List(vec![expr_doc(test), expr_doc(body)]));
+            form("cond", arms.chain(default.iter().map(|d| form("else", [expr_doc(d)]))))
+        }
+        Expr::When(c, body) => form("when", [expr_doc(c), expr_doc(body)]),
+        Expr::Unless(c, body) => form("unless", [expr_doc(c), expr_doc(body)]),
+        Expr::Case(e, arms, default) => {
+            let arms = arms.iter().map(|(keys, body)| Doc::List(vec![Doc::List(exprs(keys)), expr_doc(body)]));
+            let clauses = arms.chain(default.iter().map(|d| form("else", [expr_doc(d)])));
+            form("case", std::iter::once(expr_doc(e)).chain(clauses))
+        }
+        Expr::Loop(l, e) => form("loop", label(l).into_iter().chain([expr_doc(e)])),
+        Expr::Break(l, e) => form("break", label(l).into_iter().chain([expr_doc(e)])),
+        Expr::Continue(l) => form("continue", label(l)),
+        Expr::Set(name, e) => form("set!", [atom(name.as_str()), expr_doc(e)]),
+        Expr::Block(es) => form("block", exprs(es)),
+        Expr::Call(name, args, _) => form(name.as_str(), exprs(args)),
+    }
+}
+
+fn pattern_doc(pattern: &Pattern) -> Doc {
+    match pattern {
+        Pattern::Variant(name, _, names, _) => form(name.as_str(), names.iter().map(|n| atom(n.name.as_str()))),
+        Pattern::Wildcard => atom("_"),
+    }
+}
+
+/* (fun (name required* (optional default)* &rest name?) body) */
+fn fun_doc(name: &str, params: &[Binder], signature: &Signature, body: &Expr) -> Doc {
+    let mut header = vec![atom(name)];
+    for (index, param) in params.iter().enumerate() {
+        if index < signature.required {
+            header.push(atom(param.name.as_str()));
+        } else if let Some(default) = signature.defaults.get(index - signature.required) {
+            header.push(Doc::List(vec![atom(param.name.as_str()), expr_doc(default)]));
+        } else {
+            header.push(atom("&rest"));
+            header.push(atom(param.name.as_str()));
+        }
+    }
+    form("fun", [Doc::List(header), expr_doc(body)])
+}
+
+fn struct_doc(name: &str, fields: &[String]) -> Doc {
+    form("struct", [atom(name), Doc::List(fields.iter().map(|f| atom(f.as_str())).collect())])
+}
+
+fn enum_doc(name: &str, variants: &[Variant]) -> Doc {
+    let variants = variants.iter().map(|v| form(v.name.as_str(), v.fields.iter().map(|f| atom(f.as_str()))));
+    form("enum", std::iter::once(atom(name)).chain(variants))
+}
+
+pub fn defenition_doc(def: &Defenition) -> Doc {
+    match def {
+        Defenition::Fun(name, params, signature, body) => fun_doc(name, params, signature, body),
+        Defenition::Struct(name, fields) => struct_doc(name, fields),
+        Defenition::Enum(name, variants) => enum_doc(name, variants),
+    }
+}
+
+pub fn repl_expr_doc(e: &ReplExpr) -> Doc {
+    match e {
+        ReplExpr::Define(name, e) => form("define", [atom(name.as_str()), expr_doc(e)]),
+        ReplExpr::Expr(e) => expr_doc(e),
+        ReplExpr::Fun(name, params, signature, body) => fun_doc(name, params, signature, body),
+        ReplExpr::Struct(name, fields) => struct_doc(name, fields),
+        ReplExpr::Enum(name, variants) => enum_doc(name, variants),
+        ReplExpr::Syntax(name, m) => sexp_doc(&m.definition(name)),
+    }
+}
+
+/* Macros keep the s-expressions they were written as */
+pub fn sexp_doc(s: &Sexp) -> Doc {
+    match s {
+        Sexp::Atom(S(s), _) => atom(s.as_str()),
+        Sexp::Atom(I(n), _) => atom(n.to_string()),
+        Sexp::Atom(F(f), _) => atom(float_literal(f.to_bits())),
+        Sexp::Atom(Str(s), _) => atom(string_literal(s)),
+        Sexp::Atom(Char(c), _) => atom(char_literal(*c)),
+        Sexp::List(items, _) => Doc::List(items.iter().map(sexp_doc).collect()),
+    }
+}
+
+impl fmt::Display for Sexp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&sexp_doc(self).render())
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&expr_doc(self).render())
+    }
+}
+
+impl fmt::Display for Defenition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&defenition_doc(self).render())
+    }
+}
+
+impl fmt::Display for ReplExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&repl_expr_doc(self).render())
+    }
+}
+
+/* The functions lowering generated for a struct or an enum, they are printed as the declaration */
+fn generated_names(declaration: &Defenition) -> Vec<String> {
+    match declaration {
+        Defenition::Struct(name, fields) => struct_names(name, fields),
+        Defenition::Enum(_, variants) => variants.iter().map(|v| v.name.clone()).collect(),
+        Defenition::Fun(_, _, _, _) => Vec::new(),
+    }
+}
+
+/* Gives the calls of a resolved program the arguments they were written with: the &rest arguments come out of
+   the vector they were packed into and the defaults at the end are left out */
+struct Written<'a>(HashMap<&'a str, &'a Signature>);
+
+impl Fold for Written<'_> {
+    fn fold_expr(&mut self, e: Expr) -> Expr {
+        match fold_expr_children(self, e) {
+            Expr::Call(name, mut args, span) => {
+                if let Some(signature) = self.0.get(name.as_str()) {
+                    let extra = match args.last() {
+                        Some(Expr::MakeVector(_)) if signature.rest => match args.pop() {
+                            Some(Expr::MakeVector(es)) => es,
+                            _ => unreachable!(),
+                        },
+                        _ => Vec::new(),
+                    };
+                    while extra.is_empty() && args.len() > signature.required && args.last() == signature.defaults.get(args.len() - 1 - signature.required) {
+                        args.pop();
+                    }
+                    args.extend(extra);
+                }
+                Expr::Call(name, args, span)
+            }
+            e => e,
+        }
+    }
+}
+
+/* The declarations, the definitions, then the globals in the order they are initialized and main, with an empty
+   line between them */
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let generated: HashSet<String> = self.declarations.iter().flat_map(generated_names).collect();
+        let mut written = Written(self.defs.iter().filter_map(|def| match def {
+            Defenition::Fun(name, _, signature, _) => Some((name.as_str(), signature)),
+            Defenition::Struct(_, _) | Defenition::Enum(_, _) => None,
+        }).collect());
+        let mut items: Vec<String> = self.declarations.iter().map(|def| def.to_string()).collect();
+        for def in &self.defs {
+            match def {
+                Defenition::Fun(name, _, _, _) if generated.contains(name) => {},
+                def => items.push(written.fold_defenition(def.clone()).to_string()),
+            }
+        }
+        items.extend(self.globals.iter().map(|(name, e)| form("define", [atom(name.as_str()), expr_doc(&written.fold_expr(e.clone()))]).render()));
+        items.push(written.fold_expr(self.main.clone()).to_string());
+        f.write_str(&items.join("\n\n"))
+    }
+}
@@ -0,0 +1,352 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::analysis::{into_result, suggestion, Diagnostic, Span};
+use crate::expressions::{Binder, Defenition, Expr, Op1, Op2, Pattern, ReplExpr, Signature};
+use crate::parse::DefNames;
+use crate::visit::{VisitorMut, walk_expr_mut};
+
+/* Name resolution runs after parsing and finds out what every call refers to. Scopes are searched from the
+   innermost out: the locals of let, for, catch and match arms, the parameters of the function and the
+   functions of the enclosing letrecs, then the globals, the functions of the program and last the builtins.
+   A name bound in an inner scope hides its outer meanings, so a program can use the name of a builtin for a
+   function or a variable, and adding a builtin never changes what an existing program means. A local or a
+   parameter that hides a function is reported as a warning. */
+
+#[derive(Clone, Debug)]
+enum Binding {
+    Local,
+    Param,
+    /* A letrec function */
+    Function(Signature),
+}
+
+/* What a name refers to once all scopes are searched */
+enum Meaning {
+    /* A local, a parameter or a global */
+    Variable(&'static str),
+    Function(String, Signature),
+    Builtin(Signature),
+    Unknown,
+}
+
+/* The operations that are written like calls and how many arguments they take, vector takes any number */
+const BUILTINS: &[(&str, usize)] = &[
+    ("argc", 0), ("read-num", 0), ("read-bool", 0),
+    ("add1", 1), ("sub1", 1), ("isnum", 1), ("isbool", 1), ("not", 1), ("print", 1), ("arg", 1),
+    ("string-length", 1), ("error-code", 1), ("raise", 1), ("vector-length", 1),
+    ("+", 2), ("-", 2), ("*", 2), ("/", 2), ("mod", 2), ("rem", 2),
+    ("bit-and", 2), ("bit-or", 2), ("bit-xor", 2), ("shl", 2), ("shr", 2),
+    ("=", 2), (">", 2), (">=", 2), ("<", 2), ("<=", 2),
+    ("string-append", 2), ("string=?", 2), ("vector-ref", 2),
+    ("substring", 3),
+    ("vector", 0),
+];
+
+fn builtin_signature(name: &str) -> Option<Signature> {
+    if name == "vector" {
+        return Some(Signature { required: 0, defaults: vec![], rest: true });
+    }
+    BUILTINS.iter().find(|(n, _)| *n == name).map(|(_, count)| Signature::fixed(*count))
+}
+
+/* The expression of a builtin operation, the number of arguments is already checked */
+fn builtin(name: &str, args: Vec<Expr>) -> Expr {
+    if name == "vector" {
+        return Expr::MakeVector(args);
+    }
+    let mut args = args.into_iter().map(Box::new);
+    let mut arg = || args.next().expect("the arguments of a builtin are counted before");
+    match name {
+        "argc" => Expr::Argc,
+        "read-num" => Expr::ReadNum,
+        "read-bool" => Expr::ReadBool,
+        "add1" => Expr::UnOp(Op1::Add1, arg()),
+        "sub1" => Expr::UnOp(Op1::Sub1, arg()),
+        "isnum" => Expr::UnOp(Op1::IsNum, arg()),
+        "isbool" => Expr::UnOp(Op1::IsBool, arg()),
+        "not" => Expr::UnOp(Op1::Not, arg()),
+        "print" => Expr::UnOp(Op1::Print, arg()),
+        "arg" => Expr::UnOp(Op1::Arg, arg()),
+        "string-length" => Expr::UnOp(Op1::StringLength, arg()),
+        "error-code" => Expr::UnOp(Op1::ErrorCode, arg()),
+        "vector-length" => Expr::UnOp(Op1::VectorLength, arg()),
+        "raise" => Expr::Raise(arg()),
+        "+" => Expr::BinOp(Op2::Plus, arg(), arg()),
+        "-" => Expr::BinOp(Op2::Minus, arg(), arg()),
+        "*" => Expr::BinOp(Op2::Times, arg(), arg()),
+        "/" => Expr::BinOp(Op2::Divide, arg(), arg()),
+        "mod" => Expr::BinOp(Op2::Modulo, arg(), arg()),
+        "rem" => Expr::BinOp(Op2::Remainder, arg(), arg()),
+        "bit-and" => Expr::BinOp(Op2::BitAnd, arg(), arg()),
+        "bit-or" => Expr::BinOp(Op2::BitOr, arg(), arg()),
+        "bit-xor" => Expr::BinOp(Op2::BitXor, arg(), arg()),
+        "shl" => Expr::BinOp(Op2::ShiftLeft, arg(), arg()),
+        "shr" => Expr::BinOp(Op2::ShiftRight, arg(), arg()),
+        "=" => Expr::BinOp(Op2::Equal, arg(), arg()),
+        ">" => Expr::BinOp(Op2::Greater, arg(), arg()),
+        ">=" => Expr::BinOp(Op2::GreaterEqual, arg(), arg()),
+        "<" => Expr::BinOp(Op2::Less, arg(), arg()),
+        "<=" => Expr::BinOp(Op2::LessEqual, arg(), arg()),
+        "string-append" => Expr::BinOp(Op2::StringAppend, arg(), arg()),
+        "string=?" => Expr::BinOp(Op2::StringEqual, arg(), arg()),
+        "vector-ref" => Expr::BinOp(Op2::VectorRef, arg(), arg()),
+        "substring" => Expr::Substring(arg(), arg(), arg()),
+        _ => unreachable!("{} is not a builtin", name),
+    }
+}
+
+/* The arguments a call passes: the missing optional ones get their default and the ones past them are packed
+   into a vector for the &rest parameter */
+fn fill_arguments(signature: &Signature, mut args: Vec<Expr>) -> Vec<Expr> {
+    let positional = signature.required + signature.defaults.len();
+    let extra = if args.len() > positional { args.split_off(positional) } else { Vec::new() };
+    let given = args.len() - signature.required;
+    args.extend(signature.defaults[given..].iter().cloned());
+    if signature.rest {
+        args.push(Expr::MakeVector(extra));
+    }
+    args
+}
+
+pub struct Resolver<'a> {
+    functions: &'a DefNames,
+    globals: HashSet<String>,
+    scopes: Vec<HashMap<String, Binding>>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+/* Resolves the definitions, the globals and main of one file in place and returns its warnings */
+pub fn resolve_program(
+    defs: &mut [Defenition],
+    globals: &mut [(String, Expr)],
+    main: &mut Expr,
+    functions: &DefNames,
+    source: Option<(&str, &str)>,
+) -> std::io::Result<Vec<String>> {
+    let mut resolver = Resolver::new(functions, globals.iter().map(|(name, _)| name.clone()).collect());
+    for def in defs.iter_mut() {
+        resolver.visit_defenition_mut(def);
+    }
+    for (_, e) in globals.iter_mut() {
+        resolver.visit_expr_mut(e);
+    }
+    resolver.visit_expr_mut(main);
+    into_result(&resolver.diagnostics, source)
+}
+
+/* The globals of a REPL session are its defines. A fun can call itself, it becomes known to the session
+   only after it compiled. */
+pub fn resolve_repl_expr(e: &mut ReplExpr, functions: &DefNames, globals: HashSet<String>, source: &str) -> std::io::Result<Vec<String>> {
+    let mut names = functions.clone();
+    if let ReplExpr::Fun(name, _, signature, _) = e {
+        names.insert(name.clone(), signature.clone());
+    }
+    let mut resolver = Resolver::new(&names, globals);
+    match e {
+        ReplExpr::Fun(_, params, _, body) => {
+            resolver.scopes.push(HashMap::new());
+            for param in params.iter() {
+                resolver.bind(param, Binding::Param);
+            }
+            resolver.visit_expr_mut(body);
+            resolver.scopes.pop();
+        }
+        ReplExpr::Define(_, e) | ReplExpr::Expr(e) => resolver.visit_expr_mut(e),
+        ReplExpr::Struct(_, _) | ReplExpr::Enum(_, _) | ReplExpr::Syntax(_, _) => {}
+    }
+    into_result(&resolver.diagnostics, Some(("input", source)))
+}
+
+impl<'a> Resolver<'a> {
+    pub fn new(functions: &'a DefNames, globals: HashSet<String>) -> Self {
+        Resolver { functions, globals, scopes: Vec::new(), diagnostics: Vec::new() }
+    }
+
+    fn lookup(&self, name: &str) -> Meaning {
+        for scope in self.scopes.iter().rev() {
+            match scope.get(name) {
+                Some(Binding::Local) => return Meaning::Variable("local"),
+                Some(Binding::Param) => return Meaning::Variable("parameter"),
+                Some(Binding::Function(signature)) => return Meaning::Function(name.to_string(), signature.clone()),
+                None => {}
+            }
+        }
+        if self.globals.contains(name) {
+            return Meaning::Variable("global");
+        }
+        if let Some((qualified, signature)) = self.functions.resolve(name) {
+            return Meaning::Function(qualified, signature);
+        }
+        match builtin_signature(name) {
+            Some(signature) => Meaning::Builtin(signature),
+            None => Meaning::Unknown,
+        }
+    }
+
+    /* Binds a local or a parameter in the innermost scope. The warning points at where the name is bound, a
+       name that isn't written in the source, like the ones the prelude binds, is not warned about. */
+    fn bind(&mut self, binder: &Binder, binding: Binding) {
+        let name = binder.name.as_str();
+        let kind = match binding {
+            Binding::Param => "parameter",
+            Binding::Local | Binding::Function(_) => "local",
+        };
+        let hidden = match self.lookup(name) {
+            Meaning::Function(_, _) => Some("function"),
+            Meaning::Builtin(_) => Some("builtin"),
+            Meaning::Variable(_) | Meaning::Unknown => None,
+        };
+        if let (Some(hidden), false) = (hidden, binder.span.0.is_empty()) {
+            self.diagnostics.push(Diagnostic::warning(
+                format!("{} `{}` shadows the {} `{}`", kind, name, hidden, name),
+                &format!("{} `{}`", kind, name),
+                binder.span.clone(),
+            ));
+        }
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_string(), binding);
+        }
+    }
+
+    /* Calls of functions get their qualified name and the defaults and &rest vector filled in, calls of
+       builtins become the operation. A call that can't be resolved stays as it is and is reported. */
+    fn call(&mut self, name: String, args: Vec<Expr>, span: Span) -> Expr {
+        match self.lookup(&name) {
+            Meaning::Function(callee, signature) if signature.accepts(args.len()) => Expr::Call(callee, fill_arguments(&signature, args), span),
+            Meaning::Builtin(signature) if signature.accepts(args.len()) => builtin(&name, args),
+            Meaning::Function(_, signature) | Meaning::Builtin(signature) => {
+                self.diagnostics.push(Diagnostic::wrong_arity(&name, &signature, args.len(), span.clone()));
+                Expr::Call(name, args, span)
+            }
+            Meaning::Variable(kind) => {
+                self.diagnostics.push(Diagnostic::error(
+                    format!("`{}` is a {} variable, not a function", name, kind),
+                    "not a function",
+                    span.clone(),
+                ));
+                Expr::Call(name, args, span)
+            }
+            Meaning::Unknown => {
+                let mut candidates: Vec<String> = self.functions.names().cloned().collect();
+                candidates.extend(BUILTINS.iter().map(|(n, _)| n.to_string()));
+                for scope in &self.scopes {
+                    candidates.extend(scope.iter().filter(|(_, b)| matches!(b, Binding::Function(_))).map(|(n, _)| n.clone()));
+                }
+                let message = match suggestion(&name, candidates.iter()) {
+                    Some(close) => format!("unknown function `{}`, did you mean `{}`?", name, close),
+                    None => format!("unknown function `{}`", name),
+                };
+                self.diagnostics.push(Diagnostic::error(message, "not defined", span.clone()));
+                Expr::Call(name, args, span)
+            }
+        }
+    }
+
+    /* Qualifies the names of the variants and checks that the arms match on one enum, and on all of its variants
+       unless the last arm is _ */
+    fn patterns(&mut self, arms: &mut [(Pattern, Expr)], span: &Span) {
+        let functions = self.functions;
+        let mut enum_name: Option<&String> = None;
+        let mut covered: HashSet<&String> = HashSet::new();
+        for (pattern, _) in arms.iter_mut() {
+            let (name, bindings, pattern_span) = match pattern {
+                Pattern::Variant(name, _, bindings, pattern_span) => (name, bindings, pattern_span.clone()),
+                Pattern::Wildcard => continue,
+            };
+            let (owner, variant) = match functions.variant(name) {
+                Some(v) => v,
+                None => {
+                    self.diagnostics.push(Diagnostic::error(format!("unknown variant {}", name), "not a variant", pattern_span.clone()));
+                    continue;
+                }
+            };
+            if *enum_name.get_or_insert(owner) != owner {
+                self.diagnostics.push(Diagnostic::error("all patterns of a match should belong to the same enum".to_string(), &format!("a variant of {}", owner), pattern_span.clone()));
+            } else if bindings.len() != variant.fields.len() {
+                self.diagnostics.push(Diagnostic::error(format!("{} has {} fields", name, variant.fields.len()), "wrong number of fields", pattern_span.clone()));
+            } else if !covered.insert(&variant.name) {
+                self.diagnostics.push(Diagnostic::error(format!("Duplicate match arm for {}", name), "matched before", pattern_span.clone()));
+            }
+            *name = variant.name.clone();
+        }
+
+        if let Some(name) = enum_name {
+            if !arms.iter().any(|(p, _)| *p == Pattern::Wildcard) {
+                let missing: Vec<&str> = functions.enum_variants(name).iter().filter(|v| !covered.contains(&v.name)).map(|v| v.name.as_str()).collect();
+                if !missing.is_empty() {
+                    self.diagnostics.push(Diagnostic::error(
+                        format!("non-exhaustive match on {}, missing {}", name, missing.join(", ")),
+                        "not every variant is matched",
+                        span.clone(),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/* The resolver keeps a scope per binding form and rewrites the calls, the rest of the tree is walked as it is */
+impl VisitorMut for Resolver<'_> {
+    fn visit_defenition_mut(&mut self, def: &mut Defenition) {
+        if let Defenition::Fun(_, params, _, body) = def {
+            self.scopes.push(HashMap::new());
+            for param in params.iter() {
+                self.bind(param, Binding::Param);
+            }
+            self.visit_expr_mut(body);
+            self.scopes.pop();
+        }
+    }
+
+    fn visit_expr_mut(&mut self, e: &mut Expr) {
+        match e {
+            Expr::Let(bindings, body) => {
+                self.scopes.push(HashMap::new());
+                for (name, value) in bindings.iter_mut() {
+                    self.visit_expr_mut(value);
+                    self.bind(name, Binding::Local);
+                }
+                self.visit_expr_mut(body);
+                self.scopes.pop();
+            },
+            Expr::LetRec(funs, _) => {
+                let mut scope = HashMap::new();
+                for def in funs.iter() {
+                    if let Defenition::Fun(name, _, signature, _) = def {
+                        scope.insert(name.clone(), Binding::Function(signature.clone()));
+                    }
+                }
+                self.scopes.push(scope);
+                walk_expr_mut(self, e);
+                self.scopes.pop();
+            },
+            Expr::Try(body, name, handler) => {
+                self.visit_expr_mut(body);
+                self.scopes.push(HashMap::new());
+                self.bind(name, Binding::Local);
+                self.visit_expr_mut(handler);
+                self.scopes.pop();
+            },
+            Expr::Call(name, args, span) => {
+                for arg in args.iter_mut() {
+                    self.visit_expr_mut(arg);
+                }
+                let (name, args, span) = (std::mem::take(name), std::mem::take(args), std::mem::take(span));
+                *e = self.call(name, args, span);
+            },
+            Expr::Match(scrutinee, arms, span) => {
+                self.visit_expr_mut(scrutinee);
+                self.patterns(arms, span);
+                for (pattern, body) in arms.iter_mut() {
+                    self.scopes.push(HashMap::new());
+                    for (_, name) in pattern.bindings() {
+                        self.bind(name, Binding::Local);
+                    }
+                    self.visit_expr_mut(body);
+                    self.scopes.pop();
+                }
+            },
+            _ => walk_expr_mut(self, e),
+        }
+    }
+}
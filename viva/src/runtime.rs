@@ -1,24 +1,569 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::BufRead;
+use std::sync::Mutex;
+
+use crate::bignum::BigInt;
+
+/* Heap values are 8 byte aligned pointers tagged with 0b101, the first word of every
+   object is its kind. Strings continue with their length in bytes and the UTF-8 bytes. */
+pub const STRING_KIND: i64 = 1;
+/* Bignums continue with the sign (1 for negative), the number of limbs and the limbs, least significant first */
+pub const BIGNUM_KIND: i64 = 2;
+/* Floats continue with the bits of the double */
+pub const FLOAT_KIND: i64 = 3;
+/* Structs continue with their id in the struct registry and the fields in declaration order */
+pub const STRUCT_KIND: i64 = 4;
+/* Runtime errors caught by a try continue with their error code */
+pub const ERROR_KIND: i64 = 5;
+/* Vectors continue with their length and the elements, which start where the fields of a struct do */
+pub const VECTOR_KIND: i64 = 6;
+
+/* Names of the declared structs and their fields, indexed by struct id. Declarations live as long as the
+   process, like the code compiled for them, and are shared by every REPL. */
+static STRUCTS: Mutex<Vec<(String, Vec<String>)>> = Mutex::new(Vec::new());
+
+pub fn register_struct(name: &str, fields: &[String]) -> i64 {
+    let mut structs = STRUCTS.lock().unwrap();
+    structs.push((name.to_string(), fields.to_vec()));
+    (structs.len() - 1) as i64
+}
+
+/* The id of every struct and variant with a field of the given name, along with the index of that field */
+pub fn structs_with_field(field: &str) -> Vec<(i64, usize)> {
+    let structs = STRUCTS.lock().unwrap();
+    structs.iter().enumerate()
+        .filter_map(|(id, (_, fields))| fields.iter().position(|f| f == field).map(|index| (id as i64, index)))
+        .collect()
+}
+
+/* The name and the fields a struct or variant was registered with */
+pub fn struct_declaration(id: i64) -> Option<(String, Vec<String>)> {
+    let structs = STRUCTS.lock().unwrap();
+    usize::try_from(id).ok().and_then(|id| structs.get(id).cloned())
+}
+
+fn heap_object(val: i64) -> Option<*const i64> {
+    if val & 7 == 5 { Some((val - 5) as *const i64) } else { None }
+}
+
+fn as_string(val: i64) -> Option<String> {
+    let ptr = heap_object(val)?;
+    unsafe {
+        if *ptr != STRING_KIND {
+            return None;
+        }
+        let len = *ptr.add(1) as usize;
+        let bytes = std::slice::from_raw_parts(ptr.add(2) as *const u8, len);
+        Some(String::from_utf8_lossy(bytes).into_owned())
+    }
+}
+
+/* Objects are never freed, there is no garbage collector yet */
+fn alloc_object(words: Vec<i64>) -> i64 {
+    let ptr = Box::leak(words.into_boxed_slice()).as_ptr();
+    ptr as i64 | 5
+}
+
+/* The literal strings and floats of jitted code, by their words. They outlive the code they were compiled for,
+   and a literal that is compiled again gets the object it got before. */
+static STATICS: Mutex<BTreeMap<Vec<i64>, i64>> = Mutex::new(BTreeMap::new());
+
+pub fn static_object(words: Vec<i64>) -> i64 {
+    let mut statics = STATICS.lock().unwrap();
+    *statics.entry(words).or_insert_with_key(|words| alloc_object(words.clone()))
+}
+
+pub fn string_words(s: &str) -> Vec<i64> {
+    let bytes = s.as_bytes();
+    let mut words = vec![STRING_KIND, bytes.len() as i64];
+    for chunk in bytes.chunks(8) {
+        let mut word = [0u8; 8];
+        word[..chunk.len()].copy_from_slice(chunk);
+        words.push(i64::from_le_bytes(word));
+    }
+    words
+}
+
+fn alloc_string(s: &str) -> i64 {
+    alloc_object(string_words(s))
+}
+
+pub fn float_words(f: f64) -> Vec<i64> {
+    vec![FLOAT_KIND, f.to_bits() as i64]
+}
+
+fn as_float(val: i64) -> Option<f64> {
+    let ptr = heap_object(val)?;
+    unsafe {
+        if *ptr != FLOAT_KIND {
+            return None;
+        }
+        Some(f64::from_bits(*ptr.add(1) as u64))
+    }
+}
+
+fn alloc_float(f: f64) -> i64 {
+    alloc_object(float_words(f))
+}
+
+/* The struct id and a pointer to the first field */
+fn as_struct(val: i64) -> Option<(i64, *mut i64)> {
+    let ptr = heap_object(val)? as *mut i64;
+    unsafe {
+        if *ptr != STRUCT_KIND {
+            return None;
+        }
+        Some((*ptr.add(1), ptr.add(2)))
+    }
+}
+
+/* The length and a pointer to the first element */
+fn as_vector(val: i64) -> Option<(i64, *mut i64)> {
+    let ptr = heap_object(val)? as *mut i64;
+    unsafe {
+        if *ptr != VECTOR_KIND {
+            return None;
+        }
+        Some((*ptr.add(1), ptr.add(2)))
+    }
+}
+
+fn as_error(val: i64) -> Option<i64> {
+    let ptr = heap_object(val)?;
+    unsafe {
+        if *ptr != ERROR_KIND {
+            return None;
+        }
+        Some(*ptr.add(1))
+    }
+}
+
+fn format_struct(id: i64, fields_ptr: *mut i64) -> String {
+    let (name, fields) = STRUCTS.lock().unwrap()[id as usize].clone();
+    if fields.is_empty() {
+        return format!("{} {{}}", name);
+    }
+    let values: Vec<String> = fields.iter().enumerate()
+        .map(|(index, field)| format!("{}: {}", field, format_value(unsafe { *fields_ptr.add(index) })))
+        .collect();
+    format!("{} {{ {} }}", name, values.join(", "))
+}
+
+fn as_bignum(val: i64) -> Option<BigInt> {
+    let ptr = heap_object(val)?;
+    unsafe {
+        if *ptr != BIGNUM_KIND {
+            return None;
+        }
+        let len = *ptr.add(2) as usize;
+        let limbs = std::slice::from_raw_parts(ptr.add(3) as *const u64, len);
+        Some(BigInt::from_parts(*ptr.add(1) == 1, limbs.to_vec()))
+    }
+}
+
+/* Numbers that fit into a tagged integer are never boxed, so every value has one representation */
+fn alloc_number(n: BigInt) -> i64 {
+    match n.to_i64() {
+        Some(v) if (-(1 << 62)..(1 << 62)).contains(&v) => v << 1,
+        _ => {
+            let mut words = vec![BIGNUM_KIND, n.negative as i64, n.limbs.len() as i64];
+            words.extend(n.limbs.iter().map(|limb| *limb as i64));
+            alloc_object(words)
+        }
+    }
+}
+
+fn as_number(val: i64) -> Option<BigInt> {
+    if val & 1 == 0 { Some(BigInt::from_i64(val >> 1)) } else { as_bignum(val) }
+}
+
+/* Characters are immediates: the code point shifted left by 4 and tagged with 0b1111 */
+pub fn char_value(c: char) -> i64 {
+    ((c as i64) << 4) | 15
+}
+
+pub fn format_value(val: i64) -> String {
+    if val == 3 { "true".to_string() }
+    else if val == 1 { "false".to_string() }
+    else if val == 7 { "nothing".to_string() }
+    else if val % 2 == 0 { format!("{}", val >> 1) }
+    else if val & 15 == 15 {
+        match char::from_u32((val >> 4) as u32) {
+            Some(c) => c.to_string(),
+            None => format!("Unknown value: {}", val),
+        }
+    }
+    else if let Some(s) = as_string(val) { s }
+    else if let Some(n) = as_bignum(val) { n.to_string() }
+    else if let Some(f) = as_float(val) { format!("{:?}", f) }
+    else if let Some((id, fields)) = as_struct(val) { format_struct(id, fields) }
+    else if let Some((len, elements)) = as_vector(val) {
+        let items: Vec<String> = (0..len as usize).map(|i| format_value(unsafe { *elements.add(i) })).collect();
+        format!("[{}]", items.join(", "))
+    }
+    else if let Some(code) = as_error(val) { format!("#<error {}: {}>", code, error_message(code).unwrap_or("unknown")) }
+    else { format!("Unknown value: {}", val) }
+}
+
 #[export_name = "\x01snek_print"]
 pub fn snek_print(val: i64) {
-    if val == 3 { println!("true"); }
-    else if val == 1 { println!("false"); }
-    else if val % 2 == 0 { println!("{}", val >> 1); }
-    else { println!("Unknown value: {}", val); }
+    println!("{}", format_value(val));
 }
 
-#[export_name = "\x01snek_error"]
-pub fn snek_error(err_code: i8) { 
-    match err_code {
-        1 => {
-            eprintln!("Runtime error: overflow");
-            std::process::exit(1);
+fn expect_string(val: i64) -> String {
+    match as_string(val) {
+        Some(s) => s,
+        None => { snek_error(2); String::new() }
+    }
+}
+
+#[export_name = "\x01snek_string_length"]
+pub extern "C" fn snek_string_length(s: i64) -> i64 {
+    (expect_string(s).chars().count() as i64) << 1
+}
+
+#[export_name = "\x01snek_string_append"]
+pub extern "C" fn snek_string_append(a: i64, b: i64) -> i64 {
+    let mut result = expect_string(a);
+    result.push_str(&expect_string(b));
+    alloc_string(&result)
+}
+
+/* Characters from start up to, but not including, end */
+#[export_name = "\x01snek_substring"]
+pub extern "C" fn snek_substring(s: i64, start: i64, end: i64) -> i64 {
+    let string = expect_string(s);
+    if start & 1 != 0 || end & 1 != 0 {
+        snek_error(2);
+        return 1;
+    }
+    let (start, end) = (start >> 1, end >> 1);
+    let len = string.chars().count() as i64;
+    if start < 0 || end < start || end > len {
+        snek_error(7);
+        return 1;
+    }
+    alloc_string(&string.chars().skip(start as usize).take((end - start) as usize).collect::<String>())
+}
+
+#[export_name = "\x01snek_string_equal"]
+pub extern "C" fn snek_string_equal(a: i64, b: i64) -> i64 {
+    if expect_string(a) == expect_string(b) { 3 } else { 1 }
+}
+
+fn expect_number(val: i64) -> BigInt {
+    match as_number(val) {
+        Some(n) => n,
+        None => { snek_error(2); BigInt::from_i64(0) }
+    }
+}
+
+/* Both operands as doubles when at least one of them is a float, mixed arithmetic is done in floating point */
+fn float_operands(a: i64, b: i64) -> Option<(f64, f64)> {
+    let to_float = |val: i64| as_float(val).or_else(|| as_number(val).map(|n| n.to_f64()));
+    if as_float(a).is_none() && as_float(b).is_none() {
+        return None;
+    }
+    match (to_float(a), to_float(b)) {
+        (Some(x), Some(y)) => Some((x, y)),
+        _ => { snek_error(2); None }
+    }
+}
+
+/* Slow paths of the arithmetic in --bignum mode, taken when an operand is boxed or the fast path overflows */
+#[export_name = "\x01snek_big_add"]
+pub extern "C" fn snek_big_add(a: i64, b: i64) -> i64 {
+    if let Some((x, y)) = float_operands(a, b) {
+        return alloc_float(x + y);
+    }
+    alloc_number(expect_number(a).plus(&expect_number(b)))
+}
+
+#[export_name = "\x01snek_big_sub"]
+pub extern "C" fn snek_big_sub(a: i64, b: i64) -> i64 {
+    if let Some((x, y)) = float_operands(a, b) {
+        return alloc_float(x - y);
+    }
+    alloc_number(expect_number(a).minus(&expect_number(b)))
+}
+
+#[export_name = "\x01snek_big_mul"]
+pub extern "C" fn snek_big_mul(a: i64, b: i64) -> i64 {
+    if let Some((x, y)) = float_operands(a, b) {
+        return alloc_float(x * y);
+    }
+    alloc_number(expect_number(a).times(&expect_number(b)))
+}
+
+/* Untagged -1, 0 or 1, so that the caller can compare the result with 0. A NaN has no
+   such answer, comparing it with a bignum is reported as an invalid argument. */
+#[export_name = "\x01snek_big_compare"]
+pub extern "C" fn snek_big_compare(a: i64, b: i64) -> i64 {
+    if let Some((x, y)) = float_operands(a, b) {
+        return match x.partial_cmp(&y) {
+            Some(ordering) => ordering as i64,
+            None => { snek_error(2); 0 }
+        };
+    }
+    expect_number(a).cmp(&expect_number(b)) as i64
+}
+
+/* Untagged 0 when the values are equal. Numbers only equal numbers, integers equal floats of the
+   same value, and other values are compared as they are. */
+#[export_name = "\x01snek_equal"]
+pub extern "C" fn snek_equal(a: i64, b: i64) -> i64 {
+    if let Some((x, y)) = float_operands(a, b) {
+        return (x != y) as i64;
+    }
+    match (as_number(a), as_number(b)) {
+        (Some(x), Some(y)) => (x != y) as i64,
+        (None, None) => (a != b) as i64,
+        _ => { snek_error(2); 1 }
+    }
+}
+
+#[export_name = "\x01snek_is_num"]
+pub extern "C" fn snek_is_num(val: i64) -> i64 {
+    if as_number(val).is_some() || as_float(val).is_some() { 3 } else { 1 }
+}
+
+#[export_name = "\x01snek_box_float"]
+pub extern "C" fn snek_box_float(bits: i64) -> i64 {
+    alloc_float(f64::from_bits(bits as u64))
+}
+
+/* Fields start out as nothing, the compiled constructor stores the values right after */
+#[export_name = "\x01snek_alloc_struct"]
+pub extern "C" fn snek_alloc_struct(id: i64, field_count: i64) -> i64 {
+    let mut words = vec![STRUCT_KIND, id];
+    words.extend(std::iter::repeat(7).take(field_count as usize));
+    alloc_object(words)
+}
+
+/* Elements are filled in by the compiled code right after the allocation */
+#[export_name = "\x01snek_alloc_vector"]
+pub extern "C" fn snek_alloc_vector(len: i64) -> i64 {
+    let mut words = vec![VECTOR_KIND, len];
+    words.extend(std::iter::repeat(7).take(len as usize));
+    alloc_object(words)
+}
+
+#[export_name = "\x01snek_vector_length"]
+pub extern "C" fn snek_vector_length(v: i64) -> i64 {
+    match as_vector(v) {
+        Some((len, _)) => len << 1,
+        None => { snek_error(2); 1 }
+    }
+}
+
+#[export_name = "\x01snek_vector_ref"]
+pub extern "C" fn snek_vector_ref(v: i64, index: i64) -> i64 {
+    let (len, elements) = match as_vector(v) {
+        Some(vector) => vector,
+        None => { snek_error(2); return 1; }
+    };
+    if index & 1 != 0 {
+        snek_error(2);
+        return 1;
+    }
+    let index = index >> 1;
+    if index < 0 || index >= len {
+        snek_error(7);
+        return 1;
+    }
+    unsafe { *elements.add(index as usize) }
+}
+
+#[export_name = "\x01snek_read_num"]
+pub extern "C" fn snek_read_num() -> i64 {
+    match read_line() {
+        Some(line) => match parse_arg(&line) {
+            Ok(v) if v & 1 == 0 => v,
+            _ => { snek_error(6); 1 }
         },
-        2 => {
-            eprintln!("Runtime error: invalid argument");
-            std::process::exit(2);
-        }
-        _ => {
+        None => { snek_error(5); 1 }
+    }
+}
+
+#[export_name = "\x01snek_read_bool"]
+pub extern "C" fn snek_read_bool() -> i64 {
+    match read_line() {
+        Some(line) => match line.trim() {
+            "true" => 3,
+            "false" => 1,
+            _ => { snek_error(6); 1 }
+        },
+        None => { snek_error(5); 1 }
+    }
+}
+
+fn error_message(err_code: i64) -> Option<&'static str> {
+    match err_code {
+        1 => Some("overflow"),
+        2 => Some("invalid argument"),
+        3 => Some("division by zero"),
+        4 => Some("argument index out of range"),
+        5 => Some("end of input"),
+        6 => Some("invalid input"),
+        7 => Some("index out of range"),
+        8 => Some("wrong struct"),
+        9 => Some("no match"),
+        11 => Some("assertion failed"),
+        _ => None,
+    }
+}
+
+/* Inside a try the error is raised as an error value carrying the code, otherwise the process exits with it */
+#[export_name = "\x01snek_error"]
+pub fn snek_error(err_code: i8) {
+    let message = match error_message(err_code as i64) {
+        Some(message) => message,
+        None => {
             eprintln!("snek_error called with code = {}", err_code);
+            return;
+        }
+    };
+    if HANDLER.with(|h| h.get()) != 0 {
+        snek_raise(alloc_object(vec![ERROR_KIND, err_code as i64]));
+    }
+    eprintln!("Runtime error: {}", message);
+    std::process::exit(err_code as i32);
+}
+
+thread_local! {
+    /* Address of the handler frame of the innermost running try, 0 outside of any try. A frame is four
+       stack slots of the function containing the try, from the frame address downwards: the enclosing
+       frame, the stack pointer and input to restore, and the address of the catch code. */
+    static HANDLER: Cell<i64> = const { Cell::new(0) };
+}
+
+#[export_name = "\x01snek_push_handler"]
+pub extern "C" fn snek_push_handler(frame: i64) -> i64 {
+    HANDLER.with(|h| unsafe {
+        *(frame as *mut i64) = h.get();
+        h.set(frame);
+    });
+    7
+}
+
+/* Leaves the innermost try, the value passes through so that the result of the body survives the call */
+#[export_name = "\x01snek_pop_handler"]
+pub extern "C" fn snek_pop_handler(value: i64) -> i64 {
+    HANDLER.with(|h| h.set(unsafe { *(h.get() as *const i64) }));
+    value
+}
+
+/* Unwinds to the catch of the innermost try with the value in rax. Frames of the Viva functions
+   and of the runtime in between are dropped without running any cleanup. */
+#[export_name = "\x01snek_raise"]
+pub extern "C" fn snek_raise(value: i64) -> ! {
+    let frame = HANDLER.with(|h| h.get()) as *const i64;
+    if frame.is_null() {
+        eprintln!("Runtime error: uncaught exception {}", format_value(value));
+        std::process::exit(10);
+    }
+    unsafe {
+        HANDLER.with(|h| h.set(*frame));
+        std::arch::asm!(
+            "mov rsp, {stack}",
+            "jmp {catch}",
+            stack = in(reg) *frame.sub(1),
+            catch = in(reg) *frame.sub(3),
+            in("rdi") *frame.sub(2),
+            in("rax") value,
+            options(noreturn),
+        );
+    }
+}
+
+/* A failed assertion outside of any try reports its message, inside one it is raised like any runtime error */
+#[export_name = "\x01snek_assert_failed"]
+pub extern "C" fn snek_assert_failed(message: i64) -> i64 {
+    if HANDLER.with(|h| h.get()) == 0 {
+        eprintln!("Runtime error: assertion failed: {}", expect_string(message));
+        std::process::exit(11);
+    }
+    snek_error(11);
+    1
+}
+
+#[export_name = "\x01snek_error_code"]
+pub extern "C" fn snek_error_code(val: i64) -> i64 {
+    match as_error(val) {
+        Some(code) => code << 1,
+        None => { snek_error(2); 1 }
+    }
+}
+
+thread_local! {
+    /* Tagged values of the program arguments, set by the -e and -g modes of the cli and by Repl::set_inputs */
+    static ARGS: RefCell<Vec<i64>> = const { RefCell::new(Vec::new()) };
+}
+
+thread_local! {
+    /* Lines given to a REPL session as stdin. When it is not set the process stdin is read instead, as the cli does. */
+    static STDIN: RefCell<Option<VecDeque<String>>> = const { RefCell::new(None) };
+}
+
+pub fn set_stdin(lines: Option<VecDeque<String>>) {
+    STDIN.with(|s| *s.borrow_mut() = lines);
+}
+
+/* Hands the unread lines back so that they can be installed again for the next run */
+pub fn take_stdin() -> Option<VecDeque<String>> {
+    STDIN.with(|s| s.borrow_mut().take())
+}
+
+fn read_line() -> Option<String> {
+    let buffered = STDIN.with(|s| s.borrow_mut().as_mut().map(|lines| lines.pop_front()));
+    match buffered {
+        Some(line) => line,
+        None => {
+            let mut line = String::new();
+            match std::io::stdin().lock().read_line(&mut line) {
+                Ok(0) | Err(_) => None,
+                Ok(_) => Some(line),
+            }
         }
     }
-}
\ No newline at end of file
+}
+
+pub fn set_args(args: Vec<i64>) {
+    ARGS.with(|a| *a.borrow_mut() = args);
+}
+
+/* The value of input: the first argument, or false when there are none */
+pub fn snek_input() -> i64 {
+    ARGS.with(|a| a.borrow().first().copied().unwrap_or(1))
+}
+
+pub fn parse_arg(s: &str) -> std::io::Result<i64> {
+    match s.trim() {
+        "true" => Ok(3),
+        "false" => Ok(1),
+        n => match n.parse::<i64>() {
+            Ok(v) if (-(1 << 62)..(1 << 62)).contains(&v) => Ok(v << 1),
+            _ => Err(std::io::Error::other(format!("Invalid input: {}", s))),
+        },
+    }
+}
+
+#[export_name = "\x01snek_arg"]
+pub extern "C" fn snek_arg(index: i64) -> i64 {
+    if index & 1 != 0 {
+        snek_error(2);
+        return 1;
+    }
+    /* The arguments are released before reporting an error, a surrounding try continues running */
+    let value = usize::try_from(index >> 1).ok().and_then(|i| ARGS.with(|a| a.borrow().get(i).copied()));
+    match value {
+        Some(v) => v,
+        None => { snek_error(4); 1 }
+    }
+}
+
+#[export_name = "\x01snek_argc"]
+pub extern "C" fn snek_argc() -> i64 {
+    ARGS.with(|a| (a.borrow().len() as i64) << 1)
+}
@@ -0,0 +1,269 @@
+use crate::expressions::{Expr, Defenition, Program};
+
+/* Walking the tree of a program. A pass overrides the methods for the nodes it cares about and calls the
+   matching walk function to go on into the children, so the passes don't each spell out every variant of
+   Expr. Defaults of a signature are literals and are not walked. */
+
+pub trait Visitor {
+    fn visit_expr(&mut self, e: &Expr) {
+        walk_expr(self, e);
+    }
+
+    fn visit_defenition(&mut self, def: &Defenition) {
+        walk_defenition(self, def);
+    }
+
+    fn visit_program(&mut self, prog: &Program) {
+        walk_program(self, prog);
+    }
+}
+
+pub trait VisitorMut {
+    fn visit_expr_mut(&mut self, e: &mut Expr) {
+        walk_expr_mut(self, e);
+    }
+
+    fn visit_defenition_mut(&mut self, def: &mut Defenition) {
+        walk_defenition_mut(self, def);
+    }
+
+    fn visit_program_mut(&mut self, prog: &mut Program) {
+        walk_program_mut(self, prog);
+    }
+}
+
+/* Rebuilds the tree from the nodes the methods return, the defaults give back the same tree */
+pub trait Fold {
+    fn fold_expr(&mut self, e: Expr) -> Expr {
+        fold_expr_children(self, e)
+    }
+
+    fn fold_defenition(&mut self, def: Defenition) -> Defenition {
+        fold_defenition_children(self, def)
+    }
+
+    fn fold_program(&mut self, prog: Program) -> Program {
+        fold_program_children(self, prog)
+    }
+}
+
+/* Visits the direct children of the expression, in the order they are evaluated */
+pub fn walk_expr<V: Visitor + ?Sized>(v: &mut V, e: &Expr) {
+    match e {
+        Expr::Number(_) | Expr::Boolean(_) | Expr::Nothing | Expr::Argc | Expr::ReadNum | Expr::ReadBool | Expr::Str(_) | Expr::Char(_) | Expr::Float(_) | Expr::Id(_) | Expr::Continue(_) => {},
+        Expr::Let(bindings, body) => {
+            for (_, value) in bindings {
+                v.visit_expr(value);
+            }
+            v.visit_expr(body);
+        },
+        Expr::LetRec(funs, body) => {
+            for def in funs {
+                v.visit_defenition(def);
+            }
+            v.visit_expr(body);
+        },
+        Expr::UnOp(_, e) | Expr::Loop(_, e) | Expr::Break(_, e) | Expr::Set(_, e) | Expr::GetField(_, _, e) | Expr::IsStruct(_, e) | Expr::Raise(e) | Expr::Assert(e, _) => v.visit_expr(e),
+        Expr::BinOp(_, e1, e2) | Expr::When(e1, e2) | Expr::Unless(e1, e2) | Expr::SetField(e1, _, e2) | Expr::Try(e1, _, e2) => {
+            v.visit_expr(e1);
+            v.visit_expr(e2);
+        },
+        Expr::If(e1, e2, e3) | Expr::Substring(e1, e2, e3) => {
+            v.visit_expr(e1);
+            v.visit_expr(e2);
+            v.visit_expr(e3);
+        },
+        Expr::And(es) | Expr::Or(es) | Expr::Block(es) | Expr::MakeStruct(_, es) | Expr::MakeVector(es) | Expr::Call(_, es, _) => {
+            for e in es {
+                v.visit_expr(e);
+            }
+        },
+        Expr::Cond(arms, default) => {
+            for (test, body) in arms {
+                v.visit_expr(test);
+                v.visit_expr(body);
+            }
+            if let Some(d) = default {
+                v.visit_expr(d);
+            }
+        },
+        Expr::Case(scrutinee, arms, default) => {
+            v.visit_expr(scrutinee);
+            for (labels, body) in arms {
+                for label in labels {
+                    v.visit_expr(label);
+                }
+                v.visit_expr(body);
+            }
+            if let Some(d) = default {
+                v.visit_expr(d);
+            }
+        },
+        Expr::Match(scrutinee, arms, _) => {
+            v.visit_expr(scrutinee);
+            for (_, body) in arms {
+                v.visit_expr(body);
+            }
+        },
+    }
+}
+
+pub fn walk_defenition<V: Visitor + ?Sized>(v: &mut V, def: &Defenition) {
+    match def {
+        Defenition::Fun(_, _, _, body) => v.visit_expr(body),
+        Defenition::Struct(_, _) | Defenition::Enum(_, _) => {},
+    }
+}
+
+pub fn walk_program<V: Visitor + ?Sized>(v: &mut V, prog: &Program) {
+    for def in &prog.defs {
+        v.visit_defenition(def);
+    }
+    for (_, e) in &prog.globals {
+        v.visit_expr(e);
+    }
+    v.visit_expr(&prog.main);
+}
+
+pub fn walk_expr_mut<V: VisitorMut + ?Sized>(v: &mut V, e: &mut Expr) {
+    match e {
+        Expr::Number(_) | Expr::Boolean(_) | Expr::Nothing | Expr::Argc | Expr::ReadNum | Expr::ReadBool | Expr::Str(_) | Expr::Char(_) | Expr::Float(_) | Expr::Id(_) | Expr::Continue(_) => {},
+        Expr::Let(bindings, body) => {
+            for (_, value) in bindings {
+                v.visit_expr_mut(value);
+            }
+            v.visit_expr_mut(body);
+        },
+        Expr::LetRec(funs, body) => {
+            for def in funs {
+                v.visit_defenition_mut(def);
+            }
+            v.visit_expr_mut(body);
+        },
+        Expr::UnOp(_, e) | Expr::Loop(_, e) | Expr::Break(_, e) | Expr::Set(_, e) | Expr::GetField(_, _, e) | Expr::IsStruct(_, e) | Expr::Raise(e) | Expr::Assert(e, _) => v.visit_expr_mut(e),
+        Expr::BinOp(_, e1, e2) | Expr::When(e1, e2) | Expr::Unless(e1, e2) | Expr::SetField(e1, _, e2) | Expr::Try(e1, _, e2) => {
+            v.visit_expr_mut(e1);
+            v.visit_expr_mut(e2);
+        },
+        Expr::If(e1, e2, e3) | Expr::Substring(e1, e2, e3) => {
+            v.visit_expr_mut(e1);
+            v.visit_expr_mut(e2);
+            v.visit_expr_mut(e3);
+        },
+        Expr::And(es) | Expr::Or(es) | Expr::Block(es) | Expr::MakeStruct(_, es) | Expr::MakeVector(es) | Expr::Call(_, es, _) => {
+            for e in es {
+                v.visit_expr_mut(e);
+            }
+        },
+        Expr::Cond(arms, default) => {
+            for (test, body) in arms {
+                v.visit_expr_mut(test);
+                v.visit_expr_mut(body);
+            }
+            if let Some(d) = default {
+                v.visit_expr_mut(d);
+            }
+        },
+        Expr::Case(scrutinee, arms, default) => {
+            v.visit_expr_mut(scrutinee);
+            for (labels, body) in arms {
+                for label in labels {
+                    v.visit_expr_mut(label);
+                }
+                v.visit_expr_mut(body);
+            }
+            if let Some(d) = default {
+                v.visit_expr_mut(d);
+            }
+        },
+        Expr::Match(scrutinee, arms, _) => {
+            v.visit_expr_mut(scrutinee);
+            for (_, body) in arms {
+                v.visit_expr_mut(body);
+            }
+        },
+    }
+}
+
+pub fn walk_defenition_mut<V: VisitorMut + ?Sized>(v: &mut V, def: &mut Defenition) {
+    match def {
+        Defenition::Fun(_, _, _, body) => v.visit_expr_mut(body),
+        Defenition::Struct(_, _) | Defenition::Enum(_, _) => {},
+    }
+}
+
+pub fn walk_program_mut<V: VisitorMut + ?Sized>(v: &mut V, prog: &mut Program) {
+    for def in &mut prog.defs {
+        v.visit_defenition_mut(def);
+    }
+    for (_, e) in &mut prog.globals {
+        v.visit_expr_mut(e);
+    }
+    v.visit_expr_mut(&mut prog.main);
+}
+
+/* The expression with each direct child replaced by its fold */
+pub fn fold_expr_children<F: Fold + ?Sized>(f: &mut F, e: Expr) -> Expr {
+    let mut fold = |e: Box<Expr>| Box::new(f.fold_expr(*e));
+    match e {
+        Expr::Number(_) | Expr::Boolean(_) | Expr::Nothing | Expr::Argc | Expr::ReadNum | Expr::ReadBool | Expr::Str(_) | Expr::Char(_) | Expr::Float(_) | Expr::Id(_) | Expr::Continue(_) => e,
+        Expr::Let(bindings, body) => {
+            let bindings = bindings.into_iter().map(|(name, value)| (name, f.fold_expr(value))).collect();
+            Expr::Let(bindings, Box::new(f.fold_expr(*body)))
+        },
+        Expr::LetRec(funs, body) => {
+            let funs = funs.into_iter().map(|def| f.fold_defenition(def)).collect();
+            Expr::LetRec(funs, Box::new(f.fold_expr(*body)))
+        },
+        Expr::UnOp(op, e) => Expr::UnOp(op, fold(e)),
+        Expr::Loop(label, e) => Expr::Loop(label, fold(e)),
+        Expr::Break(label, e) => Expr::Break(label, fold(e)),
+        Expr::Set(name, e) => Expr::Set(name, fold(e)),
+        Expr::GetField(id, index, e) => Expr::GetField(id, index, fold(e)),
+        Expr::IsStruct(id, e) => Expr::IsStruct(id, fold(e)),
+        Expr::Raise(e) => Expr::Raise(fold(e)),
+        Expr::Assert(e, message) => Expr::Assert(fold(e), message),
+        Expr::BinOp(op, e1, e2) => Expr::BinOp(op, fold(e1), fold(e2)),
+        Expr::When(e1, e2) => Expr::When(fold(e1), fold(e2)),
+        Expr::Unless(e1, e2) => Expr::Unless(fold(e1), fold(e2)),
+        Expr::SetField(e1, field, e2) => Expr::SetField(fold(e1), field, fold(e2)),
+        Expr::Try(body, name, handler) => Expr::Try(fold(body), name, fold(handler)),
+        Expr::If(e1, e2, e3) => Expr::If(fold(e1), fold(e2), fold(e3)),
+        Expr::Substring(e1, e2, e3) => Expr::Substring(fold(e1), fold(e2), fold(e3)),
+        Expr::And(es) => Expr::And(es.into_iter().map(|e| f.fold_expr(e)).collect()),
+        Expr::Or(es) => Expr::Or(es.into_iter().map(|e| f.fold_expr(e)).collect()),
+        Expr::Block(es) => Expr::Block(es.into_iter().map(|e| f.fold_expr(e)).collect()),
+        Expr::MakeStruct(id, es) => Expr::MakeStruct(id, es.into_iter().map(|e| f.fold_expr(e)).collect()),
+        Expr::MakeVector(es) => Expr::MakeVector(es.into_iter().map(|e| f.fold_expr(e)).collect()),
+        Expr::Call(name, args, span) => Expr::Call(name, args.into_iter().map(|e| f.fold_expr(e)).collect(), span),
+        Expr::Cond(arms, default) => {
+            let arms = arms.into_iter().map(|(test, body)| (f.fold_expr(test), f.fold_expr(body))).collect();
+            Expr::Cond(arms, default.map(|d| Box::new(f.fold_expr(*d))))
+        },
+        Expr::Case(scrutinee, arms, default) => {
+            let scrutinee = Box::new(f.fold_expr(*scrutinee));
+            let arms = arms.into_iter()
+                .map(|(labels, body)| (labels.into_iter().map(|l| f.fold_expr(l)).collect(), f.fold_expr(body)))
+                .collect();
+            Expr::Case(scrutinee, arms, default.map(|d| Box::new(f.fold_expr(*d))))
+        },
+        Expr::Match(scrutinee, arms, span) => {
+            let scrutinee = Box::new(f.fold_expr(*scrutinee));
+            Expr::Match(scrutinee, arms.into_iter().map(|(pattern, body)| (pattern, f.fold_expr(body))).collect(), span)
+        },
+    }
+}
+
+pub fn fold_defenition_children<F: Fold + ?Sized>(f: &mut F, def: Defenition) -> Defenition {
+    match def {
+        Defenition::Fun(name, params, signature, body) => Defenition::Fun(name, params, signature, Box::new(f.fold_expr(*body))),
+        Defenition::Struct(_, _) | Defenition::Enum(_, _) => def,
+    }
+}
+
+pub fn fold_program_children<F: Fold + ?Sized>(f: &mut F, prog: Program) -> Program {
+    let defs = prog.defs.into_iter().map(|def| f.fold_defenition(def)).collect();
+    let globals = prog.globals.into_iter().map(|(name, e)| (name, f.fold_expr(e))).collect();
+    let main = f.fold_expr(prog.main);
+    Program { defs, globals, main, declarations: prog.declarations, warnings: prog.warnings }
+}
@@ -0,0 +1,52 @@
+mod common;
+
+use common::eval;
+use viva::Repl;
+
+fn repl_with(inputs: &[&str]) -> Repl {
+    let mut repl = Repl::new().unwrap();
+    repl.set_inputs(&inputs.iter().map(|s| s.to_string()).collect::<Vec<String>>()).unwrap();
+    repl
+}
+
+#[test]
+fn arguments_are_read_by_index() {
+    let mut repl = repl_with(&["5", "true", "-12"]);
+    assert_eq!(eval(&mut repl, "(argc)"), "3");
+    assert_eq!(eval(&mut repl, "(arg 0)"), "5");
+    assert_eq!(eval(&mut repl, "(arg 1)"), "true");
+    assert_eq!(eval(&mut repl, "(+ (arg 0) (arg 2))"), "-7");
+    assert_eq!(eval(&mut repl, "input"), "5");
+}
+
+#[test]
+fn no_arguments() {
+    let mut repl = Repl::new().unwrap();
+    assert_eq!(eval(&mut repl, "(argc)"), "0");
+    assert_eq!(eval(&mut repl, "input"), "false");
+}
+
+/* Code 4 for an index out of range, 2 for an index that is not a number */
+#[test]
+fn wrong_indices_are_errors() {
+    let mut repl = repl_with(&["1", "2"]);
+    assert_eq!(eval(&mut repl, "(try (arg 2) (catch e (error-code e)))"), "4");
+    assert_eq!(eval(&mut repl, "(try (arg -1) (catch e (error-code e)))"), "4");
+    assert_eq!(eval(&mut repl, "(try (arg true) (catch e (error-code e)))"), "2");
+}
+
+/* The inputs apply to the following inputs until they are set again */
+#[test]
+fn inputs_are_replaced() {
+    let mut repl = repl_with(&["1"]);
+    assert_eq!(eval(&mut repl, "(argc)"), "1");
+    repl.set_inputs(&["7".to_string(), "8".to_string()]).unwrap();
+    assert_eq!(eval(&mut repl, "(arg 1)"), "8");
+}
+
+#[test]
+fn invalid_inputs_are_rejected() {
+    let mut repl = Repl::new().unwrap();
+    assert_eq!(repl.set_inputs(&["abc".to_string()]).unwrap_err().to_string(), "Invalid input: abc");
+    assert!(repl.set_inputs(&["4611686018427387904".to_string()]).is_err());
+}
@@ -0,0 +1,72 @@
+mod common;
+
+use common::eval;
+use viva::{Options, Repl};
+
+fn check(options: Options, cases: &[(&str, &str)]) {
+    let mut repl = Repl::with_options(options).unwrap();
+    eval(&mut repl, "(fun (fact n) (if (= n 0) 1 (* n (fact (sub1 n)))))");
+    for (source, expected) in cases {
+        assert_eq!(eval(&mut repl, source), *expected, "{}", source);
+    }
+}
+
+fn bignum() -> Options {
+    Options { bignum: true, ..Options::default() }
+}
+
+#[test]
+fn overflow_promotes() {
+    check(bignum(), &[
+        ("(fact 25)", "15511210043330985984000000"),
+        ("(- 0 (fact 25))", "-15511210043330985984000000"),
+        ("(+ 4611686018427387903 1)", "4611686018427387904"),
+        ("(- -4611686018427387904 1)", "-4611686018427387905"),
+        ("(add1 4611686018427387903)", "4611686018427387904"),
+        ("(* (fact 20) (- 0 (fact 20)))", "-5919012181389927685417441689600000000"),
+    ]);
+}
+
+/* A result that fits again is a plain integer */
+#[test]
+fn results_shrink_back() {
+    check(bignum(), &[("(- (+ 4611686018427387903 1) 1)", "4611686018427387903"), ("(isnum (- (fact 25) (fact 25)))", "true")]);
+}
+
+#[test]
+fn fibonacci() {
+    check(bignum(), &[
+        ("(fun (fib n) (let ((a 0) (b 1)) (block (for (i 0 n) (let ((t b)) (block (set! b (+ a b)) (set! a t)))) a)))", ""),
+        ("(fib 100)", "354224848179261915075"),
+    ]);
+}
+
+#[test]
+fn comparisons_across_representations() {
+    check(bignum(), &[
+        ("(< (fact 30) (fact 31))", "true"),
+        ("(= (fact 25) (fact 25))", "true"),
+        ("(> (fact 22) 5)", "true"),
+        ("(< 5 (- 0 (fact 22)))", "false"),
+        ("(isnum (fact 25))", "true"),
+    ]);
+}
+
+/* Only + - * promote, the other operators reject bignums */
+#[test]
+fn bignum_errors() {
+    check(bignum(), &[
+        ("(try (/ (fact 25) 2) (catch e (error-code e)))", "2"),
+        ("(try (bit-and (fact 25) 1) (catch e (error-code e)))", "2"),
+        ("(try (+ (fact 25) true) (catch e (error-code e)))", "2"),
+    ]);
+}
+
+/* Without --bignum overflow stays an error */
+#[test]
+fn overflow_without_bignum() {
+    check(Options::default(), &[
+        ("(try (+ 4611686018427387903 1) (catch e (error-code e)))", "1"),
+        ("(try (fact 25) (catch e (error-code e)))", "1"),
+    ]);
+}
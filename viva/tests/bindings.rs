@@ -0,0 +1,52 @@
+mod common;
+
+use common::{check, eval};
+use viva::Repl;
+
+#[test]
+fn let_star_is_sequential() {
+    check(&[
+        ("(let* ((a 1) (b (+ a 1)) (c (* b 3))) c)", "6"),
+        ("(let* ((x 1) (x (+ x 10))) x)", "11"),
+        ("(let* () 1)", "1"),
+    ]);
+}
+
+/* let keeps rejecting a name bound twice, let* is there for that */
+#[test]
+fn let_rejects_duplicates() {
+    assert_eq!(Repl::new().unwrap().feed("(let ((x 1) (x 2)) x)").unwrap_err().to_string(), "Duplicate binding");
+    assert_eq!(eval(&mut Repl::new().unwrap(), "(let* ((let 1)) 1)"), "Invalid: parse error");
+}
+
+#[test]
+fn letrec_functions() {
+    check(&[
+        ("(letrec ((fun (fact n) (if (= n 0) 1 (* n (fact (sub1 n)))))) (fact 10))", "3628800"),
+        ("(letrec ((fun (ev n) (if (= n 0) true (od (sub1 n)))) (fun (od n) (if (= n 0) false (ev (sub1 n))))) (ev 10))", "true"),
+        ("(letrec () 1)", "1"),
+    ]);
+}
+
+/* The variables a local function uses are passed to its lifted definition */
+#[test]
+fn letrec_captures() {
+    check(&[
+        ("(let ((k 5)) (letrec ((fun (addk n) (+ n k))) (addk 1)))", "6"),
+        ("(let ((a 2) (b 3)) (letrec ((fun (f n) (if (= n 0) b (+ a (f (sub1 n)))))) (f 4)))", "11"),
+    ]);
+}
+
+/* A local function doesn't become a global one */
+#[test]
+fn letrec_is_local() {
+    let mut repl = Repl::new().unwrap();
+    assert_eq!(eval(&mut repl, "(letrec ((fun (helper x) (* x 2))) (helper 4))"), "8");
+    assert!(eval(&mut repl, "(helper 4)").starts_with("Error: unknown function `helper`"));
+}
+
+#[test]
+fn letrec_errors() {
+    assert_eq!(eval(&mut Repl::new().unwrap(), "(letrec ((fun (f x) x) (fun (f y) y)) (f 1))"), "Invalid: parse error");
+    assert!(eval(&mut Repl::new().unwrap(), "(letrec ((fun (f x) x)) (f 1 2))").starts_with("Error: f expects 1 argument, got 2"));
+}
@@ -0,0 +1,54 @@
+mod common;
+
+use common::{check, error_code};
+
+#[test]
+fn not() {
+    check(&[("(not true)", "false"), ("(not false)", "true"), ("(not (not true))", "true"), ("(not (< 2 1))", "true")]);
+}
+
+#[test]
+fn and_or() {
+    check(&[
+        ("(and)", "true"),
+        ("(or)", "false"),
+        ("(and true true false)", "false"),
+        ("(and true (< 1 2))", "true"),
+        ("(or false false true)", "true"),
+        ("(or false (> 1 2))", "false"),
+        ("(if (not (and true (or false true))) 1 2)", "2"),
+    ]);
+}
+
+/* Only true and false are accepted, numbers, strings, characters and nothing are invalid arguments */
+#[test]
+fn non_boolean_operands() {
+    check(&[
+        (&error_code("(not 0)"), "2"),
+        (&error_code("(not 1)"), "2"),
+        (&error_code("(not \"abc\")"), "2"),
+        (&error_code("(not #\\a)"), "2"),
+        (&error_code("(not (block))"), "2"),
+        (&error_code("(and true 5)"), "2"),
+        (&error_code("(or false \"x\")"), "2"),
+        (&error_code("(if (not (vector 1)) 1 2)"), "2"),
+    ]);
+}
+
+#[test]
+fn short_circuit() {
+    check(&[
+        ("(define hits 0)", ""),
+        ("(and false (block (set! hits 1) true))", "false"),
+        ("hits", "0"),
+        ("(or true (block (set! hits 2) true))", "true"),
+        ("hits", "0"),
+        ("(and true (block (set! hits 3) false))", "false"),
+        ("hits", "3"),
+        ("(or false (block (set! hits 4) true))", "true"),
+        ("hits", "4"),
+        /* The operand that decides is not checked further, the rest is not evaluated */
+        ("(and false 5)", "false"),
+        ("(or true \"x\")", "true"),
+    ]);
+}
@@ -0,0 +1,81 @@
+mod common;
+
+use common::{check, eval};
+use viva::compile_repl::run_prog;
+use viva::context::Options;
+use viva::parse::{parse_prog, parse_repl_expr, read, read_sexp, DefNames};
+use viva::runtime::format_value;
+use viva::Repl;
+
+/* The first line of a rendered report */
+fn report(repl: &mut Repl, source: &str) -> String {
+    eval(repl, source).lines().next().unwrap_or_default().to_string()
+}
+
+fn parse_error(source: &str) -> String {
+    parse_repl_expr(&read(source).unwrap()[0].0, &DefNames::default()).unwrap_err().to_string()
+}
+
+#[test]
+fn calls_are_checked_against_the_parameters() {
+    let mut repl = Repl::new().unwrap();
+    eval(&mut repl, "(fun (two a b) (+ a b))");
+    eval(&mut repl, "(fun (some a (b 10) (c \"s\")) a)");
+    eval(&mut repl, "(fun (many a &rest xs) a)");
+    assert_eq!(report(&mut repl, "(two 1)"), "Error: two expects 2 arguments, got 1");
+    assert_eq!(report(&mut repl, "(two 1 2 3)"), "Error: two expects 2 arguments, got 3");
+    assert_eq!(report(&mut repl, "(some)"), "Error: some expects 1 to 3 arguments, got 0");
+    assert_eq!(report(&mut repl, "(some 1 2 3 4)"), "Error: some expects 1 to 3 arguments, got 4");
+    assert_eq!(report(&mut repl, "(many)"), "Error: many expects at least 1 argument, got 0");
+    assert_eq!(eval(&mut repl, "(two 1 2)"), "3");
+}
+
+#[test]
+fn defaults_are_filled_in_by_the_caller() {
+    check(&[
+        ("(fun (f a (b 10) (c \"s\")) (vector a b c))", ""),
+        ("(f 1)", "[1, 10, s]"),
+        ("(f 1 2)", "[1, 2, s]"),
+        ("(f 1 2 3)", "[1, 2, 3]"),
+        ("(fun (g x (y (block))) y)", ""),
+        ("(g 1)", "nothing"),
+        ("(g 1 2)", "2"),
+        ("(fun (h (c #\\a) (t true) (x 2.5)) (vector c t x))", ""),
+        ("(h)", "[a, true, 2.5]"),
+    ]);
+}
+
+#[test]
+fn rest_packs_the_other_arguments() {
+    check(&[
+        ("(fun (g a &rest xs) (vector a xs))", ""),
+        ("(g 1)", "[1, []]"),
+        ("(g 1 2 3)", "[1, [2, 3]]"),
+        ("(fun (k &rest xs) (vector-length xs))", ""),
+        ("(k)", "0"),
+        ("(k 1 2 3 4)", "4"),
+        ("(k (vector 1 2))", "1"),
+        ("(fun (h (a 1) &rest xs) (vector a xs))", ""),
+        ("(h)", "[1, []]"),
+        ("(h 5 6)", "[5, [6]]"),
+    ]);
+}
+
+#[test]
+fn programs_check_calls_of_functions_defined_later() {
+    let source = "((fun (main) (+ (later 1) (later 1 2 3))) (fun (later a (b 10) &rest xs) (+ a (+ b (vector-length xs)))) (main))";
+    let program = parse_prog(&read_sexp(source).unwrap()).unwrap();
+    assert_eq!(format_value(run_prog(&program, Options::default()).unwrap()), "15");
+
+    let err = parse_prog(&read_sexp("((fun (f a) a) (f))").unwrap()).unwrap_err().to_string();
+    assert!(err.starts_with("Error: f expects 1 argument, got 0"), "{}", err);
+}
+
+#[test]
+fn wrong_parameter_lists() {
+    assert_eq!(parse_error("(fun (f x (y (+ 1 2))) y)"), "Invalid: parse error: the default of a parameter should be a literal.");
+    assert_eq!(parse_error("(fun (f (y 1) x) y)"), "Invalid: parse error: parameter x without a default follows one with a default.");
+    assert_eq!(parse_error("(fun (f &rest) 1)"), "Invalid: parse error: &rest should be followed by the name of the last parameter.");
+    assert_eq!(parse_error("(fun (f &rest a b) 1)"), "Invalid: parse error: &rest should be followed by the name of the last parameter.");
+    assert_eq!(parse_error("(fun (f (y)) y)"), "Invalid: parse error: a parameter with a default should be (name literal).");
+}
@@ -0,0 +1,22 @@
+/* Helpers shared by the tests that feed inputs to a REPL session, not every test file uses all of them */
+#![allow(dead_code)]
+
+use viva::Repl;
+
+/* What the input printed or evaluated to, the input has to be accepted */
+pub fn eval(repl: &mut Repl, source: &str) -> String {
+    repl.feed(source).unwrap().unwrap_or_default()
+}
+
+/* Feeds the inputs to one session in order and compares what each gave */
+pub fn check(cases: &[(&str, &str)]) {
+    let mut repl = Repl::new().unwrap();
+    for (source, expected) in cases {
+        assert_eq!(eval(&mut repl, source), *expected, "{}", source);
+    }
+}
+
+/* The code of the runtime error the expression raises */
+pub fn error_code(source: &str) -> String {
+    format!("(try {} (catch e (error-code e)))", source)
+}
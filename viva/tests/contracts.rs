@@ -0,0 +1,55 @@
+mod common;
+
+use common::eval;
+use viva::context::Options;
+use viva::parse::{parse_repl_expr, read, DefNames};
+use viva::Repl;
+
+fn check(repl: &mut Repl, cases: &[(&str, &str)]) {
+    for (source, expected) in cases {
+        assert_eq!(eval(repl, source), *expected, "{}", source);
+    }
+}
+
+#[test]
+fn assertions_fail_when_they_run() {
+    check(&mut Repl::new().unwrap(), &[
+        ("(assert true \"fine\")", "nothing"),
+        ("(if false (assert false \"unreachable\") 1)", "1"),
+        ("(fun (never) (assert false \"not called\"))", ""),
+        ("(try (assert false \"always\") (catch e (error-code e)))", "11"),
+        ("(try (assert (not true) \"always\") (catch e e))", "#<error 11: assertion failed>"),
+        ("(try (never) (catch e (error-code e)))", "11"),
+        ("(let ((x 3)) (try (assert (< x 2) \"small\") (catch e (error-code e))))", "11"),
+    ]);
+}
+
+#[test]
+fn requires_and_ensures() {
+    check(&mut Repl::new().unwrap(), &[
+        ("(fun (g x) :requires (> x 0) :ensures (< result 100) (* x 10))", ""),
+        ("(g 5)", "50"),
+        ("(try (g 0) (catch e (error-code e)))", "11"),
+        ("(try (g 20) (catch e (error-code e)))", "11"),
+        ("(fun (h result) result)", ""),
+        ("(h 3)", "3"),
+    ]);
+}
+
+#[test]
+fn no_contracts_skips_the_checks() {
+    let mut repl = Repl::with_options(Options { no_contracts: true, ..Options::default() }).unwrap();
+    check(&mut repl, &[
+        ("(assert false \"off\")", "nothing"),
+        ("(fun (g x) :requires (> x 0) :ensures (< result 100) (* x 10))", ""),
+        ("(g 0)", "0"),
+        ("(g 20)", "200"),
+    ]);
+}
+
+#[test]
+fn ensures_needs_result_free() {
+    let source = "(fun (f result) :ensures (> result 0) result)";
+    let err = parse_repl_expr(&read(source).unwrap()[0].0, &DefNames::default()).unwrap_err().to_string();
+    assert_eq!(err, "Invalid: parse error: the parameter result of f hides the value its :ensures clauses check.");
+}
@@ -0,0 +1,83 @@
+use viva::analysis::suggestion;
+use viva::Repl;
+
+/* The report of the input, the session has defined (f y) before */
+fn report(source: &str) -> String {
+    let mut repl = Repl::new().unwrap();
+    repl.feed("(fun (f y) y)").unwrap();
+    match repl.feed(source) {
+        Ok(output) => output.unwrap_or_default(),
+        Err(err) => err.to_string(),
+    }
+}
+
+/* The report has the position of the underlined node and, on the line below the source, the underline */
+fn underlined(report: &str, position: &str, source_line: &str, from: usize, width: usize) {
+    assert!(report.contains(&format!("[ {} ]", position)), "{}", report);
+    let lines: Vec<&str> = report.lines().collect();
+    let at = lines.iter().position(|l| l.ends_with(source_line)).unwrap_or_else(|| panic!("{}", report));
+    let offset = lines[at].chars().count() - source_line.chars().count();
+    let underline: String = lines[at + 1].chars().skip(offset).collect();
+    let expected = format!("{}{}", " ".repeat(from - 1), "─");
+    assert!(underline.starts_with(&expected), "{}", report);
+    assert_eq!(underline.trim().chars().filter(|c| *c == '─' || *c == '┬').count(), width, "{}", report);
+}
+
+#[test]
+fn wrong_calls_are_underlined() {
+    let source = "(letrec ((fun (f y) y)) (f 1 2))";
+    let err = report(source);
+    assert!(err.starts_with("Error: f expects 1 argument, got 2"), "{}", err);
+    underlined(&err, "input:1:25", source, 25, 7);
+}
+
+#[test]
+fn every_wrong_call_is_reported() {
+    let err = report("(block (f 1)\n       (f 1 2)\n       (f))");
+    assert!(err.contains("input:2:8") && err.contains("input:3:8"), "{}", err);
+    assert!(!err.contains("input:1:"), "{}", err);
+    assert_eq!(err.matches("Error: f expects 1 argument").count(), 2, "{}", err);
+}
+
+#[test]
+fn unknown_names_are_underlined() {
+    let source = "(+ 1 (lenght 2))";
+    let err = report(source);
+    assert!(err.starts_with("Error: unknown function `lenght`"), "{}", err);
+    underlined(&err, "input:1:6", source, 6, 10);
+
+    let err = report("(struct p (x))\n(f (q 1))");
+    assert!(err.contains("input:2:4"), "{}", err);
+}
+
+#[test]
+fn a_call_a_macro_wrote_is_placed_at_the_use() {
+    let mut repl = Repl::new().unwrap();
+    repl.feed("(fun (f y) y)").unwrap();
+    repl.feed("(define-syntax pair (syntax-rules () ((_ e) (f e e))))").unwrap();
+    let err = repl.feed("(+ 1 (pair 2))").unwrap().unwrap();
+    assert!(err.starts_with("Error: f expects 1 argument, got 2") && err.contains("input:1:6"), "{}", err);
+}
+
+#[test]
+fn patterns_are_underlined() {
+    let mut repl = Repl::new().unwrap();
+    repl.feed("(enum shape (circle r) (rect w h))").unwrap();
+    let err = repl.feed("(match (circle 1)\n  ((circle r) r)\n  ((rect w) w))").unwrap().unwrap();
+    assert!(err.starts_with("Error: rect has 2 fields") && err.contains("input:3:4"), "{}", err);
+    let err = repl.feed("(match (circle 1)\n  ((circle r) r))").unwrap().unwrap();
+    assert!(err.starts_with("Error: non-exhaustive match on shape") && err.contains("input:1:1"), "{}", err);
+}
+
+#[test]
+fn suggestions_are_close_names() {
+    let names: Vec<String> = ["add1", "+", "*", "string-length", "foo", "f"].iter().map(|n| n.to_string()).collect();
+    assert_eq!(suggestion("ad1", names.iter()).map(String::as_str), Some("add1"));
+    assert_eq!(suggestion("strng-length", names.iter()).map(String::as_str), Some("string-length"));
+    assert_eq!(suggestion("fooo", names.iter()).map(String::as_str), Some("foo"));
+    assert_eq!(suggestion("++", names.iter()).map(String::as_str), Some("+"));
+    /* Operators are not suggested for names, and short names need to start the same way */
+    assert_eq!(suggestion("g", names.iter()), None);
+    assert_eq!(suggestion("xy", names.iter()), None);
+    assert_eq!(suggestion("fo", names.iter()).map(String::as_str), Some("foo"));
+}
@@ -0,0 +1,45 @@
+mod common;
+
+use common::{check, eval};
+use viva::compile_repl::run_prog;
+use viva::context::Options;
+use viva::parse::{parse_prog, read_sexp};
+use viva::runtime::format_value;
+use viva::Repl;
+
+fn run(source: &str) -> String {
+    let program = parse_prog(&read_sexp(&format!("({})", source)).unwrap()).unwrap();
+    format_value(run_prog(&program, Options::default()).unwrap())
+}
+
+#[test]
+fn matches_use_the_variants_of_earlier_inputs() {
+    check(&[
+        ("(enum shape (circle r) (rect w h))", ""),
+        ("(fun (area s) (match s ((circle r) (* 3 (* r r))) ((rect w h) (* w h))))", ""),
+        ("(area (circle 2))", "12"),
+        ("(area (rect 2 5))", "10"),
+        ("(rect 2 5)", "rect { w: 2, h: 5 }"),
+        ("(match (circle 1) ((rect _ _) 0) (_ 1))", "1"),
+    ]);
+}
+
+#[test]
+fn enums_with_the_same_variants_stay_apart() {
+    let mut first = Repl::new().unwrap();
+    let mut second = Repl::new().unwrap();
+    assert_eq!(eval(&mut first, "(enum light (on) (off))"), "");
+    assert_eq!(eval(&mut second, "(enum light (off) (on))"), "");
+    assert_eq!(eval(&mut first, "(match (on) ((on) 1) ((off) 0))"), "1");
+    assert_eq!(eval(&mut second, "(match (on) ((on) 1) ((off) 0))"), "1");
+    assert_eq!(eval(&mut second, "(try (match 5 ((on) 1) ((off) 0)) (catch e (error-code e)))"), "9");
+}
+
+#[test]
+fn functions_can_match_on_enums_declared_after_them() {
+    let source = "
+        (fun (size t) (match t ((leaf) 0) ((node l _ r) (+ 1 (+ (size l) (size r))))))
+        (enum tree (leaf) (node left value right))
+        (size (node (node (leaf) 1 (leaf)) 2 (leaf)))";
+    assert_eq!(run(source), "2");
+}
@@ -0,0 +1,60 @@
+mod common;
+
+use common::check;
+
+#[test]
+fn raised_values_reach_the_handler() {
+    check(&[
+        ("(try (raise 5) (catch e e))", "5"),
+        ("(try 1 (catch e 2))", "1"),
+        ("(try (+ 1 (raise true)) (catch e e))", "true"),
+        ("(try (raise (vector 1 2)) (catch e (vector-ref e 1)))", "2"),
+        ("(try (vector 1 (raise 3) 2) (catch e e))", "3"),
+        ("(let ((x 10)) (try (raise 1) (catch e (+ x e))))", "11"),
+        ("(let ((x (try (raise 1) (catch e (* e 2))))) (+ x 1))", "3"),
+    ]);
+}
+
+#[test]
+fn raise_unwinds_calls_and_loops() {
+    check(&[
+        ("(fun (f n) (if (= n 0) (raise \"deep\") (+ 1 (f (- n 1)))))", ""),
+        ("(try (f 50) (catch e e))", "deep"),
+        ("(fun (g) (try (f 3) (catch e 7)))", ""),
+        ("(+ (g) 1)", "8"),
+        ("(let ((v 0)) (block (try (loop (block (set! v (+ v 1)) (when (= v 3) (raise v)))) (catch e e)) v))", "3"),
+    ]);
+}
+
+#[test]
+fn handlers_nest() {
+    check(&[
+        ("(try (try (raise 1) (catch e (raise (+ e 1)))) (catch e (* e 10)))", "20"),
+        ("(try (+ 1 (try (raise 1) (catch e (+ e 1)))) (catch e 100))", "3"),
+        ("(try (raise (try (raise 7) (catch e (+ e 1)))) (catch e e))", "8"),
+        ("(block (try 1 (catch e 0)) (try (raise 2) (catch e e)))", "2"),
+        /* The inner handler is gone once its try finished, the outer one catches */
+        ("(try (block (try 1 (catch e 0)) (raise 2)) (catch e (+ e 10)))", "12"),
+    ]);
+}
+
+#[test]
+fn runtime_errors_are_error_values() {
+    check(&[
+        ("(try (add1 true) (catch e e))", "#<error 2: invalid argument>"),
+        ("(try (/ 4 0) (catch e e))", "#<error 3: division by zero>"),
+        ("(try (add1 4611686018427387903) (catch e (error-code e)))", "1"),
+        ("(try (vector-ref (vector 1 2) 5) (catch e (error-code e)))", "7"),
+        ("(try (try (/ 1 0) (catch e (error-code e))) (catch e 99))", "3"),
+        ("(let ((ok false)) (try (assert ok \"boom\") (catch e e)))", "#<error 11: assertion failed>"),
+    ]);
+}
+
+#[test]
+fn error_code_only_takes_error_values() {
+    check(&[
+        ("(try (try (raise 5) (catch e (error-code e))) (catch e (error-code e)))", "2"),
+        ("(try (error-code (raise 4)) (catch e e))", "4"),
+        ("(try (error-code true) (catch e (error-code e)))", "2"),
+    ]);
+}
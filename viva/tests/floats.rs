@@ -0,0 +1,56 @@
+mod common;
+
+use common::{check, eval};
+use viva::{Options, Repl};
+
+#[test]
+fn literals() {
+    check(&[("2.5", "2.5"), ("-0.5", "-0.5"), ("1e-7", "1e-7"), ("1e20", "1e20"), ("2.5e2", "250.0")]);
+}
+
+#[test]
+fn arithmetic() {
+    check(&[
+        ("(+ 1.5 2.25)", "3.75"),
+        ("(- 0.1 0.3)", "-0.19999999999999998"),
+        ("(* 2.0 1.5)", "3.0"),
+        ("(/ 7.0 2.0)", "3.5"),
+        ("(/ 1.0 0)", "inf"),
+    ]);
+}
+
+/* An integer operand is converted when the other one is a float */
+#[test]
+fn mixed_arithmetic() {
+    check(&[("(+ 1 0.5)", "1.5"), ("(* 2 1.5)", "3.0"), ("(- 1.0 3)", "-2.0"), ("(/ 7 2.0)", "3.5"), ("(add1 1.5)", "2.5")]);
+}
+
+#[test]
+fn comparisons() {
+    check(&[("(< 1 1.5)", "true"), ("(= 2 2.0)", "true"), ("(> 2.5 2.5)", "false"), ("(>= 2.5 2.5)", "true"), ("(<= -1.5 -2)", "false")]);
+}
+
+#[test]
+fn isnum() {
+    check(&[
+        ("(isnum 2.5)", "true"),
+        ("(isnum 5)", "true"),
+        ("(isnum true)", "false"),
+        ("(isnum \"s\")", "false"),
+        ("(isnum (block))", "false"),
+        ("(isnum #\\a)", "false"),
+        ("(isnum (vector 1))", "false"),
+        ("(isbool 2.5)", "false"),
+    ]);
+    let mut repl = Repl::with_options(Options { bignum: true, ..Options::default() }).unwrap();
+    assert_eq!(eval(&mut repl, "(isnum (* 4611686018427387903 4))"), "true");
+}
+
+#[test]
+fn float_errors() {
+    check(&[
+        ("(try (mod 2.5 1) (catch e (error-code e)))", "2"),
+        ("(try (+ 2.5 true) (catch e (error-code e)))", "2"),
+        ("(try (bit-and 2.5 1) (catch e (error-code e)))", "2"),
+    ]);
+}
@@ -0,0 +1,56 @@
+use viva::format::format_source;
+
+#[test]
+fn comments_are_kept() {
+    let source = "; header\n\n\n(fun (f x) ; takes x\n    ; the body\n    (if (> x 0)   ; positive?\n        (print \"; not a comment\")\n        #\\;\n    )\n)\n(f 1) ; main\n";
+    assert_eq!(format_source(source).unwrap(), [
+        "; header",
+        "",
+        "(fun (f x) ; takes x",
+        "  ; the body",
+        "  (if (> x 0) ; positive?",
+        "    (print \"; not a comment\")",
+        "    #\\;))",
+        "(f 1) ; main",
+        "",
+    ].join("\n"));
+}
+
+#[test]
+fn long_forms_are_broken() {
+    let source = "(let ((total 0) (index 0)) (while (< index 10) (set! total (+ total (* index index))) (set! index (add1 index))) total)";
+    assert_eq!(format_source(source).unwrap(), [
+        "(let ((total 0) (index 0))",
+        "  (while (< index 10)",
+        "    (set! total (+ total (* index index)))",
+        "    (set! index (add1 index)))",
+        "  total)",
+        "",
+    ].join("\n"));
+}
+
+#[test]
+fn formatting_is_stable() {
+    let source = "(fun (pow base exp) :requires (>= exp 0) ; no negative exponents\n (let ((result 1)) (block (loop :outer (when (> exp 0) (break :outer result))) result)))\n\n\n(pow 2 10)";
+    let once = format_source(source).unwrap();
+    assert_eq!(format_source(&once).unwrap(), once);
+}
+
+#[test]
+fn prelude_is_formatted() {
+    let prelude = include_str!("../src/prelude.viva");
+    assert_eq!(format_source(prelude).unwrap(), prelude);
+}
+
+#[test]
+fn unbalanced_parens_are_errors() {
+    assert!(format_source("(f 1").is_err());
+    assert!(format_source("(f 1))").is_err());
+    assert!(format_source("(print \"open)").is_err());
+}
+
+#[test]
+fn literals_the_compiler_rejects_are_kept() {
+    let source = "(+ 99999999999999999999   1)\n(print   1e999)\n(f #\\nope 12abc)";
+    assert_eq!(format_source(source).unwrap(), "(+ 99999999999999999999 1)\n(print 1e999)\n(f #\\nope 12abc)\n");
+}
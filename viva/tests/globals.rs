@@ -0,0 +1,72 @@
+use viva::compile::compile_prog_to_asm;
+use viva::compile_repl::run_prog;
+use viva::context::Options;
+use viva::expressions::Program;
+use viva::parse::{parse_prog, read_sexp};
+use viva::runtime::format_value;
+
+fn parse(source: &str) -> std::io::Result<Program> {
+    parse_prog(&read_sexp(&format!("({})", source)).unwrap())
+}
+
+fn run(source: &str) -> String {
+    let program = parse(source).unwrap_or_else(|err| panic!("{}\n{}", err, source));
+    format_value(run_prog(&program, Options::default()).unwrap())
+}
+
+fn error(source: &str) -> String {
+    match parse(source) {
+        Ok(_) => panic!("{} should not compile", source),
+        Err(err) => err.to_string(),
+    }
+}
+
+#[test]
+fn globals_are_shared_by_functions() {
+    assert_eq!(run("(define c 0) (fun (inc) (set! c (+ c 1))) (block (inc) (inc) (inc) c)"), "3");
+    assert_eq!(run("(define a 1) (fun (f) a) (define b (f)) (+ a b)"), "2");
+    assert_eq!(run("(define total 0) (fun (add n) (block (set! total (+ total n)) total)) (block (add 4) (add 5))"), "9");
+    /* Parameters and locals hide a global of the same name */
+    assert_eq!(run("(define x 1) (fun (f x) (+ x 1)) (f 10)"), "11");
+    assert_eq!(run("(define x 1) (let ((x 5)) (block (set! x 6) x))"), "6");
+}
+
+#[test]
+fn globals_are_initialized_in_order() {
+    assert_eq!(run("(define a 2) (define b (* a 3)) (define c (+ a b)) c"), "8");
+    /* Main runs after every global has its value, so its functions may use all of them */
+    assert_eq!(run("(fun (f) b) (define a 1) (define b 2) (+ a (f))"), "3");
+}
+
+#[test]
+fn globals_are_not_used_before_they_are_defined() {
+    assert_eq!(error("(define a b) (define b 2) a"), "global a uses b before it is defined");
+    assert_eq!(error("(define a (set! b 1)) (define b 2) b"), "global a uses b before it is defined");
+    assert_eq!(error("(fun (f) b) (define a (f)) (define b 2) a"), "global a calls f, which uses b before it is defined");
+    assert_eq!(
+        error("(fun (g) b) (fun (f) (g)) (define a (f)) (define b 2) a"),
+        "global a calls f, which uses b before it is defined",
+    );
+    /* A local of the same name is not the global */
+    assert_eq!(run("(define a (let ((b 1)) b)) (define b 2) (+ a b)"), "3");
+}
+
+#[test]
+fn global_names() {
+    assert_eq!(error("(define a 1) (define a 2) a"), "Invalid: parse error: Duplicate global a.");
+    assert_eq!(error("(define input 1) input"), "Invalid: parse error: Duplicate global input.");
+    assert!(error("(define f 1) (fun (f) 2) (f)").starts_with("Error: `f` is a global variable, not a function"));
+    assert_eq!(
+        error("(define a 1) (print a) (define b 2)"),
+        "Invalid: parse error: a program ends with the expression it evaluates, not with the define of b.",
+    );
+}
+
+#[test]
+fn ahead_of_time_globals_live_in_the_data_section() {
+    let program = parse("(define a 1) (define b 2) (fun (f) (set! a b)) (block (f) a)").unwrap();
+    let asm = compile_prog_to_asm(&program, Options::default()).unwrap();
+    let data = &asm[asm.find("section .data").expect("a data section")..];
+    assert!(data.contains("global_0:\tdq 7"), "{}", data);
+    assert!(data.contains("global_1:\tdq 7"), "{}", data);
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use common::{check, eval};
+use viva::Repl;
+
+/* Errors found while compiling come back as Err from the REPL */
+fn compile_error(source: &str) -> String {
+    Repl::new().unwrap().feed(source).unwrap_err().to_string()
+}
+
+#[test]
+fn loop_and_break() {
+    check(&[
+        ("(let ((i 0)) (loop (if (= i 5) (break i) (set! i (add1 i)))))", "5"),
+        ("(let ((i 0)) (loop (block (set! i (add1 i)) (when (< i 3) (continue)) (break (* i 10)))))", "30"),
+    ]);
+}
+
+#[test]
+fn labels() {
+    check(&[
+        ("(let ((n 0)) (loop :outer (loop :inner (block (set! n (add1 n)) (if (> n 3) (break :outer n) (continue :outer))))))", "4"),
+        ("(let ((c 0)) (block (for :o (i 0 3) (for (j 0 3) (when (= j 1) (continue :o)) (set! c (add1 c)))) c))", "3"),
+    ]);
+}
+
+#[test]
+fn while_loops() {
+    check(&[
+        ("(let ((s 0) (i 0)) (block (while (< i 5) (set! i (add1 i)) (when (= i 3) (continue)) (set! s (+ s i))) s))", "12"),
+        ("(let ((i 0)) (while (< i 3) (set! i (add1 i))))", "nothing"),
+        /* Only true keeps the loop going */
+        ("(while 5 (print 1))", "nothing"),
+    ]);
+}
+
+/* The range of for includes the start and excludes the end */
+#[test]
+fn for_loops() {
+    check(&[
+        ("(let ((s 0)) (block (for (i 0 5) (set! s (+ s i))) s))", "10"),
+        ("(let ((s 0)) (block (for (i 3 1) (set! s 1)) s))", "0"),
+        ("(fun (find-first n) (for (i 0 n) (when (= (mod (* i i) 7) 2) (break i))))", ""),
+        ("(find-first 10)", "3"),
+        ("(find-first 2)", "nothing"),
+        ("(try (for (i true 3) 1) (catch e (error-code e)))", "2"),
+        ("(try (for (i 0 \"x\") 1) (catch e (error-code e)))", "2"),
+    ]);
+}
+
+#[test]
+fn misplaced_break_and_continue() {
+    assert_eq!(compile_error("(break 1)"), "break outside of a loop");
+    assert_eq!(compile_error("(continue)"), "continue outside of a loop");
+    assert_eq!(compile_error("(loop (break :nope 1))"), "unknown loop label :nope");
+    assert_eq!(compile_error("(loop (continue :x))"), "unknown loop label :x");
+    assert_eq!(eval(&mut Repl::new().unwrap(), "(for (i 0 3))"), "Invalid: parse error");
+}
@@ -0,0 +1,44 @@
+mod common;
+
+use common::check;
+
+#[test]
+fn macros_expand() {
+    check(&[
+        ("(define-syntax twice (syntax-rules () ((_ e) (block e e))))", ""),
+        ("(twice 5)", "5"),
+        ("(define n 0)", ""),
+        ("(block (twice (set! n (+ n 1))) n)", "2"),
+        ("(define-syntax my-or (syntax-rules () ((_) false) ((_ e rest ...) (if e true (my-or rest ...)))))", ""),
+        ("(my-or false false true)", "true"),
+        ("(my-or)", "false"),
+        /* The tmp of the template doesn't capture the tmp of the caller */
+        ("(define-syntax add-first (syntax-rules () ((_ a b) (let ((tmp a)) (+ tmp b)))))", ""),
+        ("(let ((tmp 1)) (add-first 10 tmp))", "11"),
+    ]);
+}
+
+#[test]
+fn definition_errors_say_what_is_wrong() {
+    check(&[
+        ("(define-syntax name)", "Invalid: parse error: define-syntax needs a name and syntax-rules."),
+        ("(define-syntax bad (syntax-rules))", "Invalid: parse error: define-syntax needs (syntax-rules (literal*) (pattern template)+)."),
+        ("(define-syntax bad (syntax-rules () ((_ x x) x)))", "Invalid: parse error: pattern variable x is used twice."),
+        ("(define-syntax twice (syntax-rules () ((_ e) (block e e))))", ""),
+        ("(define-syntax twice (syntax-rules () ((_ e) e)))", "Invalid: parse error: Duplicate macro name."),
+    ]);
+}
+
+#[test]
+fn expansion_errors_say_what_is_wrong() {
+    check(&[
+        ("(define-syntax twice (syntax-rules () ((_ e) (block e e))))", ""),
+        ("(twice 1 2)", "Invalid: parse error: no syntax-rules pattern of twice matches."),
+        ("(define-syntax forever (syntax-rules () ((_ e) (forever e))))", ""),
+        ("(forever 1)", "Invalid: parse error: expansion of forever doesn't terminate."),
+        ("(define-syntax spread (syntax-rules () ((_ e ...) (+ e))))", ""),
+        ("(spread 1 2)", "Invalid: parse error: e has to be followed by ... in the template."),
+        /* Other parse errors keep the plain message */
+        ("(twice (let))", "Invalid: parse error"),
+    ]);
+}
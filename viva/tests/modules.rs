@@ -0,0 +1,80 @@
+use std::path::PathBuf;
+
+use viva::compile_repl::run_prog;
+use viva::context::Options;
+use viva::parse::parse_prog_file;
+use viva::runtime::format_value;
+
+/* Writes the files of a program into a fresh directory and returns the path of the first one */
+fn write_files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("viva-modules-{}-{}", std::process::id(), test));
+    for (name, text) in files {
+        let path = dir.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, text).unwrap();
+    }
+    dir.join(files[0].0)
+}
+
+fn run(test: &str, files: &[(&str, &str)]) -> std::io::Result<String> {
+    let program = parse_prog_file(&write_files(test, files), Options::default())?;
+    Ok(format_value(run_prog(&program, Options::default())?))
+}
+
+const MATH: &str = "
+(enum shape (circle r) (square s))
+(fun (gcd a b) (if (= b 0) a (gcd b (mod a b))))
+(fun (area x) (match x ((circle r) (* 3 r)) ((square s) (* s s))))";
+
+const UTIL: &str = "
+(import \"lib/math.viva\")
+(fun (twice x) (math/gcd (* 2 x) (* 4 x)))";
+
+#[test]
+fn imported_names_take_the_module_prefix() {
+    let main = "
+(import \"util.viva\")
+(import \"lib/math.viva\")
+(+ (util/twice 5) (math/area (math/square 3)))";
+    let files = [("main.viva", main), ("util.viva", UTIL), ("lib/math.viva", MATH)];
+    assert_eq!(run("prefix", &files).unwrap(), "19");
+}
+
+#[test]
+fn imports_are_not_passed_on() {
+    let main = "
+(import \"util.viva\")
+(math/gcd 4 6)";
+    let files = [("main.viva", main), ("util.viva", UTIL), ("lib/math.viva", MATH)];
+    let err = run("transitive", &files).unwrap_err().to_string();
+    assert!(err.starts_with("Error: unknown function `math/gcd`"), "{}", err);
+}
+
+#[test]
+fn variants_of_an_import_are_not_passed_on() {
+    let main = "
+(import \"util.viva\")
+(match (util/twice 1) ((math/circle r) r) (_ 0))";
+    let files = [("main.viva", main), ("util.viva", UTIL), ("lib/math.viva", MATH)];
+    let err = run("variants", &files).unwrap_err().to_string();
+    assert!(err.starts_with("Error: unknown variant math/circle"), "{}", err);
+}
+
+#[test]
+fn modules_see_the_prelude() {
+    /* A function of the module itself takes the place of the prelude one inside the module */
+    let main = "
+(import \"math.viva\")
+(import \"tens.viva\")
+(+ (math/useabs -3) (tens/own -3))";
+    let files = [
+        ("main.viva", main),
+        ("math.viva", "(fun (useabs x) (max (abs x) (gcd 4 6)))"),
+        ("tens.viva", "(fun (abs x) (* x 10)) (fun (own x) (abs x))"),
+    ];
+    assert_eq!(run("prelude", &files).unwrap(), "-27");
+
+    let options = Options { no_prelude: true, ..Options::default() };
+    let err = parse_prog_file(&write_files("no-prelude", &files), options).unwrap_err().to_string();
+    assert!(err.starts_with("Error: unknown function `abs`"), "{}", err);
+}
@@ -0,0 +1,44 @@
+mod common;
+
+use common::{check, error_code};
+
+#[test]
+fn division() {
+    check(&[("(/ 7 2)", "3"), ("(/ -7 2)", "-3"), ("(/ 6 -3)", "-2")]);
+    check(&[(&error_code("(/ 1 0)"), "3"), (&error_code("(/ -4611686018427387904 -1)"), "1")]);
+}
+
+/* mod takes the sign of the divisor, rem the sign of the dividend */
+#[test]
+fn modulo_and_remainder() {
+    check(&[("(mod -7 2)", "1"), ("(rem -7 2)", "-1"), ("(mod 7 -2)", "-1"), ("(rem 7 -2)", "1"), ("(mod 6 3)", "0")]);
+    check(&[(&error_code("(mod 1 0)"), "3"), (&error_code("(rem 1 0)"), "3")]);
+}
+
+#[test]
+fn bitwise() {
+    check(&[("(bit-and 12 10)", "8"), ("(bit-or 12 10)", "14"), ("(bit-xor 12 10)", "6"), ("(bit-and -1 5)", "5")]);
+    check(&[(&error_code("(bit-or 1 true)"), "2")]);
+}
+
+#[test]
+fn shifts() {
+    check(&[("(shl 3 4)", "48"), ("(shr -16 2)", "-4"), ("(shr 5 1)", "2")]);
+    check(&[(&error_code("(shl 1 70)"), "1"), (&error_code("(shl 4611686018427387903 1)"), "1")]);
+}
+
+#[test]
+fn comparisons() {
+    check(&[
+        ("(< 1 2)", "true"),
+        ("(< 2 2)", "false"),
+        ("(<= 2 2)", "true"),
+        ("(<= 3 2)", "false"),
+        ("(> 3 2)", "true"),
+        ("(> 2 2)", "false"),
+        ("(>= 2 2)", "true"),
+        ("(>= 1 2)", "false"),
+        ("(>= -5 -6)", "true"),
+    ]);
+    check(&[(&error_code("(<= 1 true)"), "2"), (&error_code("(>= false 1)"), "2")]);
+}
@@ -0,0 +1,109 @@
+use viva::analysis::Span;
+use viva::expressions::{Expr, ReplExpr};
+use viva::parse::{lex, parse_repl_expr, read, read_forms, Atom, DefNames, Sexp, TokenKind};
+use viva::Repl;
+
+fn name(s: &str) -> Sexp {
+    Sexp::Atom(Atom::S(s.to_string()), Span::default())
+}
+
+#[test]
+fn forms_keep_their_spans() {
+    let source = "(define x 1)\n  (print x) ; shown\n42";
+    let forms = read(source).unwrap();
+    let spans: Vec<&str> = forms.iter().map(|(_, span)| &source[span.clone()]).collect();
+    assert_eq!(spans, ["(define x 1)", "(print x)", "42"]);
+    assert_eq!(forms[1].0, Sexp::List(vec![name("print"), name("x")], Span::default()));
+}
+
+#[test]
+fn comments_are_tokens() {
+    let tokens = lex("(f #\\; \"a;b\") ; done").unwrap();
+    let kinds: Vec<TokenKind> = tokens.into_iter().map(|t| t.kind).collect();
+    assert_eq!(kinds, [
+        TokenKind::Open,
+        TokenKind::Atom(Atom::S("f".to_string())),
+        TokenKind::Atom(Atom::Char(';')),
+        TokenKind::Atom(Atom::Str("a;b".to_string())),
+        TokenKind::Close,
+        TokenKind::Comment("; done".to_string()),
+    ]);
+}
+
+#[test]
+fn atoms_are_read_by_their_shape() {
+    let forms: Vec<Sexp> = read("12 -7 2.5 -0.5 1e3 2.5E-2 - inf Total").unwrap().into_iter().map(|(s, _)| s).collect();
+    assert_eq!(forms, [
+        Sexp::Atom(Atom::I(12), Span::default()),
+        Sexp::Atom(Atom::I(-7), Span::default()),
+        Sexp::Atom(Atom::F(2.5), Span::default()),
+        Sexp::Atom(Atom::F(-0.5), Span::default()),
+        Sexp::Atom(Atom::F(1000.0), Span::default()),
+        Sexp::Atom(Atom::F(0.025), Span::default()),
+        name("-"),
+        name("inf"),
+        name("Total"),
+    ]);
+}
+
+#[test]
+fn malformed_numbers_are_errors() {
+    for text in [".5", "1.", "1abc", "2.5.1", "1e", "-3e+"] {
+        let err = read_forms("input", text).unwrap_err().to_string();
+        assert!(err.contains(&format!("malformed number {}", text)), "{}", err);
+    }
+    assert!(read_forms("input", "1e999").unwrap_err().to_string().contains("float literal 1e999 is out of range"));
+}
+
+#[test]
+fn integer_literals_are_range_checked() {
+    assert_eq!(read("4611686018427387903").unwrap()[0].0, Sexp::Atom(Atom::I(4611686018427387903), Span::default()));
+    assert_eq!(read("-4611686018427387904").unwrap()[0].0, Sexp::Atom(Atom::I(-4611686018427387904), Span::default()));
+    for big in ["4611686018427387904", "-4611686018427387905", "99999999999999999999"] {
+        let err = read_forms("input", &format!("(print {})", big)).unwrap_err().to_string();
+        assert!(err.contains(&format!("integer literal {} is out of range", big)), "{}", err);
+        assert!(err.contains("input:1:8"), "{}", err);
+    }
+}
+
+#[test]
+fn read_errors_point_into_the_source() {
+    let err = read_forms("input", "(print 1)\n(print (+ 1 2)").unwrap_err().to_string();
+    assert!(err.contains("missing )") && err.contains("input:2:1"), "{}", err);
+    let err = read_forms("input", "(f 1))").unwrap_err().to_string();
+    assert!(err.contains("unexpected )") && err.contains("input:1:6"), "{}", err);
+    let err = read_forms("input", "(print \"a\\q\")").unwrap_err().to_string();
+    assert!(err.contains("unknown escape \\q"), "{}", err);
+    assert!(read("(print \"open)").is_err());
+}
+
+#[test]
+fn names_are_case_sensitive() {
+    let form = parse_repl_expr(&read("(define Total 1)").unwrap()[0].0, &DefNames::default()).unwrap();
+    assert_eq!(form, ReplExpr::Define("Total".to_string(), Box::new(Expr::Number(1))));
+
+    let mut repl = Repl::new().unwrap();
+    assert_eq!(repl.feed("(define X 5)").unwrap(), None);
+    assert_eq!(repl.feed("(define x 6)").unwrap(), None);
+    assert_eq!(repl.feed("(+ X x)").unwrap(), Some("11".to_string()));
+}
+
+#[test]
+fn an_input_can_hold_several_forms() {
+    let mut repl = Repl::new().unwrap();
+    assert_eq!(repl.feed("(fun (twice n) (* 2 n)) (define a (twice 4)) (+ a 1) (twice a)").unwrap(), Some("9\n16".to_string()));
+}
+
+#[test]
+fn an_input_stops_at_its_first_failing_form() {
+    let mut repl = Repl::new().unwrap();
+    let output = repl.feed("(define a 1) (nope) (define b 2)").unwrap().unwrap();
+    assert!(output.starts_with("Error: unknown function `nope`") && output.contains("input:1:14"), "{}", output);
+    assert_eq!(repl.feed("a").unwrap(), Some("1".to_string()));
+    assert!(repl.feed("b").is_err());
+
+    /* What the forms before a compile error gave is kept in front of it */
+    let err = repl.feed("(+ a 4) (let ((x 1) (x 2)) x) (define c 3)").unwrap_err();
+    assert_eq!(err.to_string(), "5\nDuplicate binding");
+    assert!(repl.feed("c").is_err());
+}
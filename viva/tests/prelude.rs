@@ -0,0 +1,68 @@
+mod common;
+
+use common::{check, eval};
+use viva::{Options, Repl};
+
+#[test]
+fn abs() {
+    check(&[("(abs -5)", "5"), ("(abs 7)", "7"), ("(abs 0)", "0")]);
+}
+
+#[test]
+fn max() {
+    check(&[("(max 3 9)", "9"), ("(max 9 3)", "9"), ("(max -2 -8)", "-2")]);
+}
+
+#[test]
+fn min() {
+    check(&[("(min 3 9)", "3"), ("(min 9 3)", "3"), ("(min -2 -8)", "-8")]);
+}
+
+#[test]
+fn gcd() {
+    check(&[("(gcd 12 18)", "6"), ("(gcd -12 18)", "6"), ("(gcd 7 0)", "7"), ("(gcd 0 0)", "0"), ("(gcd 17 5)", "1")]);
+}
+
+#[test]
+fn pow() {
+    check(&[
+        ("(pow 2 10)", "1024"),
+        ("(pow -3 3)", "-27"),
+        ("(pow 5 0)", "1"),
+        ("(try (pow 2 -1) (catch e (error-code e)))", "11"),
+    ]);
+}
+
+#[test]
+fn even() {
+    check(&[("(even? 4)", "true"), ("(even? 7)", "false"), ("(even? -4)", "true"), ("(even? 0)", "true")]);
+}
+
+#[test]
+fn odd() {
+    check(&[("(odd? 7)", "true"), ("(odd? 4)", "false"), ("(odd? -3)", "true")]);
+}
+
+#[test]
+fn no_prelude() {
+    let mut repl = Repl::with_options(Options { no_prelude: true, ..Options::default() }).unwrap();
+    assert!(eval(&mut repl, "(abs -5)").starts_with("Error: unknown function `abs`"));
+    assert_eq!(eval(&mut repl, "(fun (abs x) x)"), "");
+    assert_eq!(eval(&mut repl, "(abs -5)"), "-5");
+}
+
+#[test]
+fn session_definitions_replace_prelude_functions() {
+    check(&[
+        ("(max 1 2)", "2"),
+        ("(fun (max a b) a)", ""),
+        ("(max 1 2)", "1"),
+        ("(fun (max a b) b)", "Invalid: parse error"),
+        ("(fun (abs x) 42)", ""),
+        ("(abs -3)", "42"),
+        /* The prelude functions compiled before keep calling the prelude abs */
+        ("(gcd -12 18)", "6"),
+        ("(fun (even? n x) (= n x))", ""),
+        ("(even? 2 2)", "true"),
+    ]);
+}
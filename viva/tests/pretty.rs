@@ -0,0 +1,237 @@
+use proptest::prelude::*;
+
+use viva::analysis::Span;
+use viva::expressions::{Binder, Defenition, Expr, Op2, Pattern, Program, ReplExpr, Signature};
+use viva::compile_repl::run_prog;
+use viva::context::Options;
+use viva::parse::{parse_expr, parse_prog, parse_repl_expr, read_sexp, DefNames};
+use viva::runtime::format_value;
+
+fn reparse(e: &Expr) -> Expr {
+    let text = e.to_string();
+    parse_expr(&read_sexp(&text).unwrap()).unwrap_or_else(|err| panic!("{}\n{}", err, text))
+}
+
+fn name() -> impl Strategy<Value = String> {
+    prop::sample::select(vec!["x", "y", "acc", "n1", "point-x", "empty?", "string->list", "#for_next1", "+", "<="])
+        .prop_map(str::to_string)
+}
+
+/* Integers are tagged, literals are 63 bits */
+fn number() -> impl Strategy<Value = Expr> {
+    (-(1i64 << 62)..(1i64 << 62)).prop_map(Expr::Number)
+}
+
+fn literal() -> impl Strategy<Value = Expr> {
+    prop_oneof![
+        number(),
+        any::<f64>().prop_filter("finite", |f| f.is_finite()).prop_map(|f| Expr::Float(f.to_bits())),
+        any::<bool>().prop_map(Expr::Boolean),
+        any::<String>().prop_map(Expr::Str),
+        any::<char>().prop_map(Expr::Char),
+    ]
+}
+
+fn case_key() -> impl Strategy<Value = Expr> {
+    prop_oneof![number(), any::<bool>().prop_map(Expr::Boolean)]
+}
+
+fn label() -> impl Strategy<Value = Option<String>> {
+    prop::option::of(prop::sample::select(vec!["outer", "inner"]).prop_map(str::to_string))
+}
+
+/* A pattern binds distinct names, except for _ */
+fn pattern() -> impl Strategy<Value = Pattern> {
+    (name(), prop::collection::vec(prop_oneof![name(), Just("_".to_string())], 0..3)).prop_map(|(variant, mut names)| {
+        let mut seen = Vec::new();
+        names.retain(|n| n == "_" || (!seen.contains(n) && { seen.push(n.clone()); true }));
+        Pattern::Variant(variant, 0, names.iter().map(|n| Binder::new(n)).collect(), Span::default())
+    })
+}
+
+/* The parameters are distinct, the defaults are literals and follow the required ones */
+fn fun(body: BoxedStrategy<Expr>) -> impl Strategy<Value = Defenition> {
+    (name(), prop::collection::vec(name(), 0..4), prop::collection::vec(literal(), 0..2), any::<bool>(), body).prop_map(
+        |(fname, mut params, defaults, rest, body)| {
+            params.sort();
+            params.dedup();
+            let defaults: Vec<Expr> = defaults.into_iter().take(params.len()).collect();
+            let rest = rest && params.len() > defaults.len();
+            let required = params.len() - defaults.len() - usize::from(rest);
+            let params = params.iter().map(|p| Binder::new(p)).collect();
+            Defenition::Fun(fname, params, Signature { required, defaults, rest }, Box::new(body))
+        },
+    )
+}
+
+/* Expressions of the shapes the parser produces, before any name is resolved */
+fn expr() -> impl Strategy<Value = Expr> {
+    let leaf = prop_oneof![
+        literal(),
+        name().prop_map(Expr::Id),
+        Just(Expr::Nothing),
+        label().prop_map(Expr::Continue),
+    ];
+    leaf.prop_recursive(4, 48, 4, |inner| {
+        let many = || prop::collection::vec(inner.clone(), 0..4);
+        let boxed = || inner.clone().prop_map(Box::new);
+        prop_oneof![
+            (prop::collection::vec((name().prop_map(|n| Binder::new(&n)), inner.clone()), 0..3), boxed()).prop_map(|(bs, body)| Expr::Let(bs, body)),
+            (fun(inner.clone().boxed()), boxed()).prop_map(|(f, body)| Expr::LetRec(vec![f], body)),
+            (boxed(), boxed(), boxed()).prop_map(|(c, t, f)| Expr::If(c, t, f)),
+            (prop::collection::vec((inner.clone(), inner.clone()), 0..3), prop::option::of(boxed()))
+                .prop_map(|(arms, default)| Expr::Cond(arms, default)),
+            (boxed(), boxed()).prop_map(|(c, b)| Expr::When(c, b)),
+            (boxed(), boxed()).prop_map(|(c, b)| Expr::Unless(c, b)),
+            (boxed(), prop::collection::vec((prop::collection::vec(case_key(), 1..3), inner.clone()), 0..3), prop::option::of(boxed()))
+                .prop_map(|(e, arms, default)| Expr::Case(e, arms, default)),
+            (boxed(), prop::collection::vec((pattern(), inner.clone()), 1..3), prop::option::of(inner.clone()))
+                .prop_map(|(e, mut arms, wildcard)| {
+                    arms.extend(wildcard.map(|body| (Pattern::Wildcard, body)));
+                    Expr::Match(e, arms, Span::default())
+                }),
+            (label(), boxed()).prop_map(|(l, e)| Expr::Loop(l, e)),
+            (label(), boxed()).prop_map(|(l, e)| Expr::Break(l, e)),
+            (name(), boxed()).prop_map(|(n, e)| Expr::Set(n, e)),
+            (boxed(), name(), boxed()).prop_map(|(e, field, v)| Expr::SetField(e, field, v)),
+            (boxed(), any::<String>()).prop_map(|(e, message)| Expr::Assert(e, message)),
+            (boxed(), name(), boxed()).prop_map(|(body, n, handler)| Expr::Try(body, Binder::new(&n), handler)),
+            many().prop_map(Expr::And),
+            many().prop_map(Expr::Or),
+            prop::collection::vec(inner.clone(), 1..4).prop_map(Expr::Block),
+            (name(), many()).prop_map(|(n, args)| Expr::Call(n, args, Span::default())),
+        ]
+    })
+}
+
+const DECLARATIONS: &str = "
+(struct point (x y))
+(enum shape (circle r) (rect w h))
+(fun (area w (h 2)) (* w h))
+(fun (count (base 0) &rest xs) (+ base (vector-length xs)))
+(define p (point 1 2))";
+
+/* Expressions that resolve against DECLARATIONS: calls of the constructors and accessors, calls that leave out
+   defaults or pass &rest arguments, and matches on the variants of shape */
+fn program_expr() -> impl Strategy<Value = Expr> {
+    let call = |name: &str, args: Vec<Expr>| Expr::Call(name.to_string(), args, Span::default());
+    let leaf = prop_oneof![number(), Just(Expr::Id("p".to_string()))];
+    leaf.prop_recursive(4, 32, 4, move |inner| {
+        let arms = (any::<bool>(), any::<bool>(), inner.clone(), inner.clone(), inner.clone()).prop_map(|(circle, rect, c, r, default)| {
+            let mut arms = Vec::new();
+            if circle {
+                arms.push((Pattern::Variant("circle".to_string(), 0, vec![Binder::new("r")], Span::default()), c));
+            }
+            if rect {
+                arms.push((Pattern::Variant("rect".to_string(), 0, vec![Binder::new("w"), Binder::new("_")], Span::default()), r));
+            }
+            if !(circle && rect) {
+                arms.push((Pattern::Wildcard, default));
+            }
+            arms
+        });
+        prop_oneof![
+            prop::collection::vec(inner.clone(), 0..5).prop_map(move |args| call("count", args)),
+            prop::collection::vec(inner.clone(), 1..3).prop_map(move |args| call("area", args)),
+            (inner.clone(), inner.clone()).prop_map(move |(a, b)| call("+", vec![a, b])),
+            (inner.clone(), inner.clone()).prop_map(move |(a, b)| call("point", vec![a, b])),
+            inner.clone().prop_map(move |a| call("point-y", vec![a])),
+            (inner.clone(), any::<bool>(), arms).prop_map(move |(a, circle, arms)| {
+                let scrutinee = if circle { call("circle", vec![a]) } else { call("rect", vec![a.clone(), a]) };
+                Expr::Match(Box::new(scrutinee), arms, Span::default())
+            }),
+        ]
+    })
+}
+
+proptest! {
+    #[test]
+    fn expressions_round_trip(e in expr()) {
+        prop_assert_eq!(reparse(&e), e);
+    }
+
+    #[test]
+    fn repl_forms_round_trip(e in expr(), f in fun(expr().boxed()), fields in prop::collection::vec(name(), 0..3)) {
+        let mut fields = fields;
+        fields.sort();
+        fields.dedup();
+        let (fname, params, signature, body) = match f {
+            Defenition::Fun(fname, params, signature, body) => (fname, params, signature, body),
+            _ => unreachable!(),
+        };
+        for form in [
+            ReplExpr::Define("total".to_string(), Box::new(e.clone())),
+            ReplExpr::Fun(format!("f-{}", fname), params, signature, body),
+            ReplExpr::Struct("point".to_string(), fields),
+        ] {
+            let text = form.to_string();
+            prop_assert_eq!(parse_repl_expr(&read_sexp(&text).unwrap(), &DefNames::default()).unwrap(), form, "{}", text);
+        }
+    }
+    #[test]
+    fn lowered_programs_round_trip(e in program_expr()) {
+        let text = program(&format!("{}\n{}", DECLARATIONS, e)).to_string();
+        prop_assert_eq!(program(&text).to_string(), text.clone());
+        prop_assert!(!text.contains("(vector ") && !text.contains("(fun (circle"), "{}", text);
+    }
+}
+
+#[test]
+fn resolved_operations_print_as_calls() {
+    let e = Expr::BinOp(Op2::Plus, Box::new(Expr::Number(1)), Box::new(Expr::UnOp(viva::expressions::Op1::Add1, Box::new(Expr::Id("x".to_string())))));
+    assert_eq!(e.to_string(), "(+ 1 (add1 x))");
+}
+
+#[test]
+fn long_forms_are_indented() {
+    let source = "(fun (f x) (let ((total (+ x 1)) (count (* x 2))) (if (> total count) (print total) (print (string-append \"the count is larger: \" count)))))";
+    let form = parse_repl_expr(&read_sexp(source).unwrap(), &DefNames::default()).unwrap();
+    assert_eq!(form.to_string(), [
+        "(fun (f x)",
+        "  (let ((total (+ x 1)) (count (* x 2)))",
+        "    (if (> total count)",
+        "      (print total)",
+        "      (print (string-append \"the count is larger: \" count)))))",
+    ].join("\n"));
+}
+
+fn program(source: &str) -> Program {
+    parse_prog(&read_sexp(&format!("({})", source)).unwrap()).unwrap_or_else(|err| panic!("{}\n{}", err, source))
+}
+
+/* A resolved program reads back the same, the prelude included. Structs and variants get a new id every time
+   they are lowered, so the text is compared and the programs are run. */
+#[test]
+fn programs_round_trip() {
+    let sources = [
+        "(fun (area w (h 2)) (* w h)) (define scale 3) (define base (area scale)) (print (+ base (gcd 12 18)))",
+        "(struct point (x y))
+         (enum shape (circle r) (rect w h))
+         (fun (size s) (match s ((circle r) (* 3 (* r r))) ((rect w h) (* w h))))
+         (fun (count (base 0) &rest xs) (+ base (vector-length xs)))
+         (define p (point 1 2))
+         (block (set-field! p y 5) (+ (point-y p) (+ (size (circle 2)) (+ (count) (+ (count 1 2 3) (count 5))))))",
+    ];
+    for source in sources {
+        let first = program(source);
+        let text = first.to_string();
+        let again = program(&text);
+        assert_eq!(again.to_string(), text);
+        assert_eq!(format_value(run_prog(&again, Options::default()).unwrap()), format_value(run_prog(&first, Options::default()).unwrap()), "{}", text);
+    }
+
+    let text = program(sources[1]).to_string();
+    for written in ["(struct point (x y))", "(enum shape (circle r) (rect w h))", "((circle r) (* 3 (* r r)))", "(count)", "(count 1 2 3)", "(count 5)"] {
+        assert!(text.contains(written), "{}\n{}", written, text);
+    }
+    assert!(!text.contains("(fun (point-y") && !text.contains("(fun (circle") && !text.contains("(vector "), "{}", text);
+}
+
+#[test]
+fn macros_round_trip() {
+    let source = "(define-syntax log! (syntax-rules (at) ((_ at where e ...) (block (print \"at\") (print where) e ...))))";
+    let form = parse_repl_expr(&read_sexp(source).unwrap(), &DefNames::default()).unwrap();
+    let text = form.to_string();
+    assert_eq!(parse_repl_expr(&read_sexp(&text).unwrap(), &DefNames::default()).unwrap(), form, "{}", text);
+    assert!(text.contains("(print \"at\")"), "{}", text);
+}
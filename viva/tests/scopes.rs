@@ -0,0 +1,70 @@
+mod common;
+
+use common::{check, eval};
+use viva::parse::{parse_prog, read_sexp};
+use viva::Repl;
+
+/* The last line is the value, the lines before it the warnings */
+fn value(output: &str) -> &str {
+    output.lines().last().unwrap_or_default()
+}
+
+#[test]
+fn inner_scopes_hide_outer_ones() {
+    check(&[
+        ("(let ((x 1)) (let ((x 2)) x))", "2"),
+        ("(let ((x 1)) (block (let ((x 2)) x) x))", "1"),
+        ("(define y 10)", ""),
+        ("(fun (f y) (+ y 1))", ""),
+        ("(f 1)", "2"),
+        ("(let ((y 3)) (try (raise 4) (catch y y)))", "4"),
+        ("(enum box (full v))", ""),
+        ("(let ((v 1)) (match (full 7) ((full v) v)))", "7"),
+        ("(let ((n 5)) (letrec ((fun (g n) (* n 2))) (g 1)))", "2"),
+    ]);
+}
+
+#[test]
+fn names_of_builtins_can_be_bound() {
+    let mut repl = Repl::new().unwrap();
+    assert_eq!(value(&eval(&mut repl, "(let ((add1 10)) (+ add1 1))")), "11");
+    assert_eq!(value(&eval(&mut repl, "(letrec ((fun (add1 x) (* x 2))) (add1 5))")), "10");
+    assert_eq!(eval(&mut repl, "(fun (max3 a b c) (max a (max b c)))"), "");
+    assert_eq!(eval(&mut repl, "(max3 1 7 3)"), "7");
+    /* A function of the session takes the place of the builtin */
+    assert_eq!(eval(&mut repl, "(fun (print x) (+ x 1))"), "");
+    assert_eq!(eval(&mut repl, "(print 5)"), "6");
+    /* A local is not a function */
+    let err = eval(&mut repl, "(let ((sub1 1)) (sub1 2))");
+    assert!(err.contains("Error: `sub1` is a local variable, not a function"), "{}", err);
+}
+
+#[test]
+fn shadowing_warnings_point_at_the_binder() {
+    let mut repl = Repl::new().unwrap();
+    let cases = [
+        ("(let ((print 5)) (+ print 1))", "Warning: local `print` shadows the builtin `print`", "input:1:8"),
+        ("(fun (g print) print)", "Warning: parameter `print` shadows the builtin `print`", "input:1:9"),
+        ("(try 1 (catch add1 add1))", "Warning: local `add1` shadows the builtin `add1`", "input:1:15"),
+        ("(let* ((a 1) (abs 2)) abs)", "Warning: local `abs` shadows the function `abs`", "input:1:15"),
+        ("(for (not 0 1) 2)", "Warning: local `not` shadows the builtin `not`", "input:1:7"),
+        ("(enum opt (some v))", "", ""),
+        ("(match (some 1)\n  ((some max) max))", "Warning: local `max` shadows the function `max`", "input:2:10"),
+    ];
+    for (source, warning, position) in cases {
+        let output = eval(&mut repl, source);
+        assert!(output.starts_with(warning), "{}\n{}", source, output);
+        assert!(output.contains(position), "{}\n{}", source, output);
+    }
+}
+
+#[test]
+fn names_the_prelude_binds_are_not_warned_about() {
+    /* The prelude has parameters named x, a and b and locals named r and y */
+    for name in ["x", "a", "b", "r", "y"] {
+        let program = parse_prog(&read_sexp(&format!("((fun ({} q) q) ({} 1))", name, name)).unwrap()).unwrap();
+        assert_eq!(program.warnings, Vec::<String>::new(), "{}", name);
+    }
+    let program = parse_prog(&read_sexp("((fun (f print) print) (f 1))").unwrap()).unwrap();
+    assert_eq!(program.warnings, ["Warning: parameter `print` shadows the builtin `print`"]);
+}
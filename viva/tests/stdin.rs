@@ -0,0 +1,42 @@
+mod common;
+
+use common::eval;
+use viva::Repl;
+
+#[test]
+fn values_are_read_line_by_line() {
+    let mut repl = Repl::new().unwrap();
+    repl.set_stdin("5\n41\n true \n");
+    assert_eq!(eval(&mut repl, "(read-num)"), "5");
+    assert_eq!(eval(&mut repl, "(+ (read-num) 1)"), "42");
+    assert_eq!(eval(&mut repl, "(read-bool)"), "true");
+}
+
+/* Code 5 at the end of the input, 6 for a line that is not a value of the type */
+#[test]
+fn reading_errors() {
+    let mut repl = Repl::new().unwrap();
+    repl.set_stdin("abc\nfalse\n");
+    assert_eq!(eval(&mut repl, "(try (read-num) (catch e (error-code e)))"), "6");
+    assert_eq!(eval(&mut repl, "(try (read-num) (catch e (error-code e)))"), "6");
+    assert_eq!(eval(&mut repl, "(try (read-bool) (catch e (error-code e)))"), "5");
+}
+
+/* Lines that are not read stay for the following inputs until stdin is set again */
+#[test]
+fn unread_lines_are_kept() {
+    let mut repl = Repl::new().unwrap();
+    repl.set_stdin("1\n2\n3\n");
+    assert_eq!(eval(&mut repl, "(read-num)"), "1");
+    assert_eq!(eval(&mut repl, "(read-num)"), "2");
+    repl.set_stdin("10\n");
+    assert_eq!(eval(&mut repl, "(read-num)"), "10");
+    assert_eq!(eval(&mut repl, "(try (read-num) (catch e (error-code e)))"), "5");
+}
+
+/* A session reads only the stdin it is given, the process stdin is left to the command line */
+#[test]
+fn without_stdin_the_input_has_ended() {
+    let mut repl = Repl::new().unwrap();
+    assert_eq!(eval(&mut repl, "(try (read-num) (catch e (error-code e)))"), "5");
+}
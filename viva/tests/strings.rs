@@ -0,0 +1,69 @@
+mod common;
+
+use common::{check, error_code};
+use viva::compile::compile_prog_to_asm;
+use viva::compile_repl::run_prog;
+use viva::context::Options;
+use viva::parse::{parse_prog, read_sexp};
+use viva::runtime::format_value;
+
+#[test]
+fn string_operations() {
+    check(&[
+        ("\"hello\"", "hello"),
+        ("(string-length \"héllo\")", "5"),
+        ("(string-length \"a\\nb\")", "3"),
+        ("(substring \"hello\" 1 3)", "el"),
+        ("(string-append \"ab\" \"cd\")", "abcd"),
+        ("(string=? \"ab\" \"ab\")", "true"),
+        ("(string=? \"ab\" \"ac\")", "false"),
+    ]);
+}
+
+#[test]
+fn characters() {
+    check(&[("#\\a", "a"), ("(= #\\a #\\a)", "true"), ("(= #\\a #\\b)", "false"), ("(isnum #\\a)", "false"), ("(isbool #\\a)", "false")]);
+}
+
+#[test]
+fn string_errors() {
+    check(&[
+        (&error_code("(substring \"abc\" 2 5)"), "7"),
+        (&error_code("(substring \"abc\" 2 1)"), "7"),
+        (&error_code("(string-length 5)"), "2"),
+        (&error_code("(string-append \"a\" 1)"), "2"),
+        (&error_code("(+ \"a\" 1)"), "2"),
+    ]);
+}
+
+/* Strings are heap pointers and characters immediates, both are odd like the booleans but aren't ones */
+#[test]
+fn strings_and_characters_are_not_booleans() {
+    check(&[
+        (&error_code("(not \"abc\")"), "2"),
+        (&error_code("(not #\\a)"), "2"),
+        (&error_code("(and \"abc\" true)"), "2"),
+        (&error_code("(or #\\a true)"), "2"),
+        ("(isbool \"abc\")", "false"),
+    ]);
+}
+
+/* The objects of literals are not part of the code, they stay valid after the code of a run is freed */
+#[test]
+fn literals_outlive_the_code() {
+    for (source, expected) in [
+        ("\"hi\"", "hi"),
+        ("2.5", "2.5"),
+        ("(let ((x \"hi\")) x)", "hi"),
+        ("(fun (f) \"x\") (vector (f) 1.5)", "[x, 1.5]"),
+    ] {
+        let program = parse_prog(&read_sexp(&format!("({})", source)).unwrap()).unwrap();
+        assert_eq!(format_value(run_prog(&program, Options::default()).unwrap()), expected, "{}", source);
+    }
+
+    let program = parse_prog(&read_sexp("((string-append \"ab\" \"cd\"))").unwrap()).unwrap();
+    let asm = compile_prog_to_asm(&program, Options::default()).unwrap();
+    let (text, data) = asm.split_at(asm.find("section .data").expect("a data section"));
+    assert!(!text.contains("dq"), "{}", text);
+    assert!(data.contains(":\tdq 1, 2, 25185") && data.contains(":\tdq 1, 2, 25699"), "{}", data);
+}
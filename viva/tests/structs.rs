@@ -0,0 +1,55 @@
+mod common;
+
+use common::check;
+use viva::parse::{parse_repl_expr, read, DefNames};
+use viva::Repl;
+
+#[test]
+fn set_field_updates_in_place() {
+    check(&[
+        ("(struct point (x y))", ""),
+        ("(define p (point 1 2))", ""),
+        ("(set-field! p y 5)", "5"),
+        ("p", "point { x: 1, y: 5 }"),
+        ("(point-y p)", "5"),
+        ("(fun (bump! p) (set-field! p x (+ (point-x p) 1)))", ""),
+        ("(block (bump! p) (bump! p) (point-x p))", "3"),
+        ("(let ((q p)) (block (set-field! q x 0) (point-x p)))", "0"),
+    ]);
+}
+
+#[test]
+fn set_field_picks_the_struct_of_the_value() {
+    check(&[
+        ("(struct point (x y))", ""),
+        ("(struct pair (y x))", ""),
+        ("(define p (point 1 2))", ""),
+        ("(define q (pair 1 2))", ""),
+        ("(block (set-field! p x 7) (set-field! q x 8) p)", "point { x: 7, y: 2 }"),
+        ("q", "pair { y: 1, x: 8 }"),
+        ("(enum shape (circle r) (rect w h))", ""),
+        ("(define c (circle 1))", ""),
+        ("(block (set-field! c r 4) c)", "circle { r: 4 }"),
+    ]);
+}
+
+#[test]
+fn set_field_errors() {
+    check(&[
+        ("(struct point (x y))", ""),
+        ("(struct other (q))", ""),
+        ("(define p (point 1 2))", ""),
+        ("(try (set-field! 5 x 1) (catch e (error-code e)))", "8"),
+        ("(try (set-field! p q 1) (catch e (error-code e)))", "8"),
+        ("p", "point { x: 1, y: 2 }"),
+    ]);
+    assert_eq!(Repl::new().unwrap().feed("(set-field! 1 no-such-field 2)").unwrap_err().to_string(), "unknown field no-such-field");
+}
+
+#[test]
+fn duplicate_fields_are_rejected() {
+    for source in ["(struct dup (a b a))", "(enum dup (one a a))"] {
+        let err = parse_repl_expr(&read(source).unwrap()[0].0, &DefNames::default()).unwrap_err().to_string();
+        assert_eq!(err, "Invalid: parse error: Duplicate field name.", "{}", source);
+    }
+}
@@ -0,0 +1,31 @@
+mod common;
+
+use common::check;
+
+#[test]
+fn vectors() {
+    check(&[
+        ("(vector)", "[]"),
+        ("(vector 1 true \"s\" #\\a)", "[1, true, s, a]"),
+        ("(vector (vector 1 2) (vector))", "[[1, 2], []]"),
+        ("(vector-length (vector))", "0"),
+        ("(vector-length (vector 1 2 3))", "3"),
+        ("(vector-ref (vector 1 2 3) 0)", "1"),
+        ("(vector-ref (vector 1 2 3) 2)", "3"),
+        ("(let ((v (vector 1 2))) (= v v))", "true"),
+        ("(= (vector 1) (vector 1))", "false"),
+    ]);
+}
+
+#[test]
+fn vector_errors() {
+    check(&[
+        ("(try (vector-ref (vector 1 2 3) -1) (catch e (error-code e)))", "7"),
+        ("(try (vector-ref (vector 1 2 3) 3) (catch e (error-code e)))", "7"),
+        ("(try (vector-ref (vector) 0) (catch e (error-code e)))", "7"),
+        ("(try (vector-ref 5 0) (catch e (error-code e)))", "2"),
+        ("(try (vector-ref (vector 1) true) (catch e (error-code e)))", "2"),
+        ("(try (vector-length 5) (catch e (error-code e)))", "2"),
+        ("(try (vector-length \"abc\") (catch e (error-code e)))", "2"),
+    ]);
+}
@@ -0,0 +1,94 @@
+use viva::expressions::{Defenition, Expr};
+use viva::lift::lift_expr;
+use viva::parse::{parse_expr, read_sexp};
+use viva::visit::{fold_expr_children, walk_expr, walk_expr_mut, Fold, Visitor, VisitorMut};
+
+fn expr(source: &str) -> Expr {
+    parse_expr(&read_sexp(source).unwrap()).unwrap()
+}
+
+const NESTED: &str = "
+(let ((a (f 1)))
+  (letrec ((fun (g x) (f x)))
+    (match (g a)
+      ((some v) (try (f v) (catch e (f e))))
+      (_ (cond ((f 2) 3) (else (f 4)))))))";
+
+/* The names of the calls, in the order they are visited */
+struct Calls(Vec<String>);
+
+impl Visitor for Calls {
+    fn visit_expr(&mut self, e: &Expr) {
+        if let Expr::Call(name, _, _) = e {
+            self.0.push(name.clone());
+        }
+        walk_expr(self, e);
+    }
+}
+
+#[test]
+fn visitors_reach_every_node() {
+    let mut calls = Calls(Vec::new());
+    calls.visit_expr(&expr(NESTED));
+    assert_eq!(calls.0, ["f", "f", "g", "f", "f", "f", "f"]);
+}
+
+struct Rename;
+
+impl VisitorMut for Rename {
+    fn visit_expr_mut(&mut self, e: &mut Expr) {
+        if let Expr::Id(name) = e {
+            name.push('!');
+        }
+        walk_expr_mut(self, e);
+    }
+}
+
+#[test]
+fn mutable_visitors_change_the_tree_in_place() {
+    let mut e = expr("(let ((a b)) (if c (g d) (set! a e)))");
+    Rename.visit_expr_mut(&mut e);
+    assert_eq!(e, expr("(let ((a b!)) (if c! (g d!) (set! a e!)))"));
+}
+
+struct Same;
+
+impl Fold for Same {}
+
+/* Doubles every number */
+struct Double;
+
+impl Fold for Double {
+    fn fold_expr(&mut self, e: Expr) -> Expr {
+        match e {
+            Expr::Number(n) => Expr::Number(n * 2),
+            e => fold_expr_children(self, e),
+        }
+    }
+}
+
+#[test]
+fn folds_rebuild_the_tree() {
+    let e = expr(NESTED);
+    assert_eq!(Same.fold_expr(e.clone()), e);
+    assert_eq!(Double.fold_expr(expr("(block (f 1) (let ((x 2)) (+ x 3)))")), expr("(block (f 2) (let ((x 4)) (+ x 6)))"));
+}
+
+#[test]
+fn lifting_passes_captures_of_every_scope() {
+    /* y is bound by let and v by the match, the helper takes both after its own parameters */
+    let (lifted, helpers) = lift_expr(&expr("(let ((y 1)) (match (some 2) ((some v) (letrec ((fun (h) (+ y v))) (h)))))")).unwrap();
+    let helper = match helpers.as_slice() {
+        [Defenition::Fun(name, params, _, _)] => {
+            assert_eq!(params.iter().map(|p| p.name.as_str()).collect::<Vec<_>>(), ["v", "y"]);
+            name.clone()
+        }
+        other => panic!("{:?}", other),
+    };
+    let mut calls = Calls(Vec::new());
+    calls.visit_expr(&lifted);
+    assert_eq!(calls.0, ["some", helper.as_str()]);
+
+    let err = lift_expr(&expr("(let ((y 1)) (letrec ((fun (h) y) (fun (k y) (h))) (k 2)))")).unwrap_err();
+    assert_eq!(err.to_string(), "parameter y of k shadows a variable captured by letrec");
+}